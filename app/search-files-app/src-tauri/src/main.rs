@@ -9,28 +9,67 @@ use app::shortcut::register_shorcut;
 use app::{show_window, tray};
 
 use file_elf::server::api;
+use serde::Serialize;
+use tauri::async_runtime::spawn_blocking;
+use tauri::Emitter;
 use log::{error, info, trace, warn};
 use tauri_plugin_log::TimezoneStrategy;
 
-/// 热点文件搜索
+// 搜索命令是异步的, 在阻塞线程池中执行, 不会阻塞主线程;
+// 以窗口标签作为客户端标识, 同一窗口的新查询会取消上一个仍在进行的查询
+
+/// 智能搜索中一个热点文件夹的结果
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HotSearchProgress {
+    search_id: u64,
+    results: Vec<(String, bool)>,
+}
+
+/// 热点文件搜索, 每个热点文件夹遍历完成后通过 hot_search_progress 事件推送结果
 #[tauri::command]
-fn hot_search(entry: String, is_fuzzy: bool, is_regex: bool) -> Vec<(String, bool)> {
-    let res = api::api_hot_search(entry, is_fuzzy, is_regex);
-    res
+async fn hot_search(
+    window: tauri::Window,
+    entry: String,
+    is_fuzzy: bool,
+    is_regex: bool,
+    search_id: u64,
+) -> api::SearchResponse {
+    let client = Some(window.label().to_string());
+    spawn_blocking(move || {
+        api::api_hot_search_streaming(entry, is_fuzzy, is_regex, client, |results| {
+            let progress = HotSearchProgress { search_id, results };
+            if let Err(e) = window.emit("hot_search_progress", progress) {
+                warn!("emit hot search progress error: {}", e);
+            }
+        })
+    })
+    .await
+    .unwrap_or_default()
 }
 
 /// 正则表达式搜索
 #[tauri::command]
-fn regex_search(entry: String) -> Vec<(String, bool)> {
-    let res = api::api_regex_search(entry);
-    res
+async fn regex_search(window: tauri::Window, entry: String) -> api::SearchResponse {
+    let client = Some(window.label().to_string());
+    spawn_blocking(move || api::api_regex_search(entry, client))
+        .await
+        .unwrap_or_default()
 }
 
 /// 常规搜索
 #[tauri::command]
-fn search(entry: String, is_fuzzy: bool) -> Vec<(String, bool)> {
-    let res = api::api_search(entry, is_fuzzy);
-    res
+async fn search(window: tauri::Window, entry: String, is_fuzzy: bool) -> api::SearchResponse {
+    let client = Some(window.label().to_string());
+    spawn_blocking(move || api::api_search(entry, is_fuzzy, client))
+        .await
+        .unwrap_or_default()
+}
+
+/// 取消当前窗口正在进行的搜索
+#[tauri::command]
+fn cancel_search(window: tauri::Window) -> bool {
+    api::api_cancel(window.label().to_string())
 }
 
 /// star_path
@@ -47,6 +86,48 @@ fn unstar_path(path: String) -> bool {
     res
 }
 
+/// 运行状态
+#[tauri::command]
+fn stats() -> api::Stats {
+    api::api_stats()
+}
+
+/// 监听目标的状态
+#[tauri::command]
+fn watchers() -> Vec<file_elf::backend::supervisor::WatcherStatus> {
+    api::api_watchers()
+}
+
+/// 增加监听目标
+#[tauri::command]
+fn add_watch_target(target: String) -> bool {
+    api::api_add_watch_target(target)
+}
+
+/// 移除监听目标
+#[tauri::command]
+fn remove_watch_target(target: String) -> bool {
+    api::api_remove_watch_target(target)
+}
+
+/// 热点文件夹
+#[tauri::command]
+fn hot_dirs() -> Vec<file_elf::cache::hot_dir::HotDir> {
+    api::api_hot_dirs()
+}
+
+/// 固定热点文件夹
+#[tauri::command]
+fn pin_hot_dir(path: String) -> bool {
+    api::api_pin_hot_dir(path)
+}
+
+/// 取消固定热点文件夹
+#[tauri::command]
+fn unpin_hot_dir(path: String) -> bool {
+    api::api_unpin_hot_dir(path)
+}
+
 /// 打开文件
 #[tauri::command]
 fn open_file(name: String) {
@@ -185,7 +266,15 @@ fn main() {
             regex_search,
             search,
             star_path,
-            unstar_path
+            unstar_path,
+            stats,
+            cancel_search,
+            hot_dirs,
+            pin_hot_dir,
+            unpin_hot_dir,
+            watchers,
+            add_watch_target,
+            remove_watch_target
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -1,13 +1,50 @@
+use std::{thread, time::Duration};
+
+use file_elf::backend::supervisor::{watcher_status, WatcherState};
 use tauri::{
     menu::{Menu, MenuItem},
     tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
     Manager, Runtime,
 };
 
+/// 托盘中监听状态的刷新间隔
+const WATCHERS_REFRESH: Duration = Duration::from_secs(5);
+
+/// 监听状态的摘要, 有目标没在运行时附上第一个的状态和原因
+fn watchers_text() -> String {
+    let watchers = watcher_status();
+    let running = watchers
+        .iter()
+        .filter(|status| status.state == WatcherState::Running)
+        .count();
+    let mut text = format!("Watching {}/{} targets", running, watchers.len());
+    let polled: usize = watchers.iter().map(|status| status.polled.len()).sum();
+    if polled > 0 {
+        text.push_str(&format!(", {} subtrees polled", polled));
+    }
+    if let Some(status) = watchers
+        .iter()
+        .find(|status| status.state != WatcherState::Running)
+    {
+        text.push_str(&format!(" - {}: {:?}", status.target, status.state));
+        if let Some(reason) = &status.reason {
+            text.push_str(&format!(" ({})", reason));
+        }
+    }
+    text
+}
+
 pub fn create_tray<R: Runtime>(app: &tauri::AppHandle<R>) -> tauri::Result<()> {
     let quit_i = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
     let show_i = MenuItem::with_id(app, "show", "Show", true, None::<&str>)?;
-    let menu = Menu::with_items(app, &[&show_i, &quit_i])?;
+    let watchers_i = MenuItem::with_id(app, "watchers", watchers_text(), false, None::<&str>)?;
+    let menu = Menu::with_items(app, &[&watchers_i, &show_i, &quit_i])?;
+
+    // 定期刷新监听状态
+    thread::spawn(move || loop {
+        thread::sleep(WATCHERS_REFRESH);
+        let _ = watchers_i.set_text(watchers_text());
+    });
 
     let _ = TrayIconBuilder::with_id("tray")
         .icon(app.default_window_icon().unwrap().clone())
@@ -15,6 +52,8 @@ pub fn create_tray<R: Runtime>(app: &tauri::AppHandle<R>) -> tauri::Result<()> {
         .menu_on_left_click(false)
         .on_menu_event(move |app, event| match event.id.as_ref() {
             "quit" => {
+                file_elf::cache::snapshot::save_cache_snapshot();
+                file_elf::cache::hot_dir::save_hot_dirs_file();
                 app.cleanup_before_exit();
                 app.exit(0);
             }
@@ -16,6 +16,8 @@ const SearchForm = () => {
 
     // 创建输入框的引用
     const inputRef = useRef(null);
+    // 最近一次查询的编号, 用于丢弃已被取消的旧查询的结果
+    const searchIdRef = useRef(0);
 
     // 聚焦输入框的函数
     const focusInput = () => {
@@ -29,29 +31,55 @@ const SearchForm = () => {
         focusInput();
     }, []);
 
+    // 智能搜索在每个热点文件夹完成后推送结果, 先展示已经找到的部分
+    useEffect(() => {
+        const unlisten = window.__TAURI__.event.listen('hot_search_progress', (event) => {
+            const { searchId, results: dirResults } = event.payload;
+            if (searchId === searchIdRef.current) {
+                setResults((prev) => prev.concat(dirResults));
+            }
+        });
+        return () => {
+            unlisten.then((f) => f());
+        };
+    }, []);
+
     useEffect(() => {
         if (entry && !isSmart) {
             handleSearch();
+        } else if (!entry) {
+            // 输入框被清空, 取消仍在进行的查询
+            searchIdRef.current += 1;
+            window.__TAURI__.core.invoke('cancel_search');
         }
     }, [entry, isFuzzy, isRegex, isSmart]);
 
     const handleSearch = async () => {
         setResults([]);
         const invoke = window.__TAURI__.core.invoke;
+        const searchId = ++searchIdRef.current;
 
         try {
             let response;
             if (isSmart) {
-                response = await invoke('hot_search', { entry, isFuzzy, isRegex });
+                response = await invoke('hot_search', { entry, isFuzzy, isRegex, searchId });
             } else if (isRegex) {
                 response = await invoke('regex_search', { entry });
             } else {
                 response = await invoke('search', { entry, isFuzzy });
             }
 
-            if (response && Array.isArray(response)) {
-                setResults(response);
-                addMessage('Search completed successfully.');
+            if (searchId !== searchIdRef.current) {
+                // 已经有更新的查询, 这个查询在后端已被取消
+                return;
+            }
+            if (response && Array.isArray(response.results)) {
+                setResults(response.results);
+                if (response.partial) {
+                    addMessage('Search timed out, showing partial results.');
+                } else {
+                    addMessage('Search completed successfully.');
+                }
             } else {
                 throw new Error('Invalid response data');
             }
@@ -0,0 +1,688 @@
+//! 文件系统事件的转换和去抖
+//!
+//! notify 上报的原始事件粒度很细: 保存一个文件可能产生打开、多次修改、关闭等一连串事件,
+//! 重命名被拆成 From 和 To 两个事件。`Debouncer` 将同一路径在去抖时间内的事件合并为一个,
+//! 按 inotify cookie(notify 中的 tracker) 将 From 和 To 配对为一次重命名,
+//! 配对失败的 From 视为移出监听范围(删除), To 视为移入(新建)。
+//! 有些平台把跨目录的移动拆成不相关的删除和新建, 这些平台上去抖时间内文件名相同的一对也还原为重命名;
+//! Linux 上 inotify 总是提供 cookie, 不做这种猜测。
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use notify::{
+    event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode},
+    Event, EventKind,
+};
+
+use crate::db::meta::EntryType;
+
+/// 去抖后的文件系统事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsEvent {
+    /// 新建, 包括从监听范围外移入
+    Create { path: PathBuf, kind: EntryType },
+    /// 内容被修改
+    Write(PathBuf),
+    /// 被打开
+    Open(PathBuf),
+    /// 权限、时间戳等元数据变化
+    Metadata(PathBuf),
+    /// 删除, 包括移出监听范围
+    Remove { path: PathBuf, kind: EntryType },
+    /// 监听范围内的重命名或移动
+    Rename { from: PathBuf, to: PathBuf },
+    /// 内核事件队列溢出, 这段时间内的变化已经丢失, 需要重新扫描
+    Rescan(Option<PathBuf>),
+}
+
+impl FsEvent {
+    fn path_mut(&mut self) -> Option<&mut PathBuf> {
+        match self {
+            FsEvent::Create { path, .. }
+            | FsEvent::Write(path)
+            | FsEvent::Open(path)
+            | FsEvent::Metadata(path)
+            | FsEvent::Remove { path, .. } => Some(path),
+            FsEvent::Rename { .. } | FsEvent::Rescan(_) => None,
+        }
+    }
+
+    fn path(&self) -> Option<&PathBuf> {
+        match self {
+            FsEvent::Create { path, .. }
+            | FsEvent::Write(path)
+            | FsEvent::Open(path)
+            | FsEvent::Metadata(path)
+            | FsEvent::Remove { path, .. } => Some(path),
+            FsEvent::Rename { .. } | FsEvent::Rescan(_) => None,
+        }
+    }
+
+    /// 同一路径上的修改类事件合并时保留优先级高的
+    fn rank(&self) -> u8 {
+        match self {
+            FsEvent::Metadata(_) => 1,
+            FsEvent::Open(_) => 2,
+            FsEvent::Write(_) => 3,
+            _ => 0,
+        }
+    }
+}
+
+struct Pending {
+    event: FsEvent,
+    /// 首次出现的顺序, 输出时按该顺序排列
+    seq: u64,
+    deadline: Instant,
+}
+
+pub struct Debouncer {
+    delay: Duration,
+    seq: u64,
+    /// 按路径合并的事件
+    pending: HashMap<PathBuf, Pending>,
+    /// 不参与合并、按到达顺序直接输出的事件(重命名、重新扫描)
+    ready: Vec<FsEvent>,
+    /// 等待配对的重命名 From 事件, 以 cookie 为键
+    renames: HashMap<usize, (PathBuf, Instant)>,
+    /// 没有 cookie 的平台上, 相邻的 From 和 To 事件配对
+    untracked_rename: Option<(PathBuf, Instant)>,
+    /// 是否将文件名相同的删除和新建配对为重命名
+    pair_moves: bool,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Debouncer {
+        Debouncer {
+            delay,
+            seq: 0,
+            pending: HashMap::new(),
+            ready: Vec::new(),
+            renames: HashMap::new(),
+            untracked_rename: None,
+            pair_moves: !cfg!(target_os = "linux"),
+        }
+    }
+
+    /// 加入一个 notify 事件
+    pub fn push(&mut self, event: Event, now: Instant) {
+        if event.need_rescan() {
+            self.ready
+                .push(FsEvent::Rescan(event.paths.first().cloned()));
+            return;
+        }
+        let tracker = event.tracker();
+        let mut paths = event.paths.into_iter();
+        let path = match paths.next() {
+            Some(path) => path,
+            None => return,
+        };
+
+        match event.kind {
+            EventKind::Create(kind) => self.add(
+                FsEvent::Create {
+                    path,
+                    kind: create_kind(kind),
+                },
+                now,
+            ),
+            EventKind::Remove(kind) => self.add(
+                FsEvent::Remove {
+                    path,
+                    kind: remove_kind(kind),
+                },
+                now,
+            ),
+            EventKind::Modify(ModifyKind::Name(mode)) => {
+                self.push_rename(mode, tracker, path, paths.next(), now)
+            }
+            EventKind::Modify(ModifyKind::Metadata(_)) => self.add(FsEvent::Metadata(path), now),
+            EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                self.add(FsEvent::Write(path), now)
+            }
+            EventKind::Access(AccessKind::Open(_)) => self.add(FsEvent::Open(path), now),
+            _ => {}
+        }
+    }
+
+    fn push_rename(
+        &mut self,
+        mode: RenameMode,
+        tracker: Option<usize>,
+        path: PathBuf,
+        to: Option<PathBuf>,
+        now: Instant,
+    ) {
+        let deadline = now + self.delay;
+        match (mode, tracker) {
+            (RenameMode::From, Some(cookie)) => {
+                self.renames.insert(cookie, (path, deadline));
+            }
+            (RenameMode::From, None) => {
+                self.expire_untracked();
+                self.untracked_rename = Some((path, deadline));
+            }
+            (RenameMode::To, Some(cookie)) => match self.renames.remove(&cookie) {
+                Some((from, _)) => self.rename(from, path, now),
+                None => self.moved_in(path, now),
+            },
+            (RenameMode::To, None) => match self.untracked_rename.take() {
+                Some((from, _)) => self.rename(from, path, now),
+                None => self.moved_in(path, now),
+            },
+            // 带 cookie 的 Both 事件在 To 事件中已经配对过
+            (RenameMode::Both, None) => {
+                if let Some(to) = to {
+                    self.rename(path, to, now);
+                }
+            }
+            (RenameMode::Both, Some(_)) => {}
+            // 无法区分方向时按路径是否存在判断
+            _ => {
+                if path.exists() {
+                    self.moved_in(path, now);
+                } else {
+                    self.add(
+                        FsEvent::Remove {
+                            path,
+                            kind: EntryType::Unknown,
+                        },
+                        now,
+                    );
+                }
+            }
+        }
+    }
+
+    fn moved_in(&mut self, path: PathBuf, now: Instant) {
+        self.add(
+            FsEvent::Create {
+                path,
+                kind: EntryType::Unknown,
+            },
+            now,
+        );
+    }
+
+    fn rename(&mut self, from: PathBuf, to: PathBuf, now: Instant) {
+        // 目标路径上尚未输出的事件(例如被覆盖的文件)必须先于重命名处理
+        if let Some(prev) = self.pending.remove(&to) {
+            self.ready.push(prev.event);
+        }
+        // 被重命名的文件夹中尚未输出的事件改到新路径下, 在重命名之后处理
+        let moved: Vec<PathBuf> = self
+            .pending
+            .keys()
+            .filter(|path| path.starts_with(&from) && **path != from)
+            .cloned()
+            .collect();
+        for path in moved {
+            let mut pending = self.pending.remove(&path).unwrap();
+            let rebased = to.join(path.strip_prefix(&from).unwrap());
+            if let Some(path) = pending.event.path_mut() {
+                *path = rebased.clone();
+            }
+            self.pending.insert(rebased, pending);
+        }
+        match self.pending.remove(&from) {
+            // 刚新建还没有输出的文件, 直接当作在新位置新建
+            Some(Pending {
+                event: FsEvent::Create { kind, .. },
+                ..
+            }) => {
+                self.add(FsEvent::Create { path: to, kind }, now);
+            }
+            Some(prev) => {
+                self.ready.push(prev.event);
+                self.ready.push(FsEvent::Rename { from, to });
+            }
+            None => self.ready.push(FsEvent::Rename { from, to }),
+        }
+    }
+
+    /// 跨目录的移动被拆成删除和新建时, 在去抖时间内找到文件名相同、类型相容的另一半;
+    /// 只有唯一的候选, 且原路径已经不在、新路径存在时才配对, 被配对的事件不再单独输出
+    fn split_move(&mut self, event: &FsEvent) -> Option<(PathBuf, PathBuf)> {
+        if !self.pair_moves {
+            return None;
+        }
+        let (path, kind, is_create) = match event {
+            FsEvent::Create { path, kind } => (path, *kind, true),
+            FsEvent::Remove { path, kind } => (path, *kind, false),
+            _ => return None,
+        };
+        // 同一路径上的删除和新建按合并规则处理
+        if self.pending.contains_key(path) {
+            return None;
+        }
+        let name = path.file_name()?;
+        let candidates: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(other, _)| other.file_name() == Some(name))
+            .filter(|(_, pending)| match (&pending.event, is_create) {
+                (FsEvent::Remove { kind: other, .. }, true)
+                | (FsEvent::Create { kind: other, .. }, false) => {
+                    kind == *other || kind == EntryType::Unknown || *other == EntryType::Unknown
+                }
+                _ => false,
+            })
+            .map(|(other, _)| other.clone())
+            .collect();
+        if candidates.len() != 1 {
+            return None;
+        }
+        let other = candidates.into_iter().next().unwrap();
+        let (from, to) = if is_create {
+            (other, path.clone())
+        } else {
+            (path.clone(), other)
+        };
+        if from.exists() || !to.exists() {
+            return None;
+        }
+        self.pending.remove(if is_create { &from } else { &to });
+        Some((from, to))
+    }
+
+    /// 按路径合并事件
+    fn add(&mut self, event: FsEvent, now: Instant) {
+        if let Some((from, to)) = self.split_move(&event) {
+            self.rename(from, to, now);
+            return;
+        }
+        let path = event.path().cloned().unwrap();
+        // 合并时保留第一次出现时的截止时间, 持续写入的文件(日志、下载)也会定期输出
+        let (seq, deadline) = match self.pending.get(&path) {
+            Some(prev) => (prev.seq, prev.deadline),
+            None => {
+                self.seq += 1;
+                (self.seq, now + self.delay)
+            }
+        };
+        let merged = match self.pending.remove(&path) {
+            None => Some(event),
+            Some(prev) => match (prev.event, event) {
+                // 新建后又在去抖时间内删除, 两个事件都不需要处理
+                (FsEvent::Create { .. }, FsEvent::Remove { .. }) => None,
+                (FsEvent::Create { kind, .. }, FsEvent::Write(_))
+                | (FsEvent::Create { kind, .. }, FsEvent::Open(_))
+                | (FsEvent::Create { kind, .. }, FsEvent::Metadata(_)) => Some(FsEvent::Create {
+                    path: path.clone(),
+                    kind,
+                }),
+                // 删除后重新创建(例如编辑器保存文件), 按修改处理: 之后再被删除时, 删除事件不能被抵消
+                (FsEvent::Remove { .. }, FsEvent::Create { .. }) => {
+                    Some(FsEvent::Write(path.clone()))
+                }
+                (prev, event) if prev.rank() > event.rank() && event.rank() > 0 => Some(prev),
+                (_, event) => Some(event),
+            },
+        };
+        if let Some(event) = merged {
+            self.pending.insert(
+                path,
+                Pending {
+                    event,
+                    seq,
+                    deadline,
+                },
+            );
+        }
+    }
+
+    fn expire_untracked(&mut self) {
+        if let Some((from, _)) = self.untracked_rename.take() {
+            self.ready.push(FsEvent::Remove {
+                path: from,
+                kind: EntryType::Unknown,
+            });
+        }
+    }
+
+    /// 取出已经稳定的事件, 超时未配对的 From 事件视为移出监听范围
+    pub fn drain(&mut self, now: Instant) -> Vec<FsEvent> {
+        let expired: Vec<usize> = self
+            .renames
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        for cookie in expired {
+            let (from, _) = self.renames.remove(&cookie).unwrap();
+            self.ready.push(FsEvent::Remove {
+                path: from,
+                kind: EntryType::Unknown,
+            });
+        }
+        if matches!(&self.untracked_rename, Some((_, deadline)) if *deadline <= now) {
+            self.expire_untracked();
+        }
+
+        let mut res = std::mem::take(&mut self.ready);
+        let ready_paths: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut stable: Vec<Pending> = ready_paths
+            .into_iter()
+            .filter_map(|path| self.pending.remove(&path))
+            .collect();
+        stable.sort_by_key(|pending| pending.seq);
+        res.extend(stable.into_iter().map(|pending| pending.event));
+        res
+    }
+
+    /// 下一次需要调用 `drain` 的时间
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.ready.is_empty() {
+            return Some(Instant::now());
+        }
+        self.pending
+            .values()
+            .map(|pending| pending.deadline)
+            .chain(self.renames.values().map(|(_, deadline)| *deadline))
+            .chain(self.untracked_rename.iter().map(|(_, deadline)| *deadline))
+            .min()
+    }
+}
+
+fn create_kind(kind: CreateKind) -> EntryType {
+    match kind {
+        CreateKind::File => EntryType::File,
+        CreateKind::Folder => EntryType::Dir,
+        _ => EntryType::Unknown,
+    }
+}
+
+fn remove_kind(kind: RemoveKind) -> EntryType {
+    match kind {
+        RemoveKind::File => EntryType::File,
+        RemoveKind::Folder => EntryType::Dir,
+        _ => EntryType::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+    use notify::event::{DataChange, Flag, MetadataKind};
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    fn rename(mode: RenameMode, path: &str, cookie: usize) -> Event {
+        event(EventKind::Modify(ModifyKind::Name(mode)), path).set_tracker(cookie)
+    }
+
+    #[test]
+    fn test_merge() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        debouncer.push(event(EventKind::Create(CreateKind::File), "/a"), now);
+        debouncer.push(
+            event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), "/a"),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Access(AccessKind::Open(AccessMode::Any)), "/b"),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), "/b"),
+            now,
+        );
+        debouncer.push(
+            event(
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)),
+                "/b",
+            ),
+            now,
+        );
+        debouncer.push(event(EventKind::Create(CreateKind::Folder), "/tmp"), now);
+        debouncer.push(event(EventKind::Remove(RemoveKind::Folder), "/tmp"), now);
+
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), "/c"), now);
+        debouncer.push(event(EventKind::Create(CreateKind::File), "/c"), now);
+
+        // 去抖时间内不输出
+        assert!(debouncer.drain(now).is_empty());
+        assert_eq!(
+            debouncer.drain(later),
+            vec![
+                FsEvent::Create {
+                    path: PathBuf::from("/a"),
+                    kind: EntryType::File
+                },
+                FsEvent::Write(PathBuf::from("/b")),
+                FsEvent::Write(PathBuf::from("/c")),
+            ]
+        );
+        assert!(debouncer.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_continuous_write() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let mut debouncer = Debouncer::new(delay);
+        let write = event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), "/log");
+
+        // 每 30 毫秒写入一次, 间隔始终小于去抖时间, 仍然每个去抖时间输出一次
+        let mut flushed = Vec::new();
+        for i in 0..40 {
+            let now = start + Duration::from_millis(30 * i);
+            debouncer.push(write.clone(), now);
+            flushed.extend(debouncer.drain(now));
+        }
+        assert!(flushed.len() >= 5);
+        assert!(flushed
+            .iter()
+            .all(|event| *event == FsEvent::Write(PathBuf::from("/log"))));
+    }
+
+    #[test]
+    fn test_rename_cookie() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        debouncer.push(rename(RenameMode::From, "/a", 1), now);
+        debouncer.push(rename(RenameMode::From, "/out", 2), now);
+        debouncer.push(rename(RenameMode::To, "/b", 1), now);
+        debouncer.push(
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path(PathBuf::from("/a"))
+                .add_path(PathBuf::from("/b"))
+                .set_tracker(1),
+            now,
+        );
+        debouncer.push(rename(RenameMode::To, "/in", 3), now);
+
+        assert_eq!(
+            debouncer.drain(now),
+            vec![FsEvent::Rename {
+                from: PathBuf::from("/a"),
+                to: PathBuf::from("/b")
+            }]
+        );
+        // 没有配对的 From 是移出, To 是移入
+        assert_eq!(
+            debouncer.drain(later),
+            vec![
+                FsEvent::Remove {
+                    path: PathBuf::from("/out"),
+                    kind: EntryType::Unknown
+                },
+                FsEvent::Create {
+                    path: PathBuf::from("/in"),
+                    kind: EntryType::Unknown
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_move() {
+        let root = TempDir::new("split-move");
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+        fs::create_dir_all(root.join("b/dir")).unwrap();
+        fs::create_dir_all(root.join("b/y")).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        for name in ["b/x.txt", "b/z", "b/w", "a/w"] {
+            fs::write(root.join(name), b"x").unwrap();
+        }
+
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        debouncer.pair_moves = true;
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::Folder), &path("a/dir")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Create(CreateKind::Folder), &path("b/dir")),
+            now,
+        );
+        // 先新建后删除也能配对
+        debouncer.push(
+            event(EventKind::Create(CreateKind::File), &path("b/x.txt")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::Any), &path("a/x.txt")),
+            now,
+        );
+        assert_eq!(
+            debouncer.drain(now),
+            vec![
+                FsEvent::Rename {
+                    from: root.join("a/dir"),
+                    to: root.join("b/dir")
+                },
+                FsEvent::Rename {
+                    from: root.join("a/x.txt"),
+                    to: root.join("b/x.txt")
+                },
+            ]
+        );
+
+        // 类型不同、候选不唯一或原路径仍然存在时不配对
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::File), &path("a/y")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Create(CreateKind::Folder), &path("b/y")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::File), &path("a/z")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::File), &path("c/z")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Create(CreateKind::File), &path("b/z")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Remove(RemoveKind::File), &path("a/w")),
+            now,
+        );
+        debouncer.push(
+            event(EventKind::Create(CreateKind::File), &path("b/w")),
+            now,
+        );
+        let events = debouncer.drain(later);
+        assert_eq!(events.len(), 7);
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, FsEvent::Rename { .. })));
+
+        // inotify 提供 cookie, Linux 上不猜测
+        if cfg!(target_os = "linux") {
+            let mut debouncer = Debouncer::new(Duration::from_millis(100));
+            debouncer.push(
+                event(EventKind::Remove(RemoveKind::File), &path("a/x.txt")),
+                now,
+            );
+            debouncer.push(
+                event(EventKind::Create(CreateKind::File), &path("b/x.txt")),
+                now,
+            );
+            assert_eq!(debouncer.drain(later).len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_rename_rebase() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        debouncer.push(
+            event(EventKind::Create(CreateKind::File), "/d/new.txt"),
+            now,
+        );
+        debouncer.push(
+            event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                "/d/sub/a",
+            ),
+            now,
+        );
+        debouncer.push(rename(RenameMode::From, "/d", 4), now);
+        debouncer.push(rename(RenameMode::To, "/e", 4), now);
+
+        // 文件夹中较早的事件改到新路径下, 在重命名之后输出
+        assert_eq!(
+            debouncer.drain(now),
+            vec![FsEvent::Rename {
+                from: PathBuf::from("/d"),
+                to: PathBuf::from("/e")
+            }]
+        );
+        assert_eq!(
+            debouncer.drain(later),
+            vec![
+                FsEvent::Create {
+                    path: PathBuf::from("/e/new.txt"),
+                    kind: EntryType::File
+                },
+                FsEvent::Write(PathBuf::from("/e/sub/a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_pending_create() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        debouncer.push(event(EventKind::Create(CreateKind::File), "/a.tmp"), now);
+        debouncer.push(rename(RenameMode::From, "/a.tmp", 7), now);
+        debouncer.push(rename(RenameMode::To, "/a", 7), now);
+        debouncer.push(Event::new(EventKind::Other).set_flag(Flag::Rescan), now);
+        assert_eq!(debouncer.drain(now), vec![FsEvent::Rescan(None)]);
+        assert_eq!(
+            debouncer.drain(later),
+            vec![FsEvent::Create {
+                path: PathBuf::from("/a"),
+                kind: EntryType::File
+            }]
+        );
+    }
+}
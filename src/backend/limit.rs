@@ -0,0 +1,185 @@
+//! inotify 监听数量的限制
+//!
+//! Linux 上递归监听会为每个文件夹占用一个 inotify watch, 总数受 `fs.inotify.max_user_watches`
+//! 限制。开始监听前先在后台统计目标中的文件夹, 在剩余的 watch 中尽量多地监听, 放不下的子树
+//! 改用轮询扫描。其他平台没有这个限制。
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use super::supervisor::watcher_status;
+
+/// 已用的 watch 超过限制的这个百分比时警告
+const WARN_PERCENT: usize = 80;
+
+/// 当前用户可用的 inotify watch 总数, 不是 Linux 或读取失败时返回 None
+pub fn max_user_watches() -> Option<usize> {
+    if cfg!(target_os = "linux") {
+        std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+            .ok()
+            .and_then(|limit| limit.trim().parse().ok())
+    } else {
+        None
+    }
+}
+
+/// inotify watch 的使用情况
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchUsage {
+    /// 总数限制, 没有限制时为 None
+    pub limit: Option<usize>,
+    /// 所有监听目标占用的数量
+    pub used: usize,
+}
+
+pub fn watch_usage() -> WatchUsage {
+    WatchUsage {
+        limit: max_user_watches(),
+        used: watcher_status().iter().map(|status| status.watches).sum(),
+    }
+}
+
+/// 目标中的文件夹, 用于决定哪些子树可以放进剩余的 watch
+#[derive(Debug)]
+pub(super) struct DirTree {
+    pub(super) path: PathBuf,
+    /// 递归监听这个子树需要的 watch 数量, 即其中文件夹的数量(包括自身)
+    pub(super) total: usize,
+    pub(super) children: Vec<DirTree>,
+}
+
+/// 遍历 `root` 中的所有文件夹, 目标很大时耗时较长, 不能在事件循环中调用
+///
+/// 与 notify 一致, 跟随符号链接, 也不跳过黑名单中的文件夹
+pub(super) fn dir_tree(root: &Path) -> DirTree {
+    // 深度优先遍历, 栈中是当前文件夹及其所有上级
+    let mut stack: Vec<DirTree> = Vec::new();
+    let dirs = WalkDir::new(root)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_dir());
+    for entry in dirs {
+        while stack.len() > entry.depth() {
+            fold_last(&mut stack);
+        }
+        stack.push(DirTree {
+            path: entry.into_path(),
+            total: 1,
+            children: Vec::new(),
+        });
+    }
+    while stack.len() > 1 {
+        fold_last(&mut stack);
+    }
+    stack.pop().unwrap_or(DirTree {
+        path: root.to_path_buf(),
+        total: 1,
+        children: Vec::new(),
+    })
+}
+
+/// 栈顶的子树已经遍历完, 加到上级文件夹中
+fn fold_last(stack: &mut Vec<DirTree>) {
+    let done = stack.pop().unwrap();
+    let parent = stack.last_mut().unwrap();
+    parent.total += done.total;
+    parent.children.push(done);
+}
+
+/// 在剩余的 watch 中监听一个目标的方案
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct WatchPlan {
+    /// 递归监听的子树及其占用的 watch 数量
+    pub(super) recursive: Vec<(PathBuf, usize)>,
+    /// 只监听自身的文件夹, 其中有放不下的子树
+    pub(super) single: Vec<PathBuf>,
+    /// 放不下、改用轮询的子树
+    pub(super) polled: Vec<PathBuf>,
+}
+
+impl WatchPlan {
+    /// 占用的 watch 数量
+    pub(super) fn watches(&self) -> usize {
+        self.recursive
+            .iter()
+            .map(|(_, watches)| watches)
+            .sum::<usize>()
+            + self.single.len()
+    }
+}
+
+/// 放得下的子树整个递归监听; 放不下时只监听文件夹自身, 再依次处理其中的子树, 用完后剩下的子树轮询
+pub(super) fn plan_watches(tree: &DirTree, budget: usize) -> WatchPlan {
+    let mut plan = WatchPlan::default();
+    let mut budget = budget;
+    plan_subtree(tree, &mut budget, &mut plan);
+    plan
+}
+
+fn plan_subtree(tree: &DirTree, budget: &mut usize, plan: &mut WatchPlan) {
+    if tree.total <= *budget {
+        *budget -= tree.total;
+        plan.recursive.push((tree.path.clone(), tree.total));
+    } else if *budget == 0 {
+        plan.polled.push(tree.path.clone());
+    } else {
+        *budget -= 1;
+        plan.single.push(tree.path.clone());
+        for child in &tree.children {
+            plan_subtree(child, budget, plan);
+        }
+    }
+}
+
+/// 已用的 watch 是否超过警告线
+pub(super) fn near_limit(used: usize, limit: usize) -> bool {
+    used.saturating_mul(100) >= limit.saturating_mul(WARN_PERCENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+
+    #[test]
+    fn test_plan_watches() {
+        let root = TempDir::new("limit");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        fs::write(root.join("a/file.txt"), b"").unwrap();
+
+        let tree = dir_tree(&root);
+        assert_eq!(tree.total, 4);
+        assert_eq!(dir_tree(&root.join("c")).total, 1);
+
+        // 放得下时整个目标递归监听
+        let plan = plan_watches(&tree, 4);
+        assert_eq!(plan.recursive, vec![(root.to_path_buf(), 4)]);
+        assert_eq!(plan.watches(), 4);
+
+        // 放不下时只监听目标自身, 子树中放得下的继续递归监听, 剩下的轮询
+        let plan = plan_watches(&tree, 2);
+        assert_eq!(plan.single, vec![root.to_path_buf(), root.join("a")]);
+        assert!(plan.recursive.is_empty());
+        assert_eq!(plan.polled, vec![root.join("a/b"), root.join("c")]);
+        assert_eq!(plan.watches(), 2);
+        let plan = plan_watches(&tree, 3);
+        assert_eq!(plan.single, vec![root.to_path_buf()]);
+        assert_eq!(plan.recursive, vec![(root.join("a"), 2)]);
+        assert_eq!(plan.polled, vec![root.join("c")]);
+
+        let plan = plan_watches(&tree, 0);
+        assert_eq!(plan.polled, vec![root.to_path_buf()]);
+        assert_eq!(plan.watches(), 0);
+
+        assert!(!near_limit(79, 100));
+        assert!(near_limit(80, 100));
+        assert!(near_limit(usize::MAX, 100));
+    }
+}
@@ -1,105 +1,139 @@
+pub mod event;
+pub mod limit;
+mod poll;
+pub mod service;
+pub mod supervisor;
 mod util;
 pub mod writer;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::{sync::mpsc, time::Duration};
+use std::time::Duration;
 
+use event::FsEvent;
+use lazy_static::lazy_static;
 use log::{debug, error, info, trace};
-use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use writer::DbAction;
 
 use crate::config::CONF;
-use crate::db::DB;
-
-use crate::cache::CACHER;
-use crate::util::is_blacklisted;
-
-pub fn file_checker(target: &str, db_sender: mpsc::Sender<DbAction>) {
-    let (tx, rx) = mpsc::channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(1)).unwrap();
-
-    // 监听指定目录
-    watcher
-        .watch(target, RecursiveMode::Recursive)
-        .expect("Failed to watch directory");
-
-    info!("Watching directory: {} for changes...", target);
-
-    loop {
-        match rx.recv() {
-            Ok(event) => match event {
-                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
-                    if is_blacklisted(&path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", path);
-                        continue;
-                    }
-                    new_event_handler(&path, &db_sender);
-                }
-                DebouncedEvent::Remove(path) => {
-                    if is_blacklisted(&path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", path);
-                        continue;
-                    }
-                    del_event_handler(&path, &db_sender);
-                }
-                DebouncedEvent::Rename(old_path, new_path) => {
-                    if is_blacklisted(&old_path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", old_path);
-                        continue;
-                    }
-                    info!("File renamed from {:?} to {:?}", old_path, new_path);
-                    del_event_handler(&old_path, &db_sender);
-                    new_event_handler(&new_path, &db_sender);
-                }
-                DebouncedEvent::NoticeWrite(path) => {
-                    if is_blacklisted(&path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", path);
-                        continue;
-                    }
-                    info!("File notice write: {:?}", path);
-                }
-                DebouncedEvent::NoticeRemove(path) => {
-                    if is_blacklisted(&path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", path);
-                        continue;
-                    }
-                    info!("File notice remove: {:?}", path);
-                }
-                DebouncedEvent::Chmod(path) => {
-                    if is_blacklisted(&path) {
-                        trace!("File: {:#?} is blacklisted, ignoring...", path);
-                        continue;
-                    }
-                    info!("File permissions changed: {:?}", path);
-                }
-                DebouncedEvent::Rescan => {
-                    info!("Directory rescan occurred.");
-                }
-                DebouncedEvent::Error(err, path) => {
-                    error!("An error occurred: {:?}, related path {:#?}", err, path);
-                }
-            },
-            Err(e) => error!("watch error: {:?}", e),
+use crate::db::{meta::EntryType, DB};
+
+use crate::cache::{
+    hot_dir::{record_access, rename_hot_dirs},
+    CACHER,
+};
+use crate::util::is_filtered;
+
+/// 对账溢出期间的变化时, 在最近一次事件的时间之前多检查的时长
+const RESCAN_SLACK: Duration = Duration::from_secs(2);
+
+/// 将去抖后的事件应用到缓存和数据库
+///
+/// 处于黑名单或被忽略文件排除的路径在去抖之后才检查, 同一路径的多个事件只检查一次;
+/// 新建和删除事件带有条目类型, 不需要读取磁盘
+pub fn fs_event_handler(event: FsEvent, db_sender: &Sender<DbAction>) {
+    let filtered = match &event {
+        FsEvent::Create { path, kind } | FsEvent::Remove { path, kind } => {
+            is_filtered(path, *kind == EntryType::Dir)
+        }
+        // 修改和打开事件几乎都来自文件
+        FsEvent::Write(path) | FsEvent::Open(path) | FsEvent::Metadata(path) => {
+            is_filtered(path, false)
+        }
+        FsEvent::Rename { .. } | FsEvent::Rescan(_) => false,
+    };
+    if filtered {
+        trace!("File: {:#?} is filtered, ignoring...", event);
+        return;
+    }
+    match event {
+        FsEvent::Create { path, .. } => new_event_handler(&path, db_sender),
+        FsEvent::Write(path) => touch_event_handler(&path, db_sender, true, true),
+        FsEvent::Open(path) => {
+            // 遍历文件夹也会打开文件夹, 只统计文件的打开
+            if path.is_file() {
+                touch_event_handler(&path, db_sender, false, true);
+            }
+        }
+        FsEvent::Metadata(path) => touch_event_handler(&path, db_sender, true, false),
+        FsEvent::Remove { path, .. } => del_event_handler(&path, db_sender),
+        FsEvent::Rename { from, to } => rename_event_handler(&from, &to, db_sender),
+        FsEvent::Rescan(path) => {
+            info!("Directory rescan occurred: {:?}", path);
         }
     }
 }
 
+/// 数据库文件以及快照等保存在数据库旁的文件, 包括写入时使用的临时文件
+fn database_files(db_path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![db_path.to_path_buf()];
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let mut name = db_path.as_os_str().to_os_string();
+        name.push(suffix);
+        files.push(PathBuf::from(name));
+    }
+    for extension in [
+        "snapshot",
+        "snapshot.tmp",
+        "hotdirs",
+        "hotdirs.tmp",
+        "compact",
+    ] {
+        files.push(db_path.with_extension(extension));
+    }
+    files
+}
+
+lazy_static! {
+    static ref DATABASE_FILES: Vec<PathBuf> = database_files(&CONF.database.path);
+}
+
+/// 数据库相关的文件由本程序产生, 它们的变化不需要记录; 数据库旁的其他文件照常记录
+fn is_database_file(path: &Path) -> bool {
+    DATABASE_FILES.iter().any(|file| file == path)
+}
+
+/// 新建或访问, 访问计数加 1
 pub fn new_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
-    if path.eq(&CONF.database.path) {
+    touch_event_handler(path, db_sender, false, true)
+}
+
+/// 路径被新建、修改、打开或元数据变化
+///
+/// `refresh` 时从磁盘重新读取大小和修改时间, `update_count` 时访问计数加 1
+fn touch_event_handler(
+    path: &PathBuf,
+    db_sender: &Sender<DbAction>,
+    refresh: bool,
+    update_count: bool,
+) {
+    if is_database_file(path) {
         debug!("Database file changed, ignoring...");
         return;
     }
 
-    let mut cacher_guard = match CACHER.lock() {
+    let mut cacher_guard = match CACHER.write() {
         Ok(guard) => guard,
         Err(e) => panic!("lock cache error: {}", e),
     };
 
     // 判断path是文件夹还是文件
-    info!("new_event_handler: File created or wirte: {:?}", path);
+    info!("touch_event_handler: File created or changed: {:?}", path);
+
+    let mut cached = if update_count {
+        cacher_guard.search_path(path, true)
+    } else {
+        None
+    };
+    if refresh {
+        cached = cacher_guard.refresh_path(path).or(cached);
+    }
 
-    if let Some(meta) = cacher_guard.search_path(&path, true) {
+    if let Some(meta) = cached {
+        drop(cacher_guard);
+        if update_count {
+            record_access(path, meta.entry_type);
+        }
         // 缓存中存在, 更新数据库
         match db_sender.send(DbAction::UPDATE(path.clone(), meta)) {
             Ok(_) => {}
@@ -117,27 +151,20 @@ pub fn new_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
         let mut update_data = None;
         let mut need_create = true;
 
-        match DB.lock() {
-            Ok(db_guard) => {
-                if let Ok(Some(meta)) = db_guard.find_by_path(&path) {
-                    println!("find path {:#?} in db, no need update db", &path);
-                    // 数据库查询到后还需要更新到缓存
-                    update_data = Some(meta);
-                    // 数据库中存在, 不需要更新数据库
-                    need_create = false;
-                }
-            }
-            Err(e) => {
-                error!("lock db error: {}", e);
-            }
+        if let Ok(Some(meta)) = DB.find_by_path(path) {
+            println!("find path {:#?} in db, no need update db", &path);
+            // 数据库查询到后还需要更新到缓存
+            update_data = Some(meta);
+            // 数据库中存在, 不需要更新数据库
+            need_create = false;
         }
 
-        let mut cacher_guard = match CACHER.lock() {
+        let mut cacher_guard = match CACHER.write() {
             Ok(guard) => guard,
             Err(e) => panic!("lock cache error: {}", e),
         };
 
-        match cacher_guard.add_path(&path, update_data.clone(), true) {
+        match cacher_guard.add_path(path, update_data.clone(), update_count) {
             Ok(meta) => {
                 update_data = meta;
             }
@@ -148,10 +175,15 @@ pub fn new_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
 
         drop(cacher_guard); // 提前释放cache锁
 
-        if need_create {
+        if let (Some(meta), true) = (&update_data, update_count) {
+            record_access(path, meta.entry_type);
+        }
+
+        // 路径在事件到达前已被删除时没有元数据, 等待删除事件处理
+        if let (true, Some(meta)) = (need_create, update_data) {
             println!("not find path {:#?} in db, need to update db", &path);
             // 如果数据库没有查到数据, 则是新增数据, 需要插入数据库
-            match db_sender.send(DbAction::CREATE(path.clone(), update_data.unwrap())) {
+            match db_sender.send(DbAction::CREATE(path.clone(), meta)) {
                 Ok(_) => {}
                 Err(e) => {
                     error!("send create DbAction error: {}", e)
@@ -161,18 +193,75 @@ pub fn new_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
     }
 }
 
+/// 重命名或移动, 缓存中的子树和数据库中的记录整体移动到新路径, 访问计数等元数据随之保留
+fn rename_event_handler(from: &PathBuf, to: &PathBuf, db_sender: &Sender<DbAction>) {
+    if is_database_file(from) || is_database_file(to) {
+        debug!("Database file renamed, ignoring...");
+        return;
+    }
+    info!("rename_event_handler: {:?} renamed to {:?}", from, to);
+    // 移入黑名单或被忽略的位置相当于删除, 从中移出相当于新建
+    let is_dir = to.is_dir();
+    match (is_filtered(from, is_dir), is_filtered(to, is_dir)) {
+        (true, true) => {
+            trace!("File: {:#?} is filtered, ignoring...", to);
+            return;
+        }
+        (false, true) => {
+            del_event_handler(from, db_sender);
+            return;
+        }
+        (true, false) => {
+            new_event_handler(to, db_sender);
+            return;
+        }
+        (false, false) => {}
+    }
+
+    let mut cacher_guard = CACHER.write().unwrap();
+    let cached = cacher_guard.move_path(from, to).is_ok();
+    // 跨文件系统的移动会改变修改时间等元数据
+    let refreshed = if cached {
+        cacher_guard.refresh_path(to)
+    } else {
+        None
+    };
+    drop(cacher_guard);
+
+    // 缓存中的条目可能已被淘汰, 再查数据库
+    let indexed = cached
+        || DB
+            .find_by_path_prefix(from)
+            .is_ok_and(|metas| metas.iter().any(|meta| meta.path.starts_with(from)));
+    if !indexed {
+        // 移动的是尚未索引的路径, 按新建处理
+        new_event_handler(to, db_sender);
+        return;
+    }
+
+    rename_hot_dirs(from, to);
+    if let Err(e) = db_sender.send(DbAction::RENAME(from.clone(), to.clone())) {
+        error!("send rename DbAction error: {}", e);
+    }
+    if let Some(meta) = refreshed {
+        if let Err(e) = db_sender.send(DbAction::UPDATE(to.clone(), meta)) {
+            error!("send update DbAction error: {}", e);
+        }
+    }
+}
+
 #[allow(unused)]
 fn del_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
-    if path.eq(&CONF.database.path) {
+    if is_database_file(path) {
         debug!("Database file changed, ignoring...");
         return;
     }
 
     info!("del_event_handler: File removed: {:?}", path);
 
-    let mut cacher_guard = CACHER.lock().unwrap();
+    let mut cacher_guard = CACHER.write().unwrap();
 
-    cacher_guard.remove_path(&path); // 缓存删除
+    cacher_guard.remove_path(path); // 缓存删除
 
     drop(cacher_guard); // 提前释放cache 锁避免死锁
 
@@ -185,3 +274,27 @@ fn del_event_handler(path: &PathBuf, db_sender: &Sender<DbAction>) {
 
     debug!("del_event_handler: send del: {:#?} DbAction success", &path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_files() {
+        let files = database_files(Path::new("/data/sqlite3.db"));
+        for name in [
+            "sqlite3.db",
+            "sqlite3.db-wal",
+            "sqlite3.db-shm",
+            "sqlite3.db-journal",
+            "sqlite3.snapshot",
+            "sqlite3.hotdirs",
+        ] {
+            assert!(files.contains(&Path::new("/data").join(name)), "{}", name);
+        }
+        // 与数据库同名开头的其他文件照常记录
+        for name in ["sqlite3-notes.md", "sqlite3.txt", "sqlite3.db.bak"] {
+            assert!(!files.contains(&Path::new("/data").join(name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! 轮询监听
+//!
+//! 网络文件系统(NFS、SMB、sshfs)和 FUSE 上其他机器的修改不会产生文件事件, inotify watch 不够用的子树
+//! 也收不到事件。这些子树由后台线程定期扫描: 从根向下若干层, 再加上缓存中已经索引过的文件夹,
+//! 将扫描到的大小和修改时间与缓存中的条目比较, 生成与系统文件事件相同的 notify 事件放进事件队列,
+//! 之后的去抖和处理与原生监听完全相同。
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind, RemoveKind},
+    Event, EventHandler, EventKind,
+};
+
+use crate::{
+    cache::{rescan::changed, Cacher, CACHER},
+    db::{meta::EntryType, Database, EntryMeta, DB},
+    util::is_filtered,
+};
+
+use super::RESCAN_SLACK;
+
+/// 轮询间隔的下限, 配置为 0 时不会连续不断地扫描
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 在后台定期扫描一个子树, 丢弃时停止
+pub(super) struct Poller {
+    _stop: Sender<()>,
+}
+
+impl Poller {
+    /// 每隔 `interval` 扫描一次 `root`, 从启动时开始比较, 之前的变化由调用方对账
+    pub(super) fn spawn(
+        root: PathBuf,
+        depth: usize,
+        interval: Duration,
+        mut handler: impl EventHandler,
+    ) -> Poller {
+        let interval = interval.max(MIN_POLL_INTERVAL);
+        let (stop, stopped) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let mut since = SystemTime::now();
+            // 发送端被丢弃后停止
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let started = SystemTime::now();
+                let events = scan(
+                    &DB,
+                    &CACHER,
+                    &root,
+                    depth,
+                    since.checked_sub(RESCAN_SLACK).unwrap_or(since),
+                );
+                if !events.is_empty() {
+                    debug!("poll {:#?}: {} changes", root, events.len());
+                }
+                for event in events {
+                    handler.handle_event(Ok(event));
+                }
+                since = started;
+            }
+            info!("stop polling {:#?}", root);
+        });
+        Poller { _stop: stop }
+    }
+}
+
+/// 扫描 `root` 并与缓存比较, 返回变化对应的事件
+///
+/// 扫描从 `root` 向下 `depth` 层的文件夹和已索引的条目所在的文件夹; 尚未索引的条目在 `since`
+/// 之后修改过才视为新建, 与对账相同。已被淘汰出缓存的条目从数据库中读取, 同样会被检查
+pub(super) fn scan(
+    db: &Arc<dyn Database>,
+    cacher: &RwLock<Cacher>,
+    root: &Path,
+    depth: usize,
+    since: SystemTime,
+) -> Vec<Event> {
+    let mut events = Vec::new();
+    // 目标本身不在时可能是被卸载了, 由监听服务处理
+    if !root.is_dir() {
+        return events;
+    }
+    let mut indexed: HashMap<PathBuf, EntryMeta> = match db.find_by_path_prefix(&root.to_path_buf())
+    {
+        Ok(metas) => metas
+            .into_iter()
+            .map(|meta| (meta.path.clone(), meta))
+            .collect(),
+        Err(e) => {
+            error!("find records under {:#?} error: {}", root, e);
+            HashMap::new()
+        }
+    };
+    // 缓存中的记录可能比数据库中的新
+    for meta in cacher.read().unwrap().entries_under(root) {
+        indexed.insert(meta.path.clone(), meta);
+    }
+    indexed.retain(|path, _| path.starts_with(root) && path != root);
+    let mut known: BTreeSet<PathBuf> = indexed
+        .keys()
+        .filter_map(|path| path.parent())
+        .chain(
+            indexed
+                .values()
+                .filter(|meta| meta.entry_type == EntryType::Dir)
+                .map(|meta| meta.path.as_path()),
+        )
+        .map(Path::to_path_buf)
+        .collect();
+
+    let mut queue = VecDeque::from([(root.to_path_buf(), 0)]);
+    let mut visited = HashSet::new();
+    // 读取失败的文件夹, 不能据此判断其中的条目被删除
+    let mut failed = HashSet::new();
+    let mut seen = HashSet::new();
+    loop {
+        // 先按层扫描, 再扫描更深处已索引的文件夹
+        let (dir, level) = match queue.pop_front() {
+            Some(next) => next,
+            None => match known.pop_first() {
+                Some(dir) => (dir, depth),
+                None => break,
+            },
+        };
+        if !visited.insert(dir.clone()) {
+            continue;
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // 文件夹已被删除, 其中已索引的条目都视为删除
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                debug!("read dir {:#?} error: {}", dir, e);
+                failed.insert(dir);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(fresh) = EntryMeta::new(&path) else {
+                continue;
+            };
+            // 已经索引的条目后来被排除时保留在索引中, 不视为删除
+            seen.insert(path.clone());
+            if is_filtered(&path, fresh.entry_type == EntryType::Dir) {
+                continue;
+            }
+            if fresh.entry_type == EntryType::Dir && level < depth {
+                queue.push_back((path.clone(), level + 1));
+            }
+            match indexed.get(&path) {
+                Some(meta) if meta.entry_type != fresh.entry_type => {
+                    events.push(remove_event(&path, meta.entry_type));
+                    events.push(create_event(&path, fresh.entry_type));
+                }
+                // 文件夹的修改时间随其中条目的增删变化, 条目的变化单独产生事件
+                Some(meta) if fresh.entry_type != EntryType::Dir && changed(meta, &fresh) => {
+                    events.push(
+                        Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                            .add_path(path.clone()),
+                    );
+                }
+                Some(_) => {}
+                None if fresh.modified >= since => {
+                    events.push(create_event(&path, fresh.entry_type));
+                }
+                None => {}
+            }
+        }
+    }
+
+    for (path, meta) in &indexed {
+        let Some(parent) = path.parent() else {
+            continue;
+        };
+        if !seen.contains(path) && visited.contains(parent) && !failed.contains(parent) {
+            events.push(remove_event(path, meta.entry_type));
+        }
+    }
+    events
+}
+
+fn create_event(path: &Path, kind: EntryType) -> Event {
+    let kind = match kind {
+        EntryType::File => CreateKind::File,
+        EntryType::Dir => CreateKind::Folder,
+        EntryType::Unknown => CreateKind::Any,
+    };
+    Event::new(EventKind::Create(kind)).add_path(path.to_path_buf())
+}
+
+fn remove_event(path: &Path, kind: EntryType) -> Event {
+    let kind = match kind {
+        EntryType::File => RemoveKind::File,
+        EntryType::Dir => RemoveKind::Folder,
+        EntryType::Unknown => RemoveKind::Any,
+    };
+    Event::new(EventKind::Remove(kind)).add_path(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{DbWriter, MemoryDatabase},
+        util::test_util::TempDir,
+    };
+
+    fn kinds(events: &[Event]) -> Vec<(EventKind, PathBuf)> {
+        let mut kinds: Vec<_> = events
+            .iter()
+            .map(|event| (event.kind, event.paths[0].clone()))
+            .collect();
+        kinds.sort_by(|a, b| a.1.cmp(&b.1));
+        kinds
+    }
+
+    #[test]
+    fn test_scan() {
+        let root = TempDir::new("poll");
+        let db: Arc<dyn Database> = Arc::new(MemoryDatabase::new());
+        let cacher = RwLock::new(Cacher::new());
+        let deep = root.join("a/b/c");
+        fs::create_dir_all(&deep).unwrap();
+        let kept = root.join("kept.txt");
+        let changed = root.join("changed.txt");
+        let removed = deep.join("removed.txt");
+        for path in [&kept, &changed, &removed] {
+            fs::write(path, b"old").unwrap();
+            cacher.write().unwrap().add_path(path, None, false).unwrap();
+        }
+        let since = SystemTime::now();
+        assert!(scan(&db, &cacher, &root, 0, since).is_empty());
+        thread::sleep(Duration::from_millis(50));
+
+        fs::write(&changed, b"new content").unwrap();
+        fs::remove_file(&removed).unwrap();
+        let created = root.join("created.txt");
+        fs::write(&created, b"x").unwrap();
+        // 超过扫描层数且没有索引过的文件夹中的新文件发现不了
+        fs::write(root.join("a/b/missed.txt"), b"x").unwrap();
+
+        let events = scan(&db, &cacher, &root, 0, since);
+        assert_eq!(
+            kinds(&events),
+            vec![
+                (EventKind::Remove(RemoveKind::File), removed.clone()),
+                (
+                    EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                    changed.clone()
+                ),
+                (EventKind::Create(CreateKind::File), created.clone()),
+            ]
+        );
+        // 向下扫描两层后可以发现
+        assert!(kinds(&scan(&db, &cacher, &root, 2, since)).contains(&(
+            EventKind::Create(CreateKind::File),
+            root.join("a/b/missed.txt")
+        )));
+
+        // 已经索引的条目被忽略文件排除后不产生删除事件
+        fs::write(root.join(".elfignore"), "kept.txt\n").unwrap();
+        crate::util::ignore_files::invalidate_ignore(&root.join(".elfignore"));
+        assert!(!kinds(&scan(&db, &cacher, &root, 0, since))
+            .iter()
+            .any(|(_, path)| path == &kept));
+
+        // 被淘汰出缓存、只在数据库中的条目同样检查
+        let evicted = root.join("evicted.txt");
+        fs::write(&evicted, b"old").unwrap();
+        db.insert_rec(&evicted, &EntryMeta::new(&evicted).unwrap())
+            .unwrap();
+        let gone = root.join("gone.txt");
+        let mut meta = EntryMeta::new_empty().unwrap();
+        meta.entry_type = EntryType::File;
+        db.insert_rec(&gone, &meta).unwrap();
+        fs::write(&evicted, b"new content").unwrap();
+        let events = kinds(&scan(&db, &cacher, &root, 0, SystemTime::now()));
+        assert!(events.contains(&(
+            EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+            evicted
+        )));
+        assert!(events.contains(&(EventKind::Remove(RemoveKind::File), gone)));
+    }
+}
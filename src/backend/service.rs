@@ -0,0 +1,850 @@
+//! 多路复用的监听服务
+//!
+//! 所有监听目标共用一个 notify 监听器、一个事件循环和一个去抖器, 缓存和数据库只由事件循环所在的
+//! 线程更新。事件经过有界队列进入事件循环, 队列满时丢弃事件, 之后对所有目标对账。
+//! 目标可以在运行时添加和移除, 不存在或监听失败的目标按退避时间重试。
+//! `poll` 模式的目标和 inotify watch 不够用的子树改用轮询扫描, 轮询产生的事件同样进入事件队列。
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use notify::{
+    event::{CreateKind, ModifyKind, RenameMode},
+    ErrorKind, Event, EventHandler, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use crate::{
+    cache::rescan::spawn_rescan,
+    config::{TargetOptions, WatchMode, CONF},
+    db::DB,
+    util::{errors::CustomError, ignore_files::invalidate_ignore, is_blacklisted},
+};
+
+use super::{
+    event::{Debouncer, FsEvent},
+    fs_event_handler,
+    limit::{dir_tree, max_user_watches, near_limit, plan_watches, DirTree, WatchPlan},
+    poll::Poller,
+    supervisor::{remove_status, set_state, update_status, watcher_targets, Backoff, WatcherState},
+    writer::DbAction,
+    RESCAN_SLACK,
+};
+
+/// 检查已监听的目标是否还在的间隔
+const TARGET_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// 监听连续失败这么多次后放弃该目标, 目标不存在时的重试不计入
+pub(super) const MAX_FAILURES: u32 = 10;
+
+/// 进入事件循环的消息
+pub(super) enum Message {
+    Event(notify::Result<Event>),
+    Add(String),
+    Remove(String),
+    /// 后台线程统计完目标中的文件夹
+    Counted(String, DirTree),
+}
+
+lazy_static! {
+    /// 正在运行的事件循环的消息队列, 用于在运行时添加和移除目标
+    static ref COMMANDS: Mutex<Option<SyncSender<Message>>> = Mutex::new(None);
+}
+
+/// 一个监听目标在事件循环中的状态
+struct Target {
+    path: PathBuf,
+    options: TargetOptions,
+    watched: bool,
+    /// 监听连续失败的次数
+    failures: u32,
+    /// 目标连续不存在的次数
+    missing: u32,
+    /// 下一次尝试监听的时间, None 表示已监听或已放弃
+    next_attempt: Option<Instant>,
+    /// 失去监听的时间, 重新监听后对账这之后的变化
+    lost: Option<SystemTime>,
+    /// 正在后台统计目标中的文件夹
+    counting: bool,
+    /// 占用的 inotify watch 数量
+    watches: usize,
+    /// 加上了 inotify watch 的路径, 停止监听时逐个移除
+    dirs: Vec<PathBuf>,
+    /// 只监听自身的文件夹, 其中新建的文件夹需要单独监听
+    single: Vec<PathBuf>,
+    /// 改用轮询扫描的子树
+    polled: Vec<PathBuf>,
+}
+
+impl Target {
+    fn new(target: &str, options: TargetOptions, now: Instant, lost: Option<SystemTime>) -> Target {
+        Target {
+            path: PathBuf::from(target),
+            options,
+            watched: false,
+            failures: 0,
+            missing: 0,
+            next_attempt: Some(now),
+            lost,
+            counting: false,
+            watches: 0,
+            dirs: Vec::new(),
+            single: Vec::new(),
+            polled: Vec::new(),
+        }
+    }
+}
+
+pub(super) struct WatchService {
+    watcher: RecommendedWatcher,
+    /// 轮询扫描的子树, 移除时停止轮询
+    pollers: HashMap<PathBuf, Poller>,
+    /// 轮询的事件也通过这个队列进入事件循环
+    events: SyncSender<Message>,
+    /// inotify watch 的总数限制, None 表示没有限制
+    watch_limit: Option<usize>,
+    rx: Receiver<Message>,
+    targets: BTreeMap<String, Target>,
+    debouncer: Debouncer,
+    backoff: Backoff,
+    /// 队列满时由 notify 的回调记下第一次丢弃事件的时间
+    overflowed: Arc<Mutex<Option<SystemTime>>>,
+    /// 最近一次收到事件的时间, 事件丢失时从这之前开始对账
+    last_received: SystemTime,
+    last_check: Instant,
+    db_sender: Sender<DbAction>,
+}
+
+impl WatchService {
+    pub(super) fn new(
+        capacity: usize,
+        backoff: Backoff,
+        db_sender: Sender<DbAction>,
+    ) -> Result<(WatchService, SyncSender<Message>), CustomError> {
+        let (tx, rx) = mpsc::sync_channel(capacity.max(1));
+        let overflowed = Arc::new(Mutex::new(None));
+        let watcher = notify::recommended_watcher(event_handler(&tx, &overflowed))?;
+        let service = WatchService {
+            watcher,
+            pollers: HashMap::new(),
+            events: tx.clone(),
+            watch_limit: max_user_watches(),
+            rx,
+            targets: BTreeMap::new(),
+            debouncer: Debouncer::new(Duration::from_millis(CONF.database.watch_debounce)),
+            backoff,
+            overflowed,
+            last_received: SystemTime::now(),
+            last_check: Instant::now(),
+            db_sender,
+        };
+        Ok((service, tx))
+    }
+
+    /// 加入目标并尝试监听, `lost` 为之前失去监听的时间
+    fn add(&mut self, target: &str, lost: Option<SystemTime>, now: Instant) {
+        let options = CONF.database.target_options(target);
+        self.add_with_options(target, options, lost, now);
+    }
+
+    fn add_with_options(
+        &mut self,
+        target: &str,
+        options: TargetOptions,
+        lost: Option<SystemTime>,
+        now: Instant,
+    ) {
+        if self.targets.contains_key(target) {
+            return;
+        }
+        update_status(target, |status| status.mode = options.mode);
+        self.targets
+            .insert(target.to_string(), Target::new(target, options, now, lost));
+        self.try_watch(target, now);
+    }
+
+    fn remove(&mut self, target: &str) {
+        self.unwatch(target);
+        if self.targets.remove(target).is_some() {
+            info!("stop watching {}", target);
+        }
+        remove_status(target);
+    }
+
+    /// 释放目标的 inotify watch 和轮询
+    fn unwatch(&mut self, target: &str) {
+        let Some(entry) = self.targets.get_mut(target) else {
+            return;
+        };
+        if !entry.watched {
+            return;
+        }
+        for dir in entry.dirs.drain(..) {
+            if let Err(e) = self.watcher.unwatch(&dir) {
+                warn!("unwatch {:#?} error: {}", dir, e);
+            }
+        }
+        entry.single.clear();
+        for path in entry.polled.drain(..) {
+            self.pollers.remove(&path);
+        }
+        entry.watched = false;
+        entry.watches = 0;
+        publish_watches(target, entry);
+    }
+
+    /// 已用的 inotify watch 接近限制时警告
+    fn warn_near_limit(&self) {
+        let Some(limit) = self.watch_limit else {
+            return;
+        };
+        let used = self.used_watches();
+        if near_limit(used, limit) {
+            warn!(
+                "{} of {} inotify watches used, consider raising fs.inotify.max_user_watches",
+                used, limit
+            );
+        }
+    }
+
+    /// 所有目标占用的 inotify watch 数量
+    fn used_watches(&self) -> usize {
+        self.targets.values().map(|entry| entry.watches).sum()
+    }
+
+    /// 开始轮询扫描子树
+    fn poll(&mut self, path: &Path, depth: usize) {
+        let poller = Poller::spawn(
+            path.to_path_buf(),
+            depth,
+            Duration::from_secs(CONF.database.poll_interval),
+            event_handler(&self.events, &self.overflowed),
+        );
+        self.pollers.insert(path.to_path_buf(), poller);
+    }
+
+    /// 在后台线程中统计目标中的文件夹, 完成后回到事件循环监听
+    fn count(&mut self, target: &str) {
+        let Some(entry) = self.targets.get_mut(target) else {
+            return;
+        };
+        entry.counting = true;
+        entry.next_attempt = None;
+        set_state(target, WatcherState::Starting, None, None);
+        let (target, path, events) = (target.to_string(), entry.path.clone(), self.events.clone());
+        thread::spawn(move || {
+            let tree = dir_tree(&path);
+            // 事件循环已经退出时丢弃
+            let _ = events.send(Message::Counted(target, tree));
+        });
+    }
+
+    /// 在剩余的 inotify watch 中尽量多地监听 `tree`, 放不下的子树轮询
+    fn watch_tree(&mut self, tree: &DirTree, options: &TargetOptions) -> notify::Result<WatchPlan> {
+        let used = self.used_watches();
+        let budget = self
+            .watch_limit
+            .map_or(usize::MAX, |limit| limit.saturating_sub(used));
+        let planned = plan_watches(tree, budget);
+        let mut plan = WatchPlan {
+            single: Vec::new(),
+            ..planned
+        };
+        for dir in &planned.single {
+            if let Err(e) = self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+                for dir in &plan.single {
+                    let _ = self.watcher.unwatch(dir);
+                }
+                return Err(e);
+            }
+            plan.single.push(dir.clone());
+        }
+        let mut recursive = Vec::new();
+        for (dir, watches) in plan.recursive.drain(..) {
+            match self.watcher.watch(&dir, RecursiveMode::Recursive) {
+                Ok(()) => recursive.push((dir, watches)),
+                // 其他程序同时也在占用 watch, 同样改为轮询
+                Err(e) if matches!(e.kind, ErrorKind::MaxFilesWatch) => {
+                    let _ = self.watcher.unwatch(&dir);
+                    plan.polled.push(dir);
+                }
+                Err(e) => {
+                    for dir in plan
+                        .single
+                        .iter()
+                        .chain(recursive.iter().map(|(dir, _)| dir))
+                    {
+                        let _ = self.watcher.unwatch(dir);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        plan.recursive = recursive;
+        if !plan.polled.is_empty() {
+            warn!(
+                "inotify watch limit reached ({} needed, {} used, limit {:?}), poll {} subtrees of {:#?} every {}s",
+                tree.total,
+                used,
+                self.watch_limit,
+                plan.polled.len(),
+                tree.path,
+                CONF.database.poll_interval
+            );
+        }
+        for dir in plan.polled.clone() {
+            self.poll(&dir, options.poll_depth);
+        }
+        Ok(plan)
+    }
+
+    /// 只监听自身的文件夹中新建或移入的文件夹不会被自动监听, 单独递归监听, 放不下时轮询
+    fn watch_new_dir(&mut self, event: &Event) {
+        let path = match event.kind {
+            EventKind::Create(CreateKind::Folder)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event.paths.first(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => event.paths.get(1),
+            _ => None,
+        };
+        let Some(path) = path else {
+            return;
+        };
+        let Some((target, entry)) = self.targets.iter_mut().find(|(_, entry)| {
+            path.parent()
+                .is_some_and(|parent| entry.single.iter().any(|dir| dir == parent))
+        }) else {
+            return;
+        };
+        // 只有这种少见的情况需要读取磁盘
+        if !path.is_dir() {
+            return;
+        }
+        match self.watcher.watch(path, RecursiveMode::Recursive) {
+            Ok(()) => {
+                entry.watches += 1;
+                entry.dirs.push(path.clone());
+                publish_watches(target, entry);
+            }
+            Err(e) if matches!(e.kind, ErrorKind::MaxFilesWatch) => {
+                let _ = self.watcher.unwatch(path);
+                self.poll_subtrees(vec![path.clone()]);
+            }
+            Err(e) => warn!("watch {:#?} error: {}", path, e),
+        }
+    }
+
+    /// 监听过程中新建的文件夹拿不到 inotify watch, 改为轮询这些子树
+    fn poll_subtrees(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            let Some((target, entry)) = self
+                .targets
+                .iter_mut()
+                .find(|(_, entry)| entry.watched && path.starts_with(&entry.path))
+            else {
+                continue;
+            };
+            if entry.polled.iter().any(|polled| path.starts_with(polled)) {
+                continue;
+            }
+            warn!(
+                "inotify watch limit reached, poll {:#?} every {}s",
+                path, CONF.database.poll_interval
+            );
+            let poller = Poller::spawn(
+                path.clone(),
+                entry.options.poll_depth,
+                Duration::from_secs(CONF.database.poll_interval),
+                event_handler(&self.events, &self.overflowed),
+            );
+            self.pollers.insert(path.clone(), poller);
+            entry.polled.push(path);
+            publish_watches(target, entry);
+        }
+    }
+
+    fn try_watch(&mut self, target: &str, now: Instant) {
+        let Some(entry) = self.targets.get_mut(target) else {
+            return;
+        };
+        // 目标不存在时一直等待, 直到目标出现
+        if !entry.path.exists() {
+            let delay = self.backoff.delay(entry.missing);
+            entry.missing += 1;
+            entry.next_attempt = Some(now + delay);
+            set_state(
+                target,
+                WatcherState::Retrying,
+                Some(format!("target {} does not exist", target)),
+                Some(delay),
+            );
+            return;
+        }
+        entry.missing = 0;
+
+        let (path, options) = (entry.path.clone(), entry.options.clone());
+        let res = if options.mode == WatchMode::Poll {
+            info!("poll {:#?} every {}s", path, CONF.database.poll_interval);
+            self.poll(&path, options.poll_depth);
+            Ok(WatchPlan {
+                polled: vec![path],
+                ..WatchPlan::default()
+            })
+        } else if self.watch_limit.is_some() {
+            // 统计文件夹需要遍历整个目标, 不能阻塞事件循环
+            self.count(target);
+            return;
+        } else {
+            self.watcher
+                .watch(&path, RecursiveMode::Recursive)
+                .map(|()| WatchPlan {
+                    recursive: vec![(path, 0)],
+                    ..WatchPlan::default()
+                })
+        };
+        self.finish_watch(target, res, now);
+    }
+
+    /// 根据监听的结果更新目标的状态, 失败时按退避时间重试
+    fn finish_watch(&mut self, target: &str, res: notify::Result<WatchPlan>, now: Instant) {
+        let Some(entry) = self.targets.get_mut(target) else {
+            return;
+        };
+        match res {
+            Ok(plan) => {
+                let watches = plan.watches();
+                entry.watched = true;
+                entry.watches = watches;
+                entry.dirs = plan
+                    .single
+                    .iter()
+                    .cloned()
+                    .chain(plan.recursive.into_iter().map(|(dir, _)| dir))
+                    .collect();
+                entry.single = plan.single;
+                entry.polled = plan.polled;
+                publish_watches(target, entry);
+                entry.failures = 0;
+                entry.next_attempt = None;
+                set_state(target, WatcherState::Running, None, None);
+                info!(
+                    "Watching directory: {} for changes, {} inotify watches",
+                    target, watches
+                );
+                // 失去监听期间的变化已经丢失, 重新开始监听后对账
+                if let Some(lost) = entry.lost.take() {
+                    let since = lost.checked_sub(RESCAN_SLACK).unwrap_or(lost);
+                    spawn_rescan(DB.clone(), entry.path.clone(), since);
+                }
+                self.warn_near_limit();
+            }
+            Err(e) => {
+                entry.failures += 1;
+                let reason = format!("watch {} error: {}", target, e);
+                if entry.failures >= MAX_FAILURES {
+                    error!("give up watching {}: {}", target, reason);
+                    entry.next_attempt = None;
+                    set_state(target, WatcherState::Failed, Some(reason), None);
+                } else {
+                    let delay = self.backoff.delay(entry.failures - 1);
+                    warn!("{}, retry in {:?}", reason, delay);
+                    entry.next_attempt = Some(now + delay);
+                    set_state(target, WatcherState::Retrying, Some(reason), Some(delay));
+                    update_status(target, |status| status.restarts += 1);
+                }
+            }
+        }
+    }
+
+    /// 重试到期的目标, 并定期检查已监听的目标是否被删除或卸载
+    fn maintain(&mut self, now: Instant) {
+        if now.duration_since(self.last_check) >= TARGET_CHECK_INTERVAL {
+            self.last_check = now;
+            let lost: Vec<String> = self
+                .targets
+                .iter()
+                .filter(|(_, entry)| entry.watched && !entry.path.is_dir())
+                .map(|(target, _)| target.clone())
+                .collect();
+            for target in lost {
+                warn!("target {} is no longer available", target);
+                // 目标已经不在, 原来的监听也已失效
+                self.unwatch(&target);
+                let entry = self.targets.get_mut(&target).unwrap();
+                entry.lost = Some(SystemTime::now());
+                entry.next_attempt = Some(now);
+            }
+        }
+
+        let due: Vec<String> = self
+            .targets
+            .iter()
+            .filter(|(_, entry)| entry.next_attempt.is_some_and(|at| at <= now))
+            .map(|(target, _)| target.clone())
+            .collect();
+        for target in due {
+            self.try_watch(&target, now);
+        }
+    }
+
+    /// 下一次需要处理的时间: 事件稳定、目标重试或定期检查
+    fn next_deadline(&self) -> Instant {
+        self.targets
+            .values()
+            .filter_map(|entry| entry.next_attempt)
+            .chain(self.debouncer.next_deadline())
+            .fold(self.last_check + TARGET_CHECK_INTERVAL, Instant::min)
+    }
+
+    /// 对子树或所有已监听的目标对账, 检查 `since` 之后的变化
+    fn rescan(&self, root: Option<PathBuf>, since: SystemTime) {
+        // 修改时间的精度有限, 多留出一些余量
+        let since = since.checked_sub(RESCAN_SLACK).unwrap_or(since);
+        let roots = match root {
+            Some(root) => vec![root],
+            None => self
+                .targets
+                .values()
+                .filter(|entry| entry.watched)
+                .map(|entry| entry.path.clone())
+                .collect(),
+        };
+        for root in roots {
+            info!("event queue overflowed, rescan {:#?}", root);
+            spawn_rescan(DB.clone(), root, since);
+        }
+    }
+
+    fn handle(&mut self, message: Message, now: Instant) {
+        match message {
+            Message::Event(Ok(event)) => {
+                // 只比较文件名, 被排除的路径在去抖之后由 `fs_event_handler` 跳过
+                for path in &event.paths {
+                    invalidate_ignore(path);
+                }
+                self.watch_new_dir(&event);
+                if !event.need_rescan() {
+                    self.last_received = SystemTime::now();
+                }
+                self.debouncer.push(event, now);
+            }
+            Message::Event(Err(e)) if matches!(e.kind, ErrorKind::MaxFilesWatch) => {
+                self.poll_subtrees(e.paths)
+            }
+            Message::Event(Err(e)) => error!("watch error: {:?}", e),
+            // 与启动时的目标相同, 不预先扫描, 条目随事件和访问逐步索引
+            Message::Add(target) => self.add(&target, None, now),
+            Message::Remove(target) => self.remove(&target),
+            Message::Counted(target, tree) => {
+                // 统计期间目标可能已被移除
+                let Some(entry) = self.targets.get_mut(&target) else {
+                    return;
+                };
+                if !entry.counting {
+                    return;
+                }
+                entry.counting = false;
+                let options = entry.options.clone();
+                let res = self.watch_tree(&tree, &options);
+                self.finish_watch(&target, res, now);
+            }
+        }
+    }
+
+    /// 事件循环, 只在事件队列断开时返回
+    fn run(&mut self) -> Result<(), CustomError> {
+        loop {
+            let wait = self
+                .next_deadline()
+                .saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(wait) {
+                Ok(message) => self.handle(message, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(CustomError::from("watch service disconnected"));
+                }
+            }
+
+            // 队列中积压的事件可能比最近处理的事件早很多, 从第一次丢弃事件时开始对账
+            let dropped = self.overflowed.lock().unwrap().take();
+            if let Some(since) = dropped {
+                self.rescan(None, since);
+            }
+            let now = Instant::now();
+            self.maintain(now);
+            for event in self.debouncer.drain(now) {
+                match event {
+                    FsEvent::Rescan(path) => self.rescan(path, self.last_received),
+                    event => fs_event_handler(event, &self.db_sender),
+                }
+            }
+        }
+    }
+}
+
+/// 把事件放进队列的 notify 回调, 回调不能阻塞, 队列满时丢弃事件并记下, 由事件循环对账
+fn event_handler(
+    tx: &SyncSender<Message>,
+    overflowed: &Arc<Mutex<Option<SystemTime>>>,
+) -> impl EventHandler {
+    let (tx, overflowed) = (tx.clone(), overflowed.clone());
+    move |res| {
+        if let Err(TrySendError::Full(_)) = tx.try_send(Message::Event(res)) {
+            overflowed
+                .lock()
+                .unwrap()
+                .get_or_insert_with(SystemTime::now);
+        }
+    }
+}
+
+/// 在状态中公开目标占用的 inotify watch 和轮询的子树
+fn publish_watches(target: &str, entry: &Target) {
+    update_status(target, |status| {
+        status.watches = entry.watches;
+        status.polled = entry.polled.clone();
+    });
+}
+
+/// 启动事件循环并监听所有目标, `since` 为上一次事件循环开始的时间, 重启后从这之后对账
+pub(super) fn run_service(
+    db_sender: Sender<DbAction>,
+    since: Option<SystemTime>,
+) -> Result<(), CustomError> {
+    let (mut service, tx) =
+        WatchService::new(CONF.database.watch_queue, Backoff::default(), db_sender)?;
+    // 先公开消息队列再读取目标, 期间添加的目标不会遗漏, 重复添加会被忽略
+    *COMMANDS.lock().unwrap() = Some(tx);
+    let now = Instant::now();
+    for target in watcher_targets() {
+        service.add(&target, since, now);
+    }
+    let res = service.run();
+    *COMMANDS.lock().unwrap() = None;
+    res
+}
+
+/// 与 `path` 互相包含的已有目标, 重叠的目标会被重复监听和对账
+fn overlapping_target(path: &Path, targets: Vec<String>) -> Option<String> {
+    targets
+        .into_iter()
+        .find(|watched| path.starts_with(watched) || Path::new(watched).starts_with(path))
+}
+
+/// 在运行时添加监听目标, 不写回配置文件; 与启动时的目标一样不预先扫描
+pub fn add_watch_target(target: &str) -> Result<(), CustomError> {
+    let path = Path::new(target);
+    if !path.is_absolute() {
+        return Err(CustomError::from(format!(
+            "target {} is not absolute",
+            target
+        )));
+    }
+    if is_blacklisted(path) {
+        return Err(CustomError::from(format!(
+            "target {} is blacklisted",
+            target
+        )));
+    }
+    if let Some(watched) = overlapping_target(path, watcher_targets()) {
+        return Err(CustomError::from(format!(
+            "target {} overlaps with watched target {}",
+            target, watched
+        )));
+    }
+
+    update_status(target, |_| {});
+    // 事件循环正在重启时, 重启后会从状态表中读到新目标
+    if let Some(tx) = COMMANDS.lock().unwrap().as_ref() {
+        tx.send(Message::Add(target.to_string()))
+            .map_err(|_| CustomError::from("watch service is not running"))?;
+    }
+    Ok(())
+}
+
+/// 在运行时移除监听目标, 已索引的数据保留
+pub fn remove_watch_target(target: &str) -> Result<(), CustomError> {
+    if !watcher_targets().iter().any(|watched| watched == target) {
+        return Err(CustomError::from(format!(
+            "target {} is not watched",
+            target
+        )));
+    }
+    remove_status(target);
+    if let Some(tx) = COMMANDS.lock().unwrap().as_ref() {
+        tx.send(Message::Remove(target.to_string()))
+            .map_err(|_| CustomError::from("watch service is not running"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{backend::supervisor::watcher_status, util::test_util::TempDir};
+
+    fn state_of(target: &str) -> Option<WatcherState> {
+        watcher_status()
+            .into_iter()
+            .find(|status| status.target == target)
+            .map(|status| status.state)
+    }
+
+    /// 处理事件循环中的消息, 直到后台统计完成、目标开始监听
+    fn wait_counted(service: &mut WatchService, target: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while service.targets[target].counting && Instant::now() < deadline {
+            if let Ok(message) = service.rx.recv_timeout(Duration::from_millis(10)) {
+                service.handle(message, Instant::now());
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlapping_target() {
+        let targets = vec!["/data/a".to_string(), "/data/b/c".to_string()];
+        assert_eq!(
+            overlapping_target(Path::new("/data/a/x"), targets.clone()),
+            Some("/data/a".to_string())
+        );
+        // 包含已有目标的上级文件夹同样重叠
+        assert_eq!(
+            overlapping_target(Path::new("/data/b"), targets.clone()),
+            Some("/data/b/c".to_string())
+        );
+        assert_eq!(overlapping_target(Path::new("/data/ab"), targets), None);
+    }
+
+    #[test]
+    fn test_late_target() {
+        let dir = TempDir::new("service");
+        let root = dir.join("late");
+        let target = root.to_string_lossy().to_string();
+        let (db_sender, _db_rx) = mpsc::channel();
+        let backoff = Backoff {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(10),
+        };
+        let (mut service, _tx) = WatchService::new(16, backoff, db_sender).unwrap();
+        service.watch_limit = None;
+
+        let now = Instant::now();
+        service.add(&target, None, now);
+        assert_eq!(state_of(&target), Some(WatcherState::Retrying));
+
+        // 目标出现后, 下一次重试时开始监听
+        fs::create_dir_all(&root).unwrap();
+        service.maintain(now);
+        assert_eq!(state_of(&target), Some(WatcherState::Retrying));
+        service.maintain(now + Duration::from_millis(20));
+        assert_eq!(state_of(&target), Some(WatcherState::Running));
+        assert!(service.targets[&target].watched);
+
+        service.remove(&target);
+        assert!(service.targets.is_empty());
+        assert_eq!(state_of(&target), None);
+    }
+
+    #[test]
+    fn test_watch_limit() {
+        let root = TempDir::new("limit-service");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        let target = root.to_string_lossy().to_string();
+        let (db_sender, _db_rx) = mpsc::channel();
+        let (mut service, _tx) = WatchService::new(16, Backoff::default(), db_sender).unwrap();
+
+        // 放得下时占用每个文件夹一个 watch, 统计在后台进行
+        service.watch_limit = Some(3);
+        service.add(&target, None, Instant::now());
+        assert_eq!(state_of(&target), Some(WatcherState::Starting));
+        wait_counted(&mut service, &target);
+        assert_eq!(state_of(&target), Some(WatcherState::Running));
+        assert_eq!(service.targets[&target].watches, 3);
+        assert!(service.targets[&target].polled.is_empty());
+        service.poll_subtrees(vec![root.join("a/b")]);
+        assert_eq!(service.targets[&target].polled, vec![root.join("a/b")]);
+        service.remove(&target);
+
+        // 放不下时只轮询放不下的子树
+        service.watch_limit = Some(2);
+        service.add(&target, None, Instant::now());
+        wait_counted(&mut service, &target);
+        assert_eq!(state_of(&target), Some(WatcherState::Running));
+        let status = watcher_status()
+            .into_iter()
+            .find(|status| status.target == target)
+            .unwrap();
+        assert_eq!(status.watches, 2);
+        assert_eq!(status.polled, vec![root.join("a/b")]);
+        assert_eq!(
+            service.targets[&target].single,
+            vec![root.to_path_buf(), root.join("a")]
+        );
+
+        // 只监听自身的文件夹中新建的文件夹单独监听
+        let created = root.join("new");
+        fs::create_dir(&created).unwrap();
+        service.watch_new_dir(
+            &Event::new(EventKind::Create(CreateKind::Folder)).add_path(created.clone()),
+        );
+        assert_eq!(service.targets[&target].watches, 3);
+        assert!(service.targets[&target].dirs.contains(&created));
+        service.remove(&target);
+    }
+
+    #[test]
+    fn test_poll_mode() {
+        let root = TempDir::new("poll-mode");
+        let target = root.to_string_lossy().to_string();
+        let (db_sender, _db_rx) = mpsc::channel();
+        let (mut service, _tx) = WatchService::new(16, Backoff::default(), db_sender).unwrap();
+
+        // poll 模式的目标不占用 inotify watch, 整个目标轮询
+        let options = TargetOptions {
+            mode: WatchMode::Poll,
+            poll_depth: 1,
+            ..TargetOptions::default()
+        };
+        service.add_with_options(&target, options, None, Instant::now());
+        assert_eq!(state_of(&target), Some(WatcherState::Running));
+        assert_eq!(service.targets[&target].watches, 0);
+        assert_eq!(service.targets[&target].polled, vec![root.to_path_buf()]);
+        assert!(service.pollers.contains_key(&*root));
+
+        service.remove(&target);
+        assert!(service.pollers.is_empty());
+    }
+
+    #[test]
+    fn test_queue_overflow() {
+        let root = TempDir::new("overflow");
+        let target = root.to_string_lossy().to_string();
+        let (db_sender, _db_rx) = mpsc::channel();
+        let (mut service, _tx) = WatchService::new(1, Backoff::default(), db_sender).unwrap();
+        service.watch_limit = None;
+        service.add(&target, None, Instant::now());
+
+        let started = SystemTime::now();
+        // 队列只能容纳一个事件, 其余的事件被丢弃并记下
+        for i in 0..8 {
+            fs::write(root.join(format!("{}.txt", i)), b"x").unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while service.overflowed.lock().unwrap().is_none() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // 记下的是第一次丢弃事件的时间
+        let dropped = service.overflowed.lock().unwrap().unwrap();
+        assert!(dropped >= started && dropped <= SystemTime::now());
+        service.remove(&target);
+    }
+}
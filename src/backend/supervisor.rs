@@ -0,0 +1,268 @@
+//! 监听服务的监督
+//!
+//! 所有目标由一个多路复用的监听服务负责(见 `service`), 这里维护每个目标的状态, 并在事件循环
+//! 出错或 panic 后按指数退避重启, 重新开始监听后对账中断期间的变化。单个目标不存在(例如稍后才挂载的
+//! 移动硬盘)或监听失败时由监听服务自行重试。每个目标的状态可以通过 API 和托盘查看。
+
+use std::{
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{mpsc::Sender, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::{config::WatchMode, util::errors::CustomError};
+
+use super::{
+    service::{run_service, MAX_FAILURES},
+    writer::DbAction,
+};
+
+/// 持续运行超过该时长后, 之前的失败不再计入退避
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// 每个监听目标的状态
+    static ref WATCHERS: Mutex<BTreeMap<String, WatcherStatus>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum WatcherState {
+    Starting,
+    Running,
+    /// 监听失败或目标不存在, 等待重试
+    Retrying,
+    /// 多次失败后放弃
+    Failed,
+}
+
+/// 一个监听目标的状态
+#[derive(Debug, Clone, Serialize)]
+pub struct WatcherStatus {
+    pub target: String,
+    pub state: WatcherState,
+    /// 最近一次失败的原因
+    pub reason: Option<String>,
+    /// 重启的次数
+    pub restarts: u32,
+    /// 监听方式
+    pub mode: WatchMode,
+    /// 占用的 inotify watch 数量, 只在 Linux 上统计
+    pub watches: usize,
+    /// 改为轮询扫描的子树: poll 模式的目标本身, 或 inotify watch 不够用的子树
+    pub polled: Vec<PathBuf>,
+    /// 进入当前状态的时间
+    pub since: SystemTime,
+    /// 下一次重试的时间
+    pub next_retry: Option<SystemTime>,
+}
+
+impl WatcherStatus {
+    fn new(target: &str) -> WatcherStatus {
+        WatcherStatus {
+            target: target.to_string(),
+            state: WatcherState::Starting,
+            reason: None,
+            restarts: 0,
+            mode: WatchMode::default(),
+            watches: 0,
+            polled: Vec::new(),
+            since: SystemTime::now(),
+            next_retry: None,
+        }
+    }
+}
+
+/// 所有监听目标的状态, 按目标排序
+pub fn watcher_status() -> Vec<WatcherStatus> {
+    WATCHERS.lock().unwrap().values().cloned().collect()
+}
+
+/// 所有监听目标
+pub(super) fn watcher_targets() -> Vec<String> {
+    WATCHERS.lock().unwrap().keys().cloned().collect()
+}
+
+pub(super) fn update_status(target: &str, f: impl FnOnce(&mut WatcherStatus)) {
+    let mut watchers = WATCHERS.lock().unwrap();
+    let status = watchers
+        .entry(target.to_string())
+        .or_insert_with(|| WatcherStatus::new(target));
+    f(status);
+}
+
+pub(super) fn remove_status(target: &str) {
+    WATCHERS.lock().unwrap().remove(target);
+}
+
+pub(super) fn set_state(
+    target: &str,
+    state: WatcherState,
+    reason: Option<String>,
+    retry: Option<Duration>,
+) {
+    update_status(target, |status| set_status(status, state, reason, retry));
+}
+
+fn set_status(
+    status: &mut WatcherStatus,
+    state: WatcherState,
+    reason: Option<String>,
+    retry: Option<Duration>,
+) {
+    status.state = state;
+    status.reason = reason;
+    status.since = SystemTime::now();
+    status.next_retry = retry.map(|retry| status.since + retry);
+}
+
+/// 事件循环停止时, 所有目标都失去监听
+fn set_all_states(state: WatcherState, reason: &str, retry: Option<Duration>) {
+    for status in WATCHERS.lock().unwrap().values_mut() {
+        set_status(status, state, Some(reason.to_string()), retry);
+        if retry.is_some() {
+            status.restarts += 1;
+        }
+    }
+}
+
+/// 重试的退避时间, 从 `min` 开始每次翻倍, 不超过 `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    pub(super) fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32 << failures.min(16);
+        self.min.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// 监督事件循环, 出错或 panic 后按退避时间重启; 连续失败多次后放弃, 返回最后一次失败的原因
+///
+/// `on_retry` 在每次重启前调用, 参数为失败的原因和等待的时间
+fn supervise(
+    backoff: Backoff,
+    mut run: impl FnMut() -> Result<(), CustomError>,
+    mut on_retry: impl FnMut(&str, Duration),
+) -> String {
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let reason = match panic::catch_unwind(AssertUnwindSafe(&mut run)) {
+            Ok(Ok(())) => "watch service stopped".to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(payload) => {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                format!("watch service panicked: {}", msg)
+            }
+        };
+
+        if started.elapsed() >= STABLE_RUN {
+            failures = 0;
+        }
+        failures += 1;
+        if failures >= MAX_FAILURES {
+            return reason;
+        }
+        let delay = backoff.delay(failures - 1);
+        warn!("{}, restart in {:?}", reason, delay);
+        on_retry(&reason, delay);
+        thread::sleep(delay);
+    }
+}
+
+/// 在后台启动受监督的监听服务, 返回的句柄在监听服务多次失败放弃后结束
+pub fn spawn_supervisor(
+    targets: Vec<String>,
+    db_sender: Sender<DbAction>,
+) -> thread::JoinHandle<()> {
+    for target in &targets {
+        update_status(target, |_| {});
+    }
+    thread::spawn(move || {
+        let mut previous: Option<SystemTime> = None;
+        let reason = supervise(
+            Backoff::default(),
+            || {
+                // 重启时从上一次开始运行的时间开始对账
+                let since = previous.replace(SystemTime::now());
+                run_service(db_sender.clone(), since)
+            },
+            |reason, delay| set_all_states(WatcherState::Retrying, reason, Some(delay)),
+        );
+        error!("give up watching: {}", reason);
+        set_all_states(WatcherState::Failed, &reason, None);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(8));
+        assert_eq!(backoff.delay(4), Duration::from_secs(10));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_supervise() {
+        let backoff = Backoff {
+            min: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+        };
+        let mut runs = 0;
+        let mut retries = Vec::new();
+        let reason = supervise(
+            backoff,
+            || {
+                runs += 1;
+                if runs == 1 {
+                    panic!("boom");
+                }
+                Err(CustomError::from("watch failed"))
+            },
+            |reason, delay| retries.push((reason.to_string(), delay)),
+        );
+
+        assert_eq!(runs, MAX_FAILURES);
+        assert_eq!(reason, "Error: watch failed");
+        assert_eq!(retries.len() as u32, MAX_FAILURES - 1);
+        assert_eq!(
+            retries[0],
+            (
+                "watch service panicked: boom".to_string(),
+                Duration::from_millis(1)
+            )
+        );
+        assert_eq!(retries[3].1, Duration::from_millis(4));
+    }
+}
@@ -1,13 +1,12 @@
 use std::{
-    fs,
     path::PathBuf,
     sync::{
         mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        Arc,
     },
 };
 
-use crate::db::{Database, EntryMeta, DB};
+use crate::db::{meta::EntryType, Database, EntryMeta, DB};
 
 #[allow(unused)]
 pub enum DbAction {
@@ -15,6 +14,8 @@ pub enum DbAction {
     FIND,
     DELETE(PathBuf),
     UPDATE(PathBuf, EntryMeta),
+    /// 重命名或移动, 子树中的记录整体移动到新路径
+    RENAME(PathBuf, PathBuf),
 }
 
 use lazy_static::lazy_static;
@@ -35,63 +36,90 @@ lazy_static! {
     };
 }
 
-pub fn db_writer(db: Arc<Mutex<dyn Database>>, db_receiver: Receiver<DbAction>) {
+pub fn db_writer(db: Arc<dyn Database>, db_receiver: Receiver<DbAction>) {
     info!("db_writer: start");
     loop {
         match db_receiver.recv() {
             Ok(msg) => match msg {
                 DbAction::CREATE(path, meta) => {
                     debug!("db_writer: create: {:?}", &path);
-                    let db_guard = db.lock().unwrap();
-                    _ = db_guard.insert_rec(&path, &meta);
+                    _ = db.insert_rec(&path, &meta);
                 }
                 DbAction::FIND => {
                     debug!("db_writer: find: nothing to do")
                 }
                 DbAction::DELETE(path) => {
-                    if let Ok(path_type) = fs::metadata(&path) {
-                        let db_guard: std::sync::MutexGuard<'_, dyn Database> = db.lock().unwrap();
-
-                        if path_type.is_dir() {
-                            debug!("db_writer: delete dir: {:?}", &path);
-
-                            // 删除指定路径文件夹下的所有内容
-                            let res = db_guard.delete_by_path_prefix(&path);
-                            match res {
-                                Ok(_) => {
-                                    debug!("db_writer: delete dir success")
-                                }
-                                Err(e) => {
-                                    debug!("db_writer: delete error: {:?}", e);
-                                }
-                            }
-                        } else if path_type.is_file() {
+                    // 路径已经被删除, 无法再从文件系统获取类型, 以数据库记录的类型为准;
+                    // 没有记录时可能是只索引了部分内容的文件夹, 按子树删除
+                    let res = match db.find_by_path(&path) {
+                        Ok(Some(meta)) if meta.entry_type == EntryType::File => {
                             debug!("db_writer: delete file: {:?}", &path);
-
-                            // 只删除指定的文件路径
-                            let res = db_guard.delete_by_path(&path);
-                            match res {
-                                Ok(_) => {
-                                    debug!("db_writer: delete file success")
-                                }
-                                Err(e) => {
-                                    debug!("db_writer: delete error: {:?}", e);
-                                }
-                            }
-                        } else if path_type.is_symlink() {
-                            debug!("Unsupported file type: symlink")
+                            db.delete_by_path(&path)
                         }
+                        _ => {
+                            debug!("db_writer: delete dir: {:?}", &path);
+                            db.delete_by_path_prefix(&path)
+                        }
+                    };
+                    if let Err(e) = res {
+                        error!("db_writer: delete error: {:?}", e);
                     }
                 }
                 DbAction::UPDATE(path, meta) => {
                     debug!("db_writer: update: {:?}", &path);
-                    let db_guard = db.lock().unwrap();
-                    _ = db_guard.update_meta(&path, &meta);
+                    _ = db.update_meta(&path, &meta);
+                }
+                DbAction::RENAME(from, to) => {
+                    debug!("db_writer: rename: {:?} -> {:?}", &from, &to);
+                    if let Err(e) = db.rename_path_prefix(&from, &to) {
+                        error!("db_writer: rename error: {:?}", e);
+                    }
                 }
             },
             Err(e) => {
+                // 所有发送端都已关闭, 不会再有新的操作
                 error!("db_writer: db_writer error: {:?}", e);
+                return;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::db::{DbReader, DbWriter, MemoryDatabase};
+
+    #[test]
+    fn test_delete_removed_dir() {
+        let db = Arc::new(MemoryDatabase::new());
+        let mut meta = EntryMeta {
+            path: PathBuf::new(),
+            size: 0,
+            modified: SystemTime::now(),
+            access_count: 1,
+            entry_type: EntryType::Dir,
+        };
+        // 路径都不存在, 相当于事件到达时已经被删除
+        let dir = PathBuf::from("/elf-writer-gone/dir");
+        db.insert_rec(&dir, &meta).unwrap();
+        meta.entry_type = EntryType::File;
+        for path in ["/elf-writer-gone/dir/a.txt", "/elf-writer-gone/dir2/b.txt"] {
+            db.insert_rec(&PathBuf::from(path), &meta).unwrap();
+        }
+
+        let (tx, rx) = mpsc::channel();
+        tx.send(DbAction::DELETE(dir.clone())).unwrap();
+        drop(tx);
+        db_writer(db.clone(), rx);
+
+        // 整个子树被删除, 名字以 `dir` 开头的兄弟保留
+        assert_eq!(db.len(), 1);
+        assert!(db
+            .find_by_path(&PathBuf::from("/elf-writer-gone/dir2/b.txt"))
+            .unwrap()
+            .is_some());
+    }
+}
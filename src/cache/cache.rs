@@ -1,82 +1,123 @@
 use std::{
-    collections::BinaryHeap,
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
-use log::{debug, error};
+use log::{debug, error, info};
+use serde::Serialize;
 
 use crate::{
     config::CONF,
-    db::{
-        meta::{EntryMeta, EntryType},
-        Database,
-    },
-    util::{errors::CustomError, is_excluded},
+    db::{meta::EntryMeta, Database},
+    util::{errors::CustomError, is_blacklisted},
 };
 
-use super::{hot_dir::HOTDIR, trie::TrieCache, CACHER};
+use super::{
+    hot_dir::{accumulate, HOTDIR},
+    query_cache::{QueryCache, QueryCacheStats, QueryKey},
+    search::SearchOptions,
+    trie::{MemoryReport, TrieCache},
+    CACHER,
+};
+
+/// 超过容量时淘汰到容量的这个比例, 避免每次插入都触发淘汰
+const EVICT_WATERMARK: f64 = 0.9;
 
 pub struct Cacher {
     pub tree: TrieCache,
+    /// 缓存条目数上限, 为 0 时不限制
+    pub capacity: usize,
+    /// 累计淘汰的条目数
+    evicted: u64,
+    /// 累计从数据库重新加载回缓存的条目数
+    promoted: u64,
+    /// 查询结果缓存, 搜索在读锁下进行, 因此单独加锁
+    queries: Mutex<QueryCache>,
 }
 
-pub fn init_trie(db: Arc<Mutex<dyn Database>>) {
-    let db_guard = db.lock().unwrap();
+/// 缓存状态, 通过 stats 接口返回
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub evicted: u64,
+    pub promoted: u64,
+    pub memory: Option<MemoryReport>,
+    pub queries: QueryCacheStats,
+}
 
-    let data = db_guard.find_all();
-    drop(db_guard); // 任何时刻只持有一把锁来避免死锁
+pub fn init_trie(db: Arc<dyn Database>) {
+    let data = db.find_all();
 
-    let mut cache_guard = CACHER.lock().unwrap();
-    let trie = &mut cache_guard.tree.root;
+    let mut cache_guard = CACHER.write().unwrap();
+    let trie = &mut cache_guard.tree;
 
     let mut del_paths = Vec::new();
+    let mut missing_paths = Vec::new();
+    let mut available_paths = Vec::new();
+    let unavailable: HashSet<PathBuf> = match db.find_unavailable() {
+        Ok(unavailable) => unavailable.into_iter().map(|(path, _)| path).collect(),
+        Err(e) => {
+            error!("find unavailable records error: {}", e);
+            HashSet::new()
+        }
+    };
 
-    // 创建一个 BinaryHeap 优先队列, 用于记录热点文件夹
-    let mut dir_heap: BinaryHeap<EntryMeta> = BinaryHeap::new();
+    // 按访问次数统计各文件夹的活跃度, 用于初始化热点文件夹
+    let mut hot_scores = HashMap::new();
 
     for (entry, meta) in data {
         let path = meta.path.clone();
-        // 判断是不是 不存在的文件 or 处于黑名单中的文件
-        if is_excluded(&path) {
-            debug!(
-                "path not exists or in blacklist: {:#?}, marked as deleted",
-                &path
-            );
+        // 处于黑名单中的文件直接删除
+        if is_blacklisted(&path) {
+            debug!("path in blacklist: {:#?}, marked as deleted", &path);
             del_paths.push(path);
             continue;
         }
 
-        if meta.entry_type == EntryType::Dir {
-            // 记录热点文件夹
-            dir_heap.push(meta.clone());
-            if dir_heap.len() > CONF.database.hotdirnum {
-                dir_heap.pop();
-            }
+        // 不存在的文件可能只是所在的磁盘暂未挂载, 标记为不可用并交给后台对账
+        if !path.exists() {
+            debug!("path not exists: {:#?}, marked as unavailable", &path);
+            missing_paths.push(path);
+            continue;
+        }
+        if unavailable.contains(&path) {
+            available_paths.push(path.clone());
         }
 
-        let paths = path
-            .components()
-            .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        match trie.insert(paths, Some(meta), false) {
-            Ok(meta) => {
-                if meta.is_some() {
-                    debug!("init trie: entry({}), meta({:?})", entry, meta.unwrap());
-                }
+        accumulate(&mut hot_scores, &path, meta.entry_type, meta.access_count);
+
+        match trie.insert_path(&path, Some(meta), false) {
+            Ok(Some(meta)) => {
+                debug!("init trie: entry({}), meta({:?})", entry, meta);
             }
+            Ok(None) => {}
             Err(e) => {
                 error!("init trie error: {}", e);
             }
         }
     }
+    cache_guard.enforce_capacity(None);
     drop(cache_guard); // 任何时刻只持有一把锁来避免死锁
 
-    let db_guard = db.lock().unwrap();
-    // 清理数据库中: 不存在的文件 + 处于黑名单中的文件
+    let now = SystemTime::now();
+    missing_paths.into_iter().for_each(|path| {
+        if let Err(e) = db.mark_unavailable(&path, now) {
+            error!("mark path unavailable error: {}", e);
+        }
+    });
+    available_paths.into_iter().for_each(|path| {
+        if let Err(e) = db.mark_available(&path) {
+            error!("mark path available error: {}", e);
+        }
+    });
+
+    // 清理数据库中处于黑名单中的文件
     del_paths
         .into_iter()
-        .for_each(|path| match db_guard.delete_by_path(&path) {
+        .for_each(|path| match db.delete_by_path(&path) {
             Ok(_) => {
                 debug!("delete path in DB: {:?}", path);
             }
@@ -84,24 +125,33 @@ pub fn init_trie(db: Arc<Mutex<dyn Database>>) {
                 debug!("delete path error: {}", e);
             }
         });
-    drop(db_guard); // 任何时刻只持有一把锁来避免死锁
 
     match HOTDIR.write() {
-        Ok(mut hd_guard) => {
-            dir_heap.into_iter().for_each(|meta| {
-                hd_guard.push(meta); // 加入全局热点文件夹列表
-            });
-        }
+        Ok(mut hd_guard) => hd_guard.seed(hot_scores, now),
         Err(e) => {
             error!("lock HOTDIR failed: {}", e);
         }
     }
 }
 
+impl Default for Cacher {
+    fn default() -> Self {
+        Cacher::new()
+    }
+}
+
 impl Cacher {
     pub fn new() -> Self {
+        Cacher::with_capacity(CONF.database.cache_capacity)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
         Cacher {
             tree: TrieCache::new(),
+            capacity,
+            evicted: 0,
+            promoted: 0,
+            queries: Mutex::new(QueryCache::new()),
         }
     }
 
@@ -111,27 +161,126 @@ impl Cacher {
         meta: Option<EntryMeta>,
         update_count: bool,
     ) -> Result<Option<EntryMeta>, CustomError> {
-        self.tree.insert_path(path, meta, update_count)
+        let res = self.tree.insert_path(path, meta, update_count)?;
+        self.enforce_capacity(Some(path));
+        Ok(res)
     }
 
-    pub fn remove_path(&mut self, path: &PathBuf) {
+    /// 条目数超过容量时淘汰最不常用的条目, 被淘汰的条目仍保留在数据库中
+    pub fn enforce_capacity(&mut self, keep: Option<&Path>) {
+        if self.capacity == 0 || self.tree.len() <= self.capacity {
+            return;
+        }
+        let target = (self.capacity as f64 * EVICT_WATERMARK) as usize;
+        let evicted = self.tree.evict(target, keep);
+        self.evicted += evicted as u64;
+        info!(
+            "cache exceeds capacity {}, {} entries evicted",
+            self.capacity, evicted
+        );
+    }
+
+    /// 是否有条目被淘汰过, 此时缓存的查询结果可能不完整, 需要再查询数据库
+    pub fn is_partial(&self) -> bool {
+        self.evicted > 0
+    }
+
+    /// 缓存查到 `found` 条结果时是否还需要查询数据库: 没有结果, 或者有条目被淘汰过且结果数没有达到上限
+    pub fn needs_db_search(&self, found: usize, limit: usize) -> bool {
+        found == 0 || (self.is_partial() && (limit == 0 || found < limit))
+    }
+
+    /// 将数据库查询命中的条目重新加载回缓存, 已经在缓存中的条目不做修改
+    pub fn promote(&mut self, meta: EntryMeta) {
+        let path = meta.path.clone();
+        if self.tree.contains(&path) {
+            return;
+        }
+        match self.add_path(&path, Some(meta), false) {
+            Ok(_) => self.promoted += 1,
+            Err(e) => debug!("promote path error: {}", e),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.tree.len(),
+            capacity: self.capacity,
+            evicted: self.evicted,
+            promoted: self.promoted,
+            memory: self.tree.memory_usage(Path::new("")),
+            queries: self.queries.lock().unwrap().stats(),
+        }
+    }
+
+    pub fn remove_path(&mut self, path: &Path) {
         _ = self.tree.delete(path);
     }
 
-    pub fn contains_path(&mut self, path: &PathBuf, update_count: bool) -> bool {
-        self.tree.contains_full_path(path, update_count)
+    /// 将路径及其子树移动到新路径, 保留访问计数等元数据
+    pub fn move_path(&mut self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError> {
+        self.tree.move_path(from, to)
+    }
+
+    /// 只读查询, 不更新访问计数
+    pub fn contains_path(&self, path: &Path) -> bool {
+        self.tree.contains(path)
     }
 
     pub fn search_path(&mut self, path: &PathBuf, update_count: bool) -> Option<EntryMeta> {
         self.tree.search_full_path(path, update_count)
     }
 
-    pub fn search_path_regex(&self, pattern_path: &str) -> Vec<PathBuf> {
-        self.tree.search_path_regex(pattern_path)
+    /// `root` 及其子树中已缓存的条目
+    pub fn entries_under(&self, root: &Path) -> Vec<EntryMeta> {
+        self.tree.entries_under(root)
+    }
+
+    /// 从磁盘重新读取已缓存条目的元数据
+    pub fn refresh_path(&mut self, path: &PathBuf) -> Option<EntryMeta> {
+        let fresh = EntryMeta::new(path).ok()?;
+        self.tree.refresh_meta(path, &fresh)
+    }
+
+    pub fn search_path_regex(&self, pattern_path: &str, opts: &SearchOptions) -> Vec<PathBuf> {
+        self.cached_search(QueryKey::regex(pattern_path, opts.limit), opts, |opts| {
+            self.tree.search_path_regex_with(pattern_path, opts)
+        })
+    }
+
+    pub fn search_entry(&self, entry: &str, is_fuzzy: bool, opts: &SearchOptions) -> Vec<PathBuf> {
+        self.cached_search(QueryKey::entry(entry, is_fuzzy, opts.limit), opts, |opts| {
+            self.tree.search_entry_with(entry, is_fuzzy, opts)
+        })
     }
 
-    pub fn search_entry(&self, entry: &str, is_fuzzy: bool) -> Vec<PathBuf> {
-        self.tree.search_entry(entry, is_fuzzy)
+    pub fn search_substring(&self, substring: &str, opts: &SearchOptions) -> Vec<PathBuf> {
+        self.cached_search(QueryKey::substring(substring, opts.limit), opts, |opts| {
+            self.tree.search_substring_with(substring, opts)
+        })
+    }
+
+    /// 优先使用查询缓存, 未命中时遍历缓存树; 提前结束的不完整结果不放入查询缓存
+    fn cached_search(
+        &self,
+        key: QueryKey,
+        opts: &SearchOptions,
+        search: impl FnOnce(&SearchOptions) -> Vec<PathBuf>,
+    ) -> Vec<PathBuf> {
+        let generation = self.tree.generation();
+        if let Some(res) = self.queries.lock().unwrap().lookup(&key, generation) {
+            return res;
+        }
+
+        let res = search(opts);
+        if !opts.is_partial() {
+            let complete = opts.limit == 0 || res.len() < opts.limit;
+            self.queries
+                .lock()
+                .unwrap()
+                .insert(key, generation, res.clone(), complete);
+        }
+        res
     }
 }
 
@@ -140,11 +289,125 @@ mod tests {
     #[test]
     fn test_init() {
         use super::*;
-        use crate::db::SqliteDatabase;
+        use crate::db::{DbReader, DbWriter, MemoryDatabase};
+
+        let db = Arc::new(MemoryDatabase::new());
+        let exist_path = std::env::temp_dir();
+        let missing_path = exist_path.join("elf_test_init_missing.txt");
+        db.insert_rec(&exist_path, &EntryMeta::new(&exist_path).unwrap())
+            .unwrap();
+        db.insert_rec(&missing_path, &EntryMeta::new_empty().unwrap())
+            .unwrap();
+
+        init_trie(db.clone());
+
+        // 不存在的文件在初始化时只标记为不可用, 不从数据库中删除
+        assert!(db.find_by_path(&exist_path).unwrap().is_some());
+        assert!(db.find_by_path(&missing_path).unwrap().is_some());
+        let unavailable: Vec<PathBuf> = db
+            .find_unavailable()
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(unavailable, vec![missing_path.clone()]);
+        assert!(CACHER.read().unwrap().contains_path(&exist_path));
+        assert!(!CACHER.read().unwrap().contains_path(&missing_path));
+    }
+
+    #[test]
+    fn test_concurrent_search() {
+        use super::*;
+        use std::thread;
+
+        let dir = std::env::temp_dir();
+        CACHER.write().unwrap().add_path(&dir, None, false).unwrap();
+
+        // 持有读锁的同时, 其他线程仍然可以搜索
+        let guard = CACHER.read().unwrap();
+        let searcher = thread::spawn(move || {
+            let name = dir.file_name().unwrap().to_string_lossy().to_string();
+            CACHER
+                .read()
+                .unwrap()
+                .search_entry(&name, false, &SearchOptions::default())
+                .contains(&dir)
+        });
+        assert!(searcher.join().unwrap());
+        drop(guard);
+    }
+
+    #[test]
+    fn test_capacity() {
+        use super::*;
+
+        let mut cacher = Cacher::with_capacity(10);
+        let metas: Vec<EntryMeta> = (0..20)
+            .map(|i| {
+                let mut meta = EntryMeta::new_empty().unwrap();
+                meta.path = PathBuf::from(format!("/capacity/file{}.txt", i));
+                meta.access_count = i;
+                meta
+            })
+            .collect();
+        for meta in &metas {
+            cacher
+                .add_path(&meta.path, Some(meta.clone()), false)
+                .unwrap();
+            assert!(cacher.tree.len() <= 10);
+        }
+        assert!(cacher.is_partial());
+        // 访问计数最高的条目留在缓存中
+        assert!(cacher.contains_path(&metas[19].path));
+        assert!(!cacher.contains_path(&metas[0].path));
+
+        // 数据库命中的条目重新加载回缓存
+        cacher.promote(metas[0].clone());
+        assert!(cacher.contains_path(&metas[0].path));
+        let stats = cacher.stats();
+        assert!(stats.evicted >= 10);
+        assert_eq!(stats.promoted, 1);
+        assert!(stats.entries <= 10);
+
+        // 已经在缓存中的条目不重复加载, 查询缓存不失效
+        let generation = cacher.tree.generation();
+        cacher.promote(metas[0].clone());
+        assert_eq!(cacher.tree.generation(), generation);
+        assert_eq!(cacher.stats().promoted, 1);
+
+        // 结果数达到上限时不需要再查询数据库
+        assert!(cacher.needs_db_search(0, 10));
+        assert!(cacher.needs_db_search(3, 10));
+        assert!(cacher.needs_db_search(3, 0));
+        assert!(!cacher.needs_db_search(10, 10));
+        assert!(!Cacher::with_capacity(10).needs_db_search(3, 10));
+    }
+
+    #[test]
+    fn test_query_cache() {
+        use super::*;
+
+        let mut cacher = Cacher::with_capacity(0);
+        for name in ["feature.rs", "feat.md", "fear.txt", "other.txt"] {
+            let path = PathBuf::from("/query").join(name);
+            let meta = EntryMeta::new_empty().unwrap();
+            cacher.add_path(&path, Some(meta), false).unwrap();
+        }
+        let opts = SearchOptions::default();
 
-        let db =
-            SqliteDatabase::new(&PathBuf::from("/home/toni/proj/file_elf/sqlite3.db")).unwrap();
+        let res = cacher.search_entry("fea", false, &opts);
+        assert_eq!(res.len(), 3);
+        // 延长的查询在上一次的结果中筛选, 结果与完整遍历一致
+        let res = cacher.search_entry("feat", false, &opts);
+        assert_eq!(res, cacher.tree.search_entry("feat", false));
+        assert_eq!(cacher.stats().queries.narrowed, 1);
 
-        init_trie(Arc::new(Mutex::new(db)));
+        // 修改缓存后版本号变化, 查询缓存失效
+        let path = PathBuf::from("/query/feather.png");
+        cacher
+            .add_path(&path, Some(EntryMeta::new_empty().unwrap()), false)
+            .unwrap();
+        assert!(cacher.search_entry("feat", false, &opts).contains(&path));
+        assert_eq!(cacher.stats().queries.misses, 2);
     }
 }
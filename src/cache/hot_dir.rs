@@ -1,111 +1,488 @@
+//! 热点文件夹
+//!
+//! 监听到的每次访问都会为所在文件夹及其上几级文件夹累加活跃度, 离访问位置越远权重越低,
+//! 因此排名反映的是整个子树的活跃程度。活跃度随时间指数衰减, 只保留分数最高的
+//! `hotdirnum` 个文件夹(固定的文件夹始终保留), 并定期保存到数据库旁的文件中。
+
 use lazy_static::lazy_static;
-use log::{debug, error};
-use std::path::PathBuf;
-use std::{collections::BinaryHeap, sync::RwLock};
+use log::{debug, error, info, trace};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
 use walkdir::WalkDir;
 
+use crate::cache::search::{SearchBudget, SearchOptions};
 use crate::config::CONF;
-use crate::util::{is_excluded, pattern_match, regex_match};
-use crate::{db::EntryMeta, util::errors::CustomError};
+use crate::db::meta::EntryType;
+use crate::util::{errors::CustomError, is_filtered, pattern_match, rebase_path};
+
+/// 活跃度的半衰期
+const HOT_DIR_HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// 一次访问最多计入的文件夹层数, 每向上一层权重减半
+const HOT_DIR_DEPTH: usize = 3;
+/// 跟踪的候选文件夹数是 `hotdirnum` 的倍数, 使排名靠后的文件夹有机会进入列表
+const CANDIDATE_FACTOR: usize = 4;
+/// 热点文件夹有变化时保存到磁盘的间隔
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 lazy_static! {
-    pub static ref HOTDIR: RwLock<BinaryHeap<EntryMeta>> = RwLock::new(BinaryHeap::new());
+    pub static ref HOTDIR: RwLock<HotDirs> = RwLock::new(HotDirs::new(CONF.database.hotdirnum));
 }
 
-pub fn push_hot_dir(meta: EntryMeta) {
-    match HOTDIR.write() {
-        Ok(mut w_guard) => {
-            w_guard.push(meta);
-            if w_guard.len() > CONF.database.hotdirnum {
-                w_guard.pop();
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HotScore {
+    score: f64,
+    updated: SystemTime,
+}
+
+impl HotScore {
+    /// 衰减到 `now` 时的分数
+    fn decayed(&self, now: SystemTime) -> f64 {
+        let age = now
+            .duration_since(self.updated)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.score * 0.5f64.powf(age / HOT_DIR_HALF_LIFE.as_secs_f64())
+    }
+}
+
+/// 热点文件夹列表中的一项
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HotDir {
+    pub path: PathBuf,
+    pub score: f64,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HotDirs {
+    scores: HashMap<PathBuf, HotScore>,
+    pinned: BTreeSet<PathBuf>,
+    #[serde(skip)]
+    capacity: usize,
+    /// 上次保存后是否有变化
+    #[serde(skip)]
+    dirty: bool,
+}
+
+/// 对 `path` 的一次访问计入的文件夹及权重: 文件夹本身或文件所在的文件夹权重为 1,
+/// 每向上一层权重减半
+fn for_each_hot_ancestor(path: &Path, is_dir: bool, mut f: impl FnMut(&Path, f64)) {
+    let start = if is_dir { Some(path) } else { path.parent() };
+    let mut weight = 1.0;
+    for dir in start
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .take(HOT_DIR_DEPTH)
+    {
+        if dir.as_os_str().is_empty() {
+            break;
+        }
+        f(dir, weight);
+        weight /= 2.0;
+    }
+}
+
+/// 将一条记录的访问次数累加到 `scores` 中, 用于从数据库统计初始的活跃度
+pub fn accumulate(
+    scores: &mut HashMap<PathBuf, f64>,
+    path: &Path,
+    entry_type: EntryType,
+    count: u32,
+) {
+    // 每条记录至少计一次, 从未被访问的文件夹也能按包含的条目数排名
+    let count = (count + 1) as f64;
+    for_each_hot_ancestor(path, entry_type == EntryType::Dir, |dir, weight| {
+        *scores.entry(dir.to_path_buf()).or_default() += count * weight;
+    });
+}
+
+impl HotDirs {
+    pub fn new(capacity: usize) -> HotDirs {
+        HotDirs {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// 记录对 `path` 的一次访问
+    pub fn record(&mut self, path: &Path, is_dir: bool, now: SystemTime) {
+        let mut touched = Vec::new();
+        for_each_hot_ancestor(path, is_dir, |dir, weight| {
+            touched.push((dir.to_path_buf(), weight))
+        });
+        for (dir, weight) in touched {
+            self.add(dir, weight, now);
+        }
+        self.prune(now);
+    }
+
+    fn add(&mut self, dir: PathBuf, weight: f64, now: SystemTime) {
+        let entry = self.scores.entry(dir).or_insert(HotScore {
+            score: 0.0,
+            updated: now,
+        });
+        entry.score = entry.decayed(now) + weight;
+        entry.updated = now;
+        self.dirty = true;
+    }
+
+    /// 合并从数据库统计的活跃度, 取两者中的较大值, 重复合并同一份统计不会累加
+    pub fn seed(&mut self, scores: HashMap<PathBuf, f64>, now: SystemTime) {
+        for (dir, score) in scores {
+            let entry = self.scores.entry(dir).or_insert(HotScore {
+                score: 0.0,
+                updated: now,
+            });
+            if score > entry.decayed(now) {
+                *entry = HotScore {
+                    score,
+                    updated: now,
+                };
+                self.dirty = true;
             }
         }
-        Err(e) => {
-            error!("lock HOTDIR failed: {}", e);
+        self.prune(now);
+    }
+
+    /// 只保留分数最高的候选文件夹
+    fn prune(&mut self, now: SystemTime) {
+        let limit = self.capacity.max(1) * CANDIDATE_FACTOR;
+        if self.scores.len() <= limit * 2 {
+            return;
+        }
+        let mut ranked: Vec<(f64, PathBuf)> = self
+            .scores
+            .iter()
+            .map(|(dir, score)| (score.decayed(now), dir.clone()))
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, dir) in ranked.into_iter().skip(limit) {
+            self.scores.remove(&dir);
+        }
+        self.dirty = true;
+    }
+
+    /// 固定的文件夹始终出现在列表中, 不受容量限制
+    pub fn pin(&mut self, path: &Path) -> bool {
+        let pinned = self.pinned.insert(path.to_path_buf());
+        self.dirty |= pinned;
+        pinned
+    }
+
+    pub fn unpin(&mut self, path: &Path) -> bool {
+        let unpinned = self.pinned.remove(path);
+        self.dirty |= unpinned;
+        unpinned
+    }
+
+    /// 文件夹被重命名或移动, 子树中文件夹的分数和固定状态随之移动
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<PathBuf> = self
+            .scores
+            .keys()
+            .filter(|dir| dir.starts_with(from))
+            .cloned()
+            .collect();
+        for dir in moved {
+            let score = self.scores.remove(&dir).unwrap();
+            self.scores
+                .insert(rebase_path(&dir, from, to).unwrap(), score);
+            self.dirty = true;
         }
+        let pinned: Vec<PathBuf> = self
+            .pinned
+            .iter()
+            .filter(|dir| dir.starts_with(from))
+            .cloned()
+            .collect();
+        for dir in pinned {
+            self.pinned.remove(&dir);
+            self.pinned.insert(rebase_path(&dir, from, to).unwrap());
+            self.dirty = true;
+        }
+    }
+
+    /// 移除不满足条件的文件夹, 固定的文件夹不受影响
+    pub fn retain(&mut self, mut f: impl FnMut(&Path) -> bool) {
+        let len = self.scores.len();
+        self.scores.retain(|dir, _| f(dir));
+        self.dirty |= self.scores.len() != len;
+    }
+
+    pub fn clear(&mut self) {
+        self.scores.clear();
+        self.pinned.clear();
+        self.dirty = true;
+    }
+
+    /// 热点文件夹列表: 先列出固定的文件夹, 再按分数从高到低补足 `capacity` 个
+    pub fn list(&self, now: SystemTime) -> Vec<HotDir> {
+        let mut res: Vec<HotDir> = self
+            .pinned
+            .iter()
+            .map(|path| HotDir {
+                path: path.clone(),
+                score: self.scores.get(path).map_or(0.0, |s| s.decayed(now)),
+                pinned: true,
+            })
+            .collect();
+        let mut ranked: Vec<HotDir> = self
+            .scores
+            .iter()
+            .filter(|(dir, _)| !self.pinned.contains(*dir))
+            .map(|(dir, score)| HotDir {
+                path: dir.clone(),
+                score: score.decayed(now),
+                pinned: false,
+            })
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        ranked.truncate(self.capacity.saturating_sub(res.len()));
+        res.append(&mut ranked);
+        res
     }
 }
 
-#[allow(unused)]
-pub fn pop_hot_dir(meta: EntryMeta) -> Result<Option<EntryMeta>, CustomError> {
+/// 记录一次访问, 由监听到的文件事件调用
+pub fn record_access(path: &Path, entry_type: EntryType) {
     match HOTDIR.write() {
-        Ok(mut w_guard) => {
-            w_guard.push(meta);
-            Ok(w_guard.pop())
-        }
-        Err(e) => Err(CustomError::ErrStr(e.to_string())),
+        Ok(mut guard) => guard.record(path, entry_type == EntryType::Dir, SystemTime::now()),
+        Err(e) => error!("lock HOTDIR failed: {}", e),
     }
 }
 
-#[allow(unused)]
-pub fn get_hot_dir() -> Vec<EntryMeta> {
-    let mut res = Vec::new();
+/// 文件夹被重命名或移动, 由监听到的文件事件调用
+pub fn rename_hot_dirs(from: &Path, to: &Path) {
+    match HOTDIR.write() {
+        Ok(mut guard) => guard.rename(from, to),
+        Err(e) => error!("lock HOTDIR failed: {}", e),
+    }
+}
+
+pub fn get_hot_dir() -> Vec<HotDir> {
     match HOTDIR.read() {
-        Ok(mut r_guard) => {
-            r_guard.iter().for_each(|meta| {
-                res.push(meta.clone());
-            });
+        Ok(guard) => guard.list(SystemTime::now()),
+        Err(e) => {
+            error!("lock HOTDIR failed: {}", e);
+            Vec::new()
         }
-        Err(e) => return res,
     }
-    res
 }
 
-pub fn search_files_from_hot_dirs(entry: &str, is_fuzzy: bool, is_regex: bool) -> Vec<String> {
-    let mut results = Vec::new();
-
-    match HOTDIR.read() {
-        Ok(w_guard) => {
-            w_guard.iter().for_each(|meta| {
-                // 在每个热点文件夹中搜索
-                let mut cur_dir_res = search_target_from_dir(&meta.path, entry, is_fuzzy, is_regex);
-                results.append(&mut cur_dir_res);
-            });
+pub fn pin_hot_dir(path: &Path) -> bool {
+    match HOTDIR.write() {
+        Ok(mut guard) => guard.pin(path),
+        Err(e) => {
+            error!("lock HOTDIR failed: {}", e);
+            false
         }
+    }
+}
+
+pub fn unpin_hot_dir(path: &Path) -> bool {
+    match HOTDIR.write() {
+        Ok(mut guard) => guard.unpin(path),
         Err(e) => {
             error!("lock HOTDIR failed: {}", e);
+            false
         }
     }
+}
 
-    results
+/// 保存热点文件夹
+pub fn save_hot_dirs(path: &Path) -> Result<(), CustomError> {
+    let payload = {
+        let mut guard = HOTDIR
+            .write()
+            .map_err(|e| CustomError::ErrStr(e.to_string()))?;
+        guard.dirty = false;
+        bincode::serialize(&*guard)?
+    };
+    write_hot_dirs(path, &payload)
+}
+
+/// 先写入临时文件再原子替换
+fn write_hot_dirs(path: &Path, payload: &[u8]) -> Result<(), CustomError> {
+    let tmp_path = path.with_extension("hotdirs.tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(payload)?;
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    debug!("hot dirs saved to {:#?}", path);
+    Ok(())
+}
+
+fn read_hot_dirs(path: &Path) -> Result<Option<HotDirs>, CustomError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize_from(BufReader::new(
+        File::open(path)?,
+    ))?))
+}
+
+/// 加载保存的热点文件夹, 文件不存在时返回 false
+pub fn load_hot_dirs(path: &Path) -> Result<bool, CustomError> {
+    let loaded = match read_hot_dirs(path)? {
+        Some(loaded) => loaded,
+        None => return Ok(false),
+    };
+    let mut guard = HOTDIR
+        .write()
+        .map_err(|e| CustomError::ErrStr(e.to_string()))?;
+    guard.scores = loaded.scores;
+    guard.pinned = loaded.pinned;
+    guard.dirty = false;
+    info!("hot dirs loaded from {:#?}", path);
+    Ok(true)
+}
+
+/// 按配置保存热点文件夹, 用于退出前调用
+pub fn save_hot_dirs_file() {
+    if let Some(path) = CONF.database.hot_dirs_path() {
+        if let Err(e) = save_hot_dirs(&path) {
+            error!("save hot dirs error: {}", e);
+        }
+    }
+}
+
+/// 启动后台线程, 热点文件夹有变化时定期保存
+pub fn spawn_hot_dirs_saver(path: PathBuf) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(SAVE_INTERVAL);
+        let dirty = HOTDIR.read().map(|guard| guard.dirty).unwrap_or(false);
+        if dirty {
+            if let Err(e) = save_hot_dirs(&path) {
+                error!("save hot dirs error: {}", e);
+            }
+        }
+    })
 }
 
-/// 搜索文件
-pub fn search_target_from_dir(
-    directory: &PathBuf,
-    pattern: &str,
+/// 在热点文件夹中搜索, 超过截止时间、被取消或 I/O 预算用完后返回已经找到的结果
+pub fn search_files_from_hot_dirs(
+    entry: &str,
     is_fuzzy: bool,
     is_regex: bool,
+    opts: &SearchOptions,
 ) -> Vec<String> {
-    let mut results = Vec::new();
-    if directory.is_file() {
-        return results;
+    search_files_from_hot_dirs_streaming(entry, is_fuzzy, is_regex, opts, |_| {})
+}
+
+/// 并行遍历所有热点文件夹, 每个文件夹遍历完成后立即通过 `on_dir` 返回其中的结果,
+/// 最终返回按热点文件夹排名排列的全部结果
+pub fn search_files_from_hot_dirs_streaming(
+    entry: &str,
+    is_fuzzy: bool,
+    is_regex: bool,
+    opts: &SearchOptions,
+    on_dir: impl Fn(Vec<String>) + Sync,
+) -> Vec<String> {
+    let matcher = match Matcher::new(entry, is_fuzzy, is_regex) {
+        Some(matcher) => matcher,
+        None => return Vec::new(),
+    };
+    let budget = SearchBudget::new(opts);
+    // 热点文件夹可能互相嵌套, 同一个路径只返回一次
+    let seen = Mutex::new(HashSet::new());
+
+    // 复制列表后释放锁, 遍历磁盘期间不阻塞事件处理
+    let hot_dirs = get_hot_dir();
+    hot_dirs
+        .par_iter()
+        .map(|hot_dir| {
+            if budget.should_stop() || is_filtered(&hot_dir.path, true) {
+                return Vec::new();
+            }
+            let mut cur_dir_res =
+                search_target_from_dir(&hot_dir.path, &matcher, CONF.database.smart_depth, &budget);
+            {
+                let mut seen = seen.lock().unwrap();
+                cur_dir_res.retain(|path| seen.insert(path.clone()));
+            }
+            if !cur_dir_res.is_empty() {
+                on_dir(cur_dir_res.clone());
+            }
+            cur_dir_res
+        })
+        .collect::<Vec<Vec<String>>>()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// 文件名匹配器, 正则表达式在每次查询时只编译一次
+enum Matcher<'a> {
+    Regex(Regex),
+    Pattern { pattern: &'a str, is_fuzzy: bool },
+}
+
+impl<'a> Matcher<'a> {
+    fn new(pattern: &'a str, is_fuzzy: bool, is_regex: bool) -> Option<Matcher<'a>> {
+        if !is_regex {
+            return Some(Matcher::Pattern { pattern, is_fuzzy });
+        }
+        match Regex::new(pattern) {
+            Ok(re) => Some(Matcher::Regex(re)),
+            Err(e) => {
+                error!("Invalid regex pattern: {}, {}", pattern, e);
+                None
+            }
+        }
+    }
+
+    fn is_match(&self, file_name: &str) -> bool {
+        match self {
+            Matcher::Regex(re) => re.is_match(file_name),
+            Matcher::Pattern { pattern, is_fuzzy } => pattern_match(file_name, pattern, *is_fuzzy),
+        }
     }
+}
+
+/// 在文件夹中向下搜索 `depth` 层, 处于黑名单或被忽略文件排除的子文件夹在进入之前被跳过;
+/// 遍历得到的条目一定存在, 不需要再次检查
+fn search_target_from_dir(
+    directory: &Path,
+    matcher: &Matcher,
+    depth: usize,
+    budget: &SearchBudget,
+) -> Vec<String> {
+    let mut results = Vec::new();
 
-    for entry in WalkDir::new(directory).max_depth(1) {
-        // 只在热点文件夹中查询1次
+    let walker = WalkDir::new(directory)
+        .max_depth(depth)
+        .into_iter()
+        .filter_entry(|dir_entry| !is_filtered(dir_entry.path(), dir_entry.file_type().is_dir()));
+    for entry in walker {
+        if budget.should_stop() || !budget.consume_io() {
+            break;
+        }
         match entry {
             Ok(dir_entry) => {
-                debug!("searching path: {:#?}", dir_entry.path());
-                let file_name = dir_entry.file_name().to_str().unwrap();
-                if is_excluded(&dir_entry.path().to_path_buf()) {
-                    continue;
-                }
-
-                if is_regex {
-                    // 如果指定的是正则匹配
-                    if regex_match(&PathBuf::from(file_name), pattern) {
-                        results.push(dir_entry.path().to_string_lossy().to_string());
-                    }
-                } else {
-                    // 不使用正则匹配
-                    if pattern_match(file_name, pattern, is_fuzzy) {
-                        results.push(dir_entry.path().to_string_lossy().to_string());
-                    }
+                trace!("searching path: {:#?}", dir_entry.path());
+                let file_name = dir_entry.file_name().to_string_lossy();
+                if matcher.is_match(&file_name) && budget.record() {
+                    results.push(dir_entry.path().to_string_lossy().to_string());
                 }
             }
             Err(e) => {
-                error!("Error: {}", e);
+                debug!("walk hot dir error: {}", e);
             }
         }
     }
@@ -115,25 +492,171 @@ pub fn search_target_from_dir(
 
 #[cfg(test)]
 mod tests {
-    use crate::{cache::cache::init_trie, db::DB};
+    use std::sync::Arc;
+
+    use crate::{
+        cache::cache::init_trie,
+        db::{DbWriter, EntryMeta, MemoryDatabase},
+    };
 
     #[allow(unused)]
     use super::*;
+    use crate::util::test_util::TempDir;
+
+    /// 创建一个包含 example.psd 的临时热点文件夹, 并记录到内存数据库中
+    fn hot_dir_db(name: &str) -> (Arc<MemoryDatabase>, TempDir) {
+        let dir = TempDir::new(name);
+        std::fs::write(dir.join("example.psd"), b"psd").unwrap();
+
+        let db = Arc::new(MemoryDatabase::new());
+        db.insert_rec(&dir, &EntryMeta::new(&dir).unwrap()).unwrap();
+        (db, dir)
+    }
 
     #[test]
     fn test_search_files() {
-        init_trie(DB.clone()); // 构建热点文件夹
-                               // let dir = PathBuf::from("F:/betopgame/6BYe9bkf/bin/electron1.7.12");
-                               // let res = search_target_from_dir(&dir, "ffmpeg.dll", false);
-        let res = search_files_from_hot_dirs("example.psd", true, false);
+        let (db, dir) = hot_dir_db("hot-search");
+        init_trie(db); // 构建热点文件夹
+        let res = search_files_from_hot_dirs("example.psd", true, false, &SearchOptions::default());
         println!("{:?}", res);
-        assert!(res.len() > 0);
+        assert!(res.contains(&dir.join("example.psd").to_string_lossy().to_string()));
+    }
+
+    #[test]
+    fn test_search_cancelled() {
+        let (db, _dir) = hot_dir_db("hot-cancel");
+        init_trie(db); // 构建热点文件夹
+        let opts = SearchOptions::default();
+        opts.cancel.cancel();
+        assert!(search_files_from_hot_dirs("example.psd", true, false, &opts).is_empty());
+        assert!(opts.is_partial());
     }
 
     #[test]
     fn test_get_hot_dir() {
-        init_trie(DB.clone()); // 构建热点文件夹
+        let (db, dir) = hot_dir_db("hot-dir");
+        init_trie(db); // 构建热点文件夹
         let hot_dirs = get_hot_dir();
         println!("{:?}", hot_dirs);
+        assert!(hot_dirs.iter().any(|hot_dir| hot_dir.path == *dir));
+    }
+
+    #[test]
+    fn test_search_depth() {
+        let dir = TempDir::new("hot-depth");
+        let deep = dir.join("sub").join("deep");
+        let pruned = dir.join("node_modules");
+        std::fs::create_dir_all(&deep).unwrap();
+        std::fs::create_dir_all(&pruned).unwrap();
+        for parent in [&*dir, &dir.join("sub"), &deep, &pruned] {
+            std::fs::write(parent.join("a.txt"), b"a").unwrap();
+        }
+        let matcher = Matcher::new("a.txt", false, false).unwrap();
+
+        let opts = SearchOptions::default();
+        let budget = SearchBudget::new(&opts);
+        let res = search_target_from_dir(&dir, &matcher, 1, &budget);
+        assert_eq!(res, vec![dir.join("a.txt").to_string_lossy().to_string()]);
+
+        // 黑名单中的文件夹不会被遍历
+        let mut res = search_target_from_dir(&dir, &matcher, 3, &budget);
+        res.sort();
+        assert_eq!(res.len(), 3);
+        assert!(res.iter().all(|path| !path.contains("node_modules")));
+        assert!(!opts.is_partial());
+
+        // I/O 预算用完后返回部分结果
+        let mut opts = SearchOptions::default();
+        opts.io_budget = 2;
+        let budget = SearchBudget::new(&opts);
+        assert!(search_target_from_dir(&dir, &matcher, 3, &budget).len() <= 1);
+        assert!(opts.is_partial());
+    }
+
+    #[test]
+    fn test_rank_and_decay() {
+        let now = SystemTime::now();
+        let mut hot_dirs = HotDirs::new(2);
+        hot_dirs.record(Path::new("/data/a/x.txt"), false, now);
+        hot_dirs.record(Path::new("/data/a/y.txt"), false, now);
+        hot_dirs.record(Path::new("/data/b/z.txt"), false, now);
+
+        // /data 汇总了两个子文件夹的活跃度: 3 次访问, 每次权重 0.5
+        let list = hot_dirs.list(now);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].path, PathBuf::from("/data/a"));
+        assert_eq!(list[0].score, 2.0);
+        assert_eq!(list[1].path, PathBuf::from("/data"));
+        assert_eq!(list[1].score, 1.5);
+
+        // 一个半衰期后分数减半, 新的访问使 /data/b 排到前面
+        let later = now + HOT_DIR_HALF_LIFE;
+        assert_eq!(hot_dirs.list(later)[0].score, 1.0);
+        hot_dirs.record(Path::new("/data/b"), true, later);
+        hot_dirs.record(Path::new("/data/b/w.txt"), false, later);
+        let list = hot_dirs.list(later);
+        assert_eq!(list[0].path, PathBuf::from("/data/b"));
+        assert_eq!(list[0].score, 2.5);
+
+        // 重复合并同一份统计不会累加
+        let mut seed = HashMap::new();
+        accumulate(&mut seed, Path::new("/data/c/v.txt"), EntryType::File, 9);
+        hot_dirs.seed(seed.clone(), later);
+        hot_dirs.seed(seed, later);
+        assert_eq!(hot_dirs.list(later)[0].path, PathBuf::from("/data/c"));
+        assert_eq!(hot_dirs.list(later)[0].score, 10.0);
+    }
+
+    #[test]
+    fn test_pin() {
+        let now = SystemTime::now();
+        let mut hot_dirs = HotDirs::new(1);
+        hot_dirs.record(Path::new("/data/a/x.txt"), false, now);
+        assert!(hot_dirs.pin(Path::new("/pinned")));
+        assert!(!hot_dirs.pin(Path::new("/pinned")));
+
+        // 固定的文件夹排在最前, 且不受容量限制
+        let list = hot_dirs.list(now);
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].path, PathBuf::from("/pinned"));
+        assert!(list[0].pinned);
+
+        hot_dirs.retain(|_| false);
+        assert_eq!(hot_dirs.list(now).len(), 1);
+        assert!(hot_dirs.unpin(Path::new("/pinned")));
+        assert!(hot_dirs.list(now).is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let now = SystemTime::now();
+        let mut hot_dirs = HotDirs::new(4);
+        hot_dirs.record(Path::new("/data/old/sub"), true, now);
+        hot_dirs.record(Path::new("/data/older"), true, now);
+        hot_dirs.pin(Path::new("/data/old/sub"));
+
+        hot_dirs.rename(Path::new("/data/old"), Path::new("/data/new"));
+        let paths: Vec<PathBuf> = hot_dirs.list(now).into_iter().map(|dir| dir.path).collect();
+        assert!(paths.contains(&PathBuf::from("/data/new/sub")));
+        assert!(paths.contains(&PathBuf::from("/data/new")));
+        assert!(paths.contains(&PathBuf::from("/data/older")));
+        assert!(!paths.iter().any(|path| path.starts_with("/data/old")));
+        assert!(hot_dirs.list(now)[0].pinned);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path =
+            std::env::temp_dir().join(format!("elf-hot-dirs-{}.hotdirs", std::process::id()));
+        let now = SystemTime::now();
+        let mut hot_dirs = HotDirs::new(2);
+        hot_dirs.record(Path::new("/data/a/x.txt"), false, now);
+        hot_dirs.pin(Path::new("/pinned"));
+        write_hot_dirs(&path, &bincode::serialize(&hot_dirs).unwrap()).unwrap();
+
+        let mut loaded = read_hot_dirs(&path).unwrap().unwrap();
+        loaded.capacity = 2;
+        assert_eq!(loaded.list(now), hot_dirs.list(now));
+        fs::remove_file(&path).unwrap();
     }
 }
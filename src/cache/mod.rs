@@ -1,13 +1,22 @@
 pub mod cache;
 pub mod trie;
 pub mod hot_dir;
+pub mod name_index;
+pub mod path_regex;
+pub mod query_cache;
+pub mod search;
+pub mod snapshot;
+pub mod trigram;
+pub mod reconcile;
+pub mod rescan;
 
-use std::sync::Mutex;
+use std::sync::RwLock;
 
 pub use cache::Cacher;
 
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref CACHER: Mutex<Cacher> = Mutex::new(Cacher::new());
+    /// 搜索只需要读锁, 可以并发进行; 文件变化和初始化等修改操作持有写锁
+    pub static ref CACHER: RwLock<Cacher> = RwLock::new(Cacher::new());
 }
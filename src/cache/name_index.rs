@@ -0,0 +1,131 @@
+//! 文件名倒排索引
+//!
+//! 以小写的文件名(或文件夹名)为键, 记录 `TrieCache` 中所有包含该名字的节点,
+//! 精确查询和前缀查询不再需要遍历整棵树。模糊查询和正则查询仍然走完整遍历。
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    hash::Hash,
+};
+
+pub struct NameIndex<T> {
+    names: BTreeMap<String, HashSet<T>>,
+}
+
+impl<T> Default for NameIndex<T> {
+    fn default() -> Self {
+        NameIndex {
+            names: BTreeMap::new(),
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.to_lowercase()
+}
+
+impl<T: Eq + Hash + Clone> NameIndex<T> {
+    pub fn new() -> Self {
+        NameIndex::default()
+    }
+
+    pub fn insert(&mut self, name: &str, value: T) {
+        self.names.entry(normalize(name)).or_default().insert(value);
+    }
+
+    pub fn remove(&mut self, name: &str, value: &T) {
+        let key = normalize(name);
+        if let Some(values) = self.names.get_mut(&key) {
+            values.remove(value);
+            if values.is_empty() {
+                self.names.remove(&key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+    }
+
+    /// 索引中不同文件名的个数
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// 与 `pattern_match(name, pattern, false)` 的语义一致:
+    /// 文件名以 `pattern` 开头, 或 `pattern` 以文件名开头, 均不区分大小写
+    /// 返回的结果可能重复, 由调用方去重
+    pub fn search(&self, pattern: &str) -> Vec<T> {
+        let pattern = normalize(pattern);
+        let mut results = Vec::new();
+
+        // 以 pattern 开头的文件名, 在有序的键上做一次范围查询
+        for (_, values) in self
+            .names
+            .range(pattern.clone()..)
+            .take_while(|(name, _)| name.starts_with(&pattern))
+        {
+            results.extend(values.iter().cloned());
+        }
+
+        // 是 pattern 真前缀的文件名, 逐个前缀精确查找
+        for (idx, _) in pattern.char_indices().skip(1) {
+            if let Some(values) = self.names.get(&pattern[..idx]) {
+                results.extend(values.iter().cloned());
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::pattern_match;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_search() {
+        let mut index = NameIndex::new();
+        let names = ["File1.txt", "file2.txt", "documents", "fi", "小论文.docx"];
+        for name in names {
+            index.insert(name, PathBuf::from("/data").join(name));
+        }
+        index.insert("file1.txt", PathBuf::from("/backup/file1.txt"));
+
+        for pattern in ["file1.txt", "FILE", "fil", "documents/a", "小论", "nothing"] {
+            let mut results = index.search(pattern);
+            results.sort();
+            let mut expected: Vec<PathBuf> = names
+                .iter()
+                .filter(|name| pattern_match(name, pattern, false))
+                .map(|name| PathBuf::from("/data").join(name))
+                .collect();
+            if pattern_match("file1.txt", pattern, false) {
+                expected.push(PathBuf::from("/backup/file1.txt"));
+            }
+            expected.sort();
+            assert_eq!(results, expected, "pattern: {}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut index = NameIndex::new();
+        let path1 = PathBuf::from("/a/file.txt");
+        let path2 = PathBuf::from("/b/file.txt");
+        index.insert("file.txt", path1.clone());
+        index.insert("file.txt", path2.clone());
+
+        index.remove("file.txt", &path1);
+        assert_eq!(index.search("file.txt"), vec![path2.clone()]);
+
+        index.remove("file.txt", &path2);
+        assert!(index.is_empty());
+    }
+}
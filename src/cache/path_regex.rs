@@ -0,0 +1,157 @@
+//! 对 trie 树做剪枝的路径正则匹配
+//!
+//! 正则表达式在每次查询时只编译一次。同时构建一个惰性 DFA, 沿着 trie 树向下遍历时
+//! 逐字节推进 DFA 状态: 一旦进入死状态且之前没有经过匹配状态, 说明以当前路径为前缀的任何路径
+//! 都不可能匹配, 整棵子树都可以跳过。经过匹配状态后, 以当前路径为前缀的路径都包含这个匹配。对于 `^/home/me/proj/.*\.md$` 这类锚定的正则,
+//! 遍历只会进入字面量前缀对应的子树。
+
+use std::path::Path;
+
+use regex::Regex;
+use regex_automata::{
+    hybrid::dfa::{Cache, DFA},
+    util::start,
+    Anchored, MatchKind,
+};
+
+use crate::util::errors::CustomError;
+
+/// 遍历过程中的 DFA 状态, `sid` 为 None 时无法判断, 不做剪枝
+#[derive(Clone, Copy)]
+pub struct PathState {
+    sid: Option<regex_automata::hybrid::LazyStateID>,
+    /// 已经读入的部分中出现过匹配, 之后的字节不会改变结果
+    matched: bool,
+}
+
+impl PathState {
+    const UNKNOWN: PathState = PathState {
+        sid: None,
+        matched: false,
+    };
+
+    /// 以当前路径为前缀的路径都不可能匹配
+    ///
+    /// 匹配完成后 `MatchKind::All` 的 DFA 也会进入死状态, 此时以当前路径为前缀的路径都匹配
+    pub fn is_dead(&self) -> bool {
+        !self.matched && self.sid.is_some_and(|sid| sid.is_dead())
+    }
+}
+
+#[derive(Clone)]
+pub struct PathRegex {
+    re: Regex,
+    dfa: Option<(DFA, Cache)>,
+}
+
+impl PathRegex {
+    pub fn new(pattern: &str) -> Result<PathRegex, CustomError> {
+        let re = Regex::new(pattern)
+            .map_err(|e| CustomError::from(format!("Invalid regex pattern: {}", e)))?;
+        // 使用 MatchKind::All, 匹配成功后 DFA 不会提前终止, 死状态只表示不可能再有匹配;
+        // 惰性 DFA 不支持的正则(例如 Unicode 单词边界)不做剪枝
+        let dfa = DFA::builder()
+            .configure(DFA::config().match_kind(MatchKind::All))
+            .build(pattern)
+            .ok()
+            .map(|dfa| {
+                let cache = dfa.create_cache();
+                (dfa, cache)
+            });
+        Ok(PathRegex { re, dfa })
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.re.is_match(path.to_str().unwrap_or(""))
+    }
+
+    /// 空路径对应的初始状态
+    pub fn start(&mut self) -> PathState {
+        PathState {
+            sid: self.dfa.as_mut().and_then(|(dfa, cache)| {
+                dfa.start_state(cache, &start::Config::new().anchored(Anchored::No))
+                    .ok()
+            }),
+            matched: false,
+        }
+    }
+
+    /// 从 `state` 出发继续读入 `bytes`
+    pub fn advance(&mut self, state: PathState, bytes: &[u8]) -> PathState {
+        if state.matched {
+            return state;
+        }
+        let (dfa, cache) = match self.dfa.as_mut() {
+            Some(dfa) => dfa,
+            None => return PathState::UNKNOWN,
+        };
+        let mut sid = match state.sid {
+            Some(sid) => sid,
+            None => return PathState::UNKNOWN,
+        };
+        for &byte in bytes {
+            if sid.is_dead() {
+                break;
+            }
+            sid = match dfa.next_state(cache, sid, byte) {
+                Ok(sid) if !sid.is_quit() => sid,
+                // 缓存失效或遇到 quit 字节, 放弃剪枝
+                _ => return PathState::UNKNOWN,
+            };
+            // 匹配状态延迟一个字节, 表示之前读入的部分已经匹配
+            if sid.is_match() {
+                return PathState {
+                    sid: Some(sid),
+                    matched: true,
+                };
+            }
+        }
+        PathState {
+            sid: Some(sid),
+            matched: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_of(re: &mut PathRegex, path: &str) -> PathState {
+        let start = re.start();
+        re.advance(start, path.as_bytes())
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut re = PathRegex::new(r"^/home/me/proj/.*\.md$").unwrap();
+        assert!(!state_of(&mut re, "/home").is_dead());
+        assert!(!state_of(&mut re, "/home/me/proj/src").is_dead());
+        assert!(state_of(&mut re, "/home/you").is_dead());
+        assert!(state_of(&mut re, "/tmp").is_dead());
+        assert!(re.is_match(Path::new("/home/me/proj/README.md")));
+
+        // 匹配之后的路径仍可能匹配, 不能剪枝
+        let mut re = PathRegex::new("proj").unwrap();
+        assert!(!state_of(&mut re, "/home/me/proj").is_dead());
+        assert!(!state_of(&mut re, "/tmp/other").is_dead());
+
+        // 已经匹配完毕且不能再延长的路径
+        let mut re = PathRegex::new("^/home$").unwrap();
+        assert!(!state_of(&mut re, "/home").is_dead());
+        assert!(state_of(&mut re, "/home/me").is_dead());
+
+        // 短的锚定前缀匹配完毕后, 更长的路径都匹配
+        let mut re = PathRegex::new("^/h").unwrap();
+        assert!(!state_of(&mut re, "/home/me/proj").is_dead());
+        assert!(state_of(&mut re, "/tmp").is_dead());
+        let mut re = PathRegex::new("^/home/me").unwrap();
+        assert!(!state_of(&mut re, "/home/me/proj/a.md").is_dead());
+        assert!(state_of(&mut re, "/home/you").is_dead());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(PathRegex::new("(foo").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! 查询结果缓存
+//!
+//! 前端在每次按键时都会发起查询。查询结果按规范化后的查询和选项缓存, 并记录查询时
+//! `TrieCache` 的版本号: 缓存的任何修改都会更新版本号, 旧版本的结果随之失效。
+//! 新查询是之前某个查询的延长(例如 "fea" → "feat")时, 直接在之前的完整结果中筛选,
+//! 不再遍历整棵树。模糊查询和正则查询不满足单调性, 只复用完全相同的查询。
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::util::pattern_match;
+
+/// 最多缓存的查询数
+const CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum QueryKind {
+    Entry { fuzzy: bool },
+    Regex,
+    Substring,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    kind: QueryKind,
+    query: String,
+    limit: usize,
+}
+
+impl QueryKey {
+    /// 文件名匹配不区分大小写
+    pub fn entry(entry: &str, is_fuzzy: bool, limit: usize) -> QueryKey {
+        QueryKey {
+            kind: QueryKind::Entry { fuzzy: is_fuzzy },
+            query: entry.to_lowercase(),
+            limit,
+        }
+    }
+
+    pub fn regex(pattern: &str, limit: usize) -> QueryKey {
+        QueryKey {
+            kind: QueryKind::Regex,
+            query: pattern.to_string(),
+            limit,
+        }
+    }
+
+    /// 子串匹配忽略 ASCII 大小写
+    pub fn substring(substring: &str, limit: usize) -> QueryKey {
+        QueryKey {
+            kind: QueryKind::Substring,
+            query: substring.to_ascii_lowercase(),
+            limit,
+        }
+    }
+
+    /// 本查询的结果是否一定包含在 `prev` 的结果中
+    fn narrows(&self, prev: &QueryKey) -> bool {
+        if self.kind != prev.kind || self.query.len() <= prev.query.len() {
+            return false;
+        }
+        match self.kind {
+            // 文件名以 "feat" 开头或是 "feat" 的前缀, 一定也满足 "fea" 的条件
+            QueryKind::Entry { fuzzy: false } => self.query.starts_with(&prev.query),
+            QueryKind::Substring => self.query.contains(&prev.query),
+            _ => false,
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => path.to_string_lossy(),
+        };
+        match self.kind {
+            QueryKind::Entry { fuzzy } => pattern_match(&name, &self.query, fuzzy),
+            QueryKind::Substring => name.to_ascii_lowercase().contains(&self.query),
+            QueryKind::Regex => false,
+        }
+    }
+}
+
+struct Cached {
+    key: QueryKey,
+    generation: u64,
+    results: Vec<PathBuf>,
+    /// 结果是否包含所有匹配, 没有被上限截断, 只有完整的结果才能用于筛选
+    complete: bool,
+}
+
+/// 查询缓存的命中统计
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub narrowed: u64,
+    pub misses: u64,
+}
+
+#[derive(Default)]
+pub struct QueryCache {
+    /// 按插入顺序排列, 超过容量时淘汰最早的查询
+    entries: VecDeque<Cached>,
+    stats: QueryCacheStats,
+}
+
+impl QueryCache {
+    pub fn new() -> QueryCache {
+        QueryCache::default()
+    }
+
+    /// 查找缓存的结果, 没有完全相同的查询时尝试在之前的完整结果中筛选
+    pub fn lookup(&mut self, key: &QueryKey, generation: u64) -> Option<Vec<PathBuf>> {
+        // 版本号变化说明缓存被修改过, 之前的结果全部失效
+        self.entries
+            .retain(|cached| cached.generation == generation);
+
+        if let Some(cached) = self.entries.iter().find(|cached| cached.key == *key) {
+            self.stats.hits += 1;
+            return Some(cached.results.clone());
+        }
+
+        let prev = self
+            .entries
+            .iter()
+            .filter(|cached| cached.complete && key.narrows(&cached.key))
+            .max_by_key(|cached| cached.key.query.len());
+        let prev = match prev {
+            Some(prev) => prev,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+
+        let mut results: Vec<PathBuf> = prev
+            .results
+            .iter()
+            .filter(|path| key.matches(path))
+            .cloned()
+            .collect();
+        let complete = key.limit == 0 || results.len() < key.limit;
+        if !complete {
+            results.truncate(key.limit);
+        }
+        self.stats.narrowed += 1;
+        // 筛选后的结果同样可以用于下一次按键
+        self.insert(key.clone(), generation, results.clone(), complete);
+        Some(results)
+    }
+
+    pub fn insert(
+        &mut self,
+        key: QueryKey,
+        generation: u64,
+        results: Vec<PathBuf>,
+        complete: bool,
+    ) {
+        self.entries.retain(|cached| cached.key != key);
+        if self.entries.len() >= CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Cached {
+            key,
+            generation,
+            results,
+            complete,
+        });
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| PathBuf::from("/data").join(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_narrow() {
+        let mut cache = QueryCache::new();
+        let key = QueryKey::entry("fea", false, 0);
+        assert!(cache.lookup(&key, 1).is_none());
+        cache.insert(
+            key,
+            1,
+            paths(&["feature.rs", "Feat", "fe", "fear.txt"]),
+            true,
+        );
+
+        let res = cache.lookup(&QueryKey::entry("FEAT", false, 0), 1).unwrap();
+        assert_eq!(res, paths(&["feature.rs", "Feat", "fe"]));
+        let res = cache
+            .lookup(&QueryKey::entry("featu", false, 0), 1)
+            .unwrap();
+        assert_eq!(res, paths(&["feature.rs", "Feat", "fe"]));
+        let res = cache
+            .lookup(&QueryKey::entry("featx", false, 0), 1)
+            .unwrap();
+        assert_eq!(res, paths(&["Feat", "fe"]));
+
+        // 模糊查询和正则查询不筛选
+        let fuzzy = QueryKey::entry("fea", true, 0);
+        cache.insert(fuzzy, 1, paths(&["feature.rs"]), true);
+        assert!(cache.lookup(&QueryKey::entry("feat", true, 0), 1).is_none());
+
+        // 版本号变化后全部失效
+        assert!(cache.lookup(&QueryKey::entry("fea", false, 0), 2).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.narrowed, stats.misses), (0, 3, 3));
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let mut cache = QueryCache::new();
+        let key = QueryKey::substring("Rep", 2);
+        cache.insert(key.clone(), 1, paths(&["report.md", "repo"]), false);
+        assert_eq!(
+            cache.lookup(&key, 1).unwrap(),
+            paths(&["report.md", "repo"])
+        );
+        // 被上限截断的结果不能用于筛选
+        assert!(cache.lookup(&QueryKey::substring("repor", 2), 1).is_none());
+
+        cache.insert(key, 1, paths(&["report.md"]), true);
+        assert_eq!(
+            cache.lookup(&QueryKey::substring("repor", 1), 1).unwrap(),
+            paths(&["report.md"])
+        );
+    }
+}
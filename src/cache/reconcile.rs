@@ -0,0 +1,162 @@
+//! 后台对账
+//!
+//! 启动时不存在的记录(例如未挂载的移动硬盘或网络共享)不再直接删除, 而是从缓存中移除并在数据库中标记为不可用。
+//! 后台线程定期检查这些记录: 路径重新出现时恢复到缓存中, 超过宽限期仍不存在才真正删除。
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+
+use crate::{db::Database, util::is_blacklisted};
+
+use super::CACHER;
+
+/// 一次对账的结果
+#[derive(Debug, Default, PartialEq)]
+pub struct ReconcileStats {
+    /// 重新出现并恢复到缓存中的记录数
+    pub restored: usize,
+    /// 超过宽限期被删除的记录数
+    pub purged: usize,
+    /// 仍然不可用, 等待下次检查的记录数
+    pub pending: usize,
+}
+
+/// 将不存在的路径从缓存中移除, 并在数据库中标记为不可用
+pub fn mark_missing(db: &Arc<dyn Database>, path: &PathBuf) {
+    debug!("path not exists: {:#?}, marked as unavailable", path);
+    CACHER.write().unwrap().remove_path(path);
+    if let Err(e) = db.mark_unavailable(path, SystemTime::now()) {
+        error!("mark path unavailable error: {}", e);
+    }
+}
+
+/// 检查所有不可用的记录
+pub fn reconcile_once(db: &Arc<dyn Database>, grace: Duration) -> ReconcileStats {
+    let mut stats = ReconcileStats::default();
+    let unavailable = match db.find_unavailable() {
+        Ok(unavailable) => unavailable,
+        Err(e) => {
+            error!("find unavailable records error: {}", e);
+            return stats;
+        }
+    };
+
+    let now = SystemTime::now();
+    for (path, since) in unavailable {
+        if is_blacklisted(&path) {
+            // 黑名单是用户配置的, 与路径是否挂载无关, 直接删除
+            if let Err(e) = db.delete_by_path(&path) {
+                error!("delete path error: {}", e);
+            }
+            stats.purged += 1;
+        } else if path.exists() {
+            restore(db, &path);
+            stats.restored += 1;
+        } else if now.duration_since(since).unwrap_or_default() >= grace {
+            debug!("path {:#?} unavailable since {:?}, purged", &path, since);
+            if let Err(e) = db.delete_by_path(&path) {
+                error!("delete path error: {}", e);
+            }
+            stats.purged += 1;
+        } else {
+            stats.pending += 1;
+        }
+    }
+
+    stats
+}
+
+/// 路径重新出现后清除标记, 并将数据库中的记录恢复到缓存中
+fn restore(db: &Arc<dyn Database>, path: &PathBuf) {
+    debug!("path {:#?} is available again, restored", path);
+    if let Err(e) = db.mark_available(path) {
+        error!("mark path available error: {}", e);
+    }
+    match db.find_by_path(path) {
+        Ok(Some(meta)) => {
+            if let Err(e) = CACHER.write().unwrap().add_path(path, Some(meta), false) {
+                error!("restore path to cache error: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("find path error: {}", e),
+    }
+}
+
+/// 启动后台对账线程
+pub fn spawn_reconciler(
+    db: Arc<dyn Database>,
+    interval: Duration,
+    grace: Duration,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let stats = reconcile_once(&db, grace);
+        if stats.restored > 0 || stats.purged > 0 {
+            info!(
+                "reconcile: {} restored, {} purged, {} pending",
+                stats.restored, stats.purged, stats.pending
+            );
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        db::{EntryMeta, MemoryDatabase},
+        util::test_util::TempDir,
+    };
+
+    #[test]
+    fn test_reconcile() {
+        let dir = TempDir::new("reconcile");
+        let mounted = dir.join("mounted.txt");
+        let pending = dir.join("pending.txt");
+        let expired = dir.join("expired.txt");
+
+        let db: Arc<dyn Database> = Arc::new(MemoryDatabase::new());
+        let long_ago = SystemTime::now() - Duration::from_secs(3600);
+        for path in [&mounted, &pending, &expired] {
+            db.insert_rec(path, &EntryMeta::new_empty().unwrap())
+                .unwrap();
+        }
+        mark_missing(&db, &mounted);
+        mark_missing(&db, &pending);
+        db.mark_unavailable(&expired, long_ago).unwrap();
+
+        // 模拟重新挂载
+        fs::write(&mounted, b"mounted").unwrap();
+
+        let stats = reconcile_once(&db, Duration::from_secs(60));
+        assert_eq!(
+            stats,
+            ReconcileStats {
+                restored: 1,
+                purged: 1,
+                pending: 1
+            }
+        );
+
+        assert!(CACHER.read().unwrap().contains_path(&mounted));
+        assert!(db.find_by_path(&pending).unwrap().is_some());
+        assert!(db.find_by_path(&expired).unwrap().is_none());
+        assert_eq!(
+            db.find_unavailable()
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            vec![pending]
+        );
+    }
+}
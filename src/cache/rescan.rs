@@ -0,0 +1,263 @@
+//! 事件队列溢出后的子树对账
+//!
+//! 内核事件队列溢出时, 溢出期间的变化全部丢失。对受影响的子树中每个已索引的路径,
+//! 比较缓存和数据库中的记录与文件系统的实际状态, 补上漏掉的删除和元数据更新;
+//! 再遍历子树, 将溢出前后修改过但尚未索引的文件作为漏掉的新建加入索引。
+//! 对账在后台线程中进行, 进度通过 stats 接口返回。
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::SystemTime,
+};
+
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::{
+    db::{Database, EntryMeta},
+    util::is_filtered,
+};
+
+use super::{Cacher, CACHER};
+
+/// 每处理这么多条目更新一次进度
+const PROGRESS_STEP: usize = 256;
+
+lazy_static! {
+    /// 每个子树最近一次对账的进度
+    static ref RESCANS: Mutex<HashMap<PathBuf, RescanProgress>> = Mutex::new(HashMap::new());
+}
+
+/// 一次子树对账的进度
+#[derive(Debug, Clone, Serialize)]
+pub struct RescanProgress {
+    pub root: PathBuf,
+    pub running: bool,
+    /// 已检查的条目数
+    pub checked: usize,
+    pub created: usize,
+    pub deleted: usize,
+    pub updated: usize,
+    pub started: SystemTime,
+    pub finished: Option<SystemTime>,
+}
+
+impl RescanProgress {
+    fn new(root: &Path) -> RescanProgress {
+        RescanProgress {
+            root: root.to_path_buf(),
+            running: true,
+            checked: 0,
+            created: 0,
+            deleted: 0,
+            updated: 0,
+            started: SystemTime::now(),
+            finished: None,
+        }
+    }
+
+    fn publish(&self) {
+        RESCANS
+            .lock()
+            .unwrap()
+            .insert(self.root.clone(), self.clone());
+    }
+
+    fn step(&mut self) {
+        self.checked += 1;
+        if self.checked.is_multiple_of(PROGRESS_STEP) {
+            self.publish();
+        }
+    }
+}
+
+/// 所有子树最近一次对账的进度
+pub fn rescan_status() -> Vec<RescanProgress> {
+    let mut res: Vec<RescanProgress> = RESCANS.lock().unwrap().values().cloned().collect();
+    res.sort_by_key(|progress| progress.started);
+    res
+}
+
+/// 大小、修改时间或类型是否变化
+pub(crate) fn changed(indexed: &EntryMeta, fresh: &EntryMeta) -> bool {
+    indexed.size != fresh.size
+        || indexed.modified != fresh.modified
+        || indexed.entry_type != fresh.entry_type
+}
+
+/// 对账 `root` 子树, `since` 之后修改过且尚未索引的文件被视为漏掉的新建;
+/// 同一子树已经在对账时返回 None
+pub fn rescan_subtree(
+    db: &Arc<dyn Database>,
+    cacher: &RwLock<Cacher>,
+    root: &Path,
+    since: SystemTime,
+) -> Option<RescanProgress> {
+    {
+        let mut rescans = RESCANS.lock().unwrap();
+        if rescans.get(root).is_some_and(|progress| progress.running) {
+            debug!("rescan of {:#?} is already running", root);
+            return None;
+        }
+        rescans.insert(root.to_path_buf(), RescanProgress::new(root));
+    }
+    let mut progress = RescanProgress::new(root);
+    info!("rescan {:#?} for changes since {:?}", root, since);
+
+    // 缓存中的条目可能已被淘汰, 数据库中的记录可能还没写入, 两者合并
+    let mut indexed: HashMap<PathBuf, EntryMeta> = match db.find_by_path_prefix(&root.to_path_buf())
+    {
+        Ok(metas) => metas
+            .into_iter()
+            .filter(|meta| meta.path.starts_with(root))
+            .map(|meta| (meta.path.clone(), meta))
+            .collect(),
+        Err(e) => {
+            error!("find records under {:#?} error: {}", root, e);
+            HashMap::new()
+        }
+    };
+    for meta in cacher.read().unwrap().entries_under(root) {
+        indexed.entry(meta.path.clone()).or_insert(meta);
+    }
+
+    // 整个子树都不存在时更可能是被卸载了, 交给后台对账处理
+    let unmounted = !root.exists();
+    for (path, meta) in &indexed {
+        progress.step();
+        match EntryMeta::new(path) {
+            Err(_) if unmounted => {
+                cacher.write().unwrap().remove_path(path);
+                if let Err(e) = db.mark_unavailable(path, SystemTime::now()) {
+                    error!("mark path unavailable error: {}", e);
+                }
+                progress.deleted += 1;
+            }
+            Err(_) => {
+                cacher.write().unwrap().remove_path(path);
+                if let Err(e) = db.delete_by_path(path) {
+                    error!("delete path error: {}", e);
+                }
+                progress.deleted += 1;
+            }
+            Ok(fresh) if changed(meta, &fresh) => {
+                let refreshed = cacher.write().unwrap().refresh_path(path);
+                let mut fresh = refreshed.unwrap_or(fresh);
+                fresh.access_count = fresh.access_count.max(meta.access_count);
+                if let Err(e) = db.update_meta(path, &fresh) {
+                    error!("update path error: {}", e);
+                }
+                progress.updated += 1;
+            }
+            Ok(_) => {}
+        }
+    }
+
+    if !unmounted {
+        // 子树的根是监听目标本身, 不需要索引
+        let walker = WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| !is_filtered(entry.path(), entry.file_type().is_dir()));
+        for entry in walker.flatten() {
+            progress.step();
+            let path = entry.path().to_path_buf();
+            if indexed.contains_key(&path) {
+                continue;
+            }
+            let modified = entry
+                .metadata()
+                .ok()
+                .and_then(|metadata| metadata.modified().ok());
+            if modified.is_none_or(|modified| modified < since) {
+                continue;
+            }
+            let meta = match cacher.write().unwrap().add_path(&path, None, false) {
+                Ok(Some(meta)) => meta,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("add path error: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = db.insert_rec(&path, &meta) {
+                error!("insert path error: {}", e);
+            }
+            progress.created += 1;
+        }
+    }
+
+    progress.running = false;
+    progress.finished = Some(SystemTime::now());
+    progress.publish();
+    info!(
+        "rescan {:#?} finished: {} checked, {} created, {} deleted, {} updated",
+        root, progress.checked, progress.created, progress.deleted, progress.updated
+    );
+    Some(progress)
+}
+
+/// 在后台线程中对账子树
+pub fn spawn_rescan(db: Arc<dyn Database>, root: PathBuf, since: SystemTime) {
+    thread::spawn(move || rescan_subtree(&db, &CACHER, &root, since));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::{db::MemoryDatabase, util::test_util::TempDir};
+
+    #[test]
+    fn test_rescan_subtree() {
+        let root = TempDir::new("rescan");
+        let cacher = RwLock::new(Cacher::new());
+        let kept = root.join("kept.txt");
+        let changed = root.join("changed.txt");
+        let removed = root.join("removed.txt");
+        let missed = root.join("missed.txt");
+        for path in [&kept, &changed, &removed] {
+            fs::write(path, b"old").unwrap();
+        }
+
+        let db: Arc<dyn Database> = Arc::new(MemoryDatabase::new());
+        for path in [&kept, &changed, &removed] {
+            let mut meta = EntryMeta::new(path).unwrap();
+            meta.access_count = 3;
+            db.insert_rec(path, &meta).unwrap();
+            cacher
+                .write()
+                .unwrap()
+                .add_path(path, Some(meta), false)
+                .unwrap();
+        }
+        let since = SystemTime::now() - Duration::from_secs(60);
+
+        // 溢出期间丢失的变化
+        fs::write(&changed, b"new content").unwrap();
+        fs::remove_file(&removed).unwrap();
+        fs::write(&missed, b"missed").unwrap();
+
+        let progress = rescan_subtree(&db, &cacher, &root, since).unwrap();
+        assert_eq!(
+            (progress.created, progress.deleted, progress.updated),
+            (1, 1, 1)
+        );
+        assert!(!progress.running);
+
+        let meta = db.find_by_path(&changed).unwrap().unwrap();
+        assert_eq!((meta.size, meta.access_count), (11, 3));
+        assert!(db.find_by_path(&removed).unwrap().is_none());
+        assert!(db.find_by_path(&missed).unwrap().is_some());
+        let cacher = cacher.read().unwrap();
+        assert!(cacher.contains_path(&missed));
+        assert!(!cacher.contains_path(&removed));
+        assert!(rescan_status().iter().any(|status| status.root == *root));
+    }
+}
@@ -0,0 +1,261 @@
+//! 搜索的结果上限、截止时间, 以及多线程遍历时部分结果的合并
+//!
+//! 节点数超过 `PARALLEL_THRESHOLD` 的缓存在 rayon 的工作窃取线程池中遍历,
+//! 每个线程各自保留得分最高的 k 个结果, 最后合并; 所有线程共享同一个 `SearchBudget`,
+//! 超过截止时间或查询被取消后, 每个线程都会尽快停止。缓存中的搜索需要遍历所有候选才能得到
+//! 得分最高的结果, 不会因为结果数达到上限而提前停止; 遍历磁盘的智能模式没有得分,
+//! 用 `SearchBudget::record` 在找到足够的结果后停止。
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// 节点数不超过该值时单线程遍历, 避免短查询承担线程调度的开销
+pub const PARALLEL_THRESHOLD: usize = 50_000;
+
+/// 查询的取消令牌, 克隆后共享同一个状态
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 是否是同一个令牌的克隆
+    pub fn same(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// 结果数上限, 为 0 时不限制
+    pub limit: usize,
+    /// 截止时间, 超过后返回已经找到的结果
+    pub deadline: Option<Instant>,
+    /// 被取消后返回已经找到的结果
+    pub cancel: CancelToken,
+    /// 遍历磁盘时最多读取的条目数, 用完后返回已经找到的结果, 为 0 时不限制
+    pub io_budget: usize,
+    /// 是否因截止时间或取消而提前结束, 由 `SearchBudget` 设置
+    interrupted: Arc<AtomicBool>,
+}
+
+impl SearchOptions {
+    pub fn with_limit(limit: usize) -> SearchOptions {
+        SearchOptions {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// 结果是否因截止时间或取消而不完整
+    pub fn is_partial(&self) -> bool {
+        self.interrupted.load(AtomicOrdering::Relaxed)
+    }
+}
+
+/// 多个线程共享的搜索预算
+pub struct SearchBudget<'a> {
+    opts: &'a SearchOptions,
+    found: AtomicUsize,
+    /// 已经读取的磁盘条目数
+    io_used: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl<'a> SearchBudget<'a> {
+    pub fn new(opts: &'a SearchOptions) -> SearchBudget<'a> {
+        SearchBudget {
+            opts,
+            found: AtomicUsize::new(0),
+            io_used: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// 是否应该停止遍历
+    pub fn should_stop(&self) -> bool {
+        if self.stopped.load(AtomicOrdering::Relaxed) {
+            return true;
+        }
+        let expired = self
+            .opts
+            .deadline
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false);
+        if expired || self.opts.cancel.is_cancelled() {
+            self.stopped.store(true, AtomicOrdering::Relaxed);
+            self.opts.interrupted.store(true, AtomicOrdering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// 记录一次磁盘读取, I/O 预算用完后通知所有线程停止并返回 false, 结果视为不完整
+    pub fn consume_io(&self) -> bool {
+        if self.opts.io_budget == 0 {
+            return true;
+        }
+        let used = self.io_used.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        if used > self.opts.io_budget {
+            self.stopped.store(true, AtomicOrdering::Relaxed);
+            self.opts.interrupted.store(true, AtomicOrdering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// 记录一个匹配, 达到上限后通知所有线程停止; 超出上限的匹配返回 false
+    ///
+    /// 只用于不排序的磁盘遍历, 返回的是最先找到的结果
+    pub fn record(&self) -> bool {
+        if self.opts.limit == 0 {
+            return true;
+        }
+        let found = self.found.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        if found >= self.opts.limit {
+            self.stopped.store(true, AtomicOrdering::Relaxed);
+        }
+        found <= self.opts.limit
+    }
+}
+
+/// 按得分排序的结果, 得分相同时按路径排序
+#[derive(PartialEq, Eq)]
+struct Scored {
+    score: u32,
+    path: PathBuf,
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .cmp(&other.score)
+            .then_with(|| other.path.cmp(&self.path))
+    }
+}
+
+/// 单个线程的部分结果, 只保留得分最高的 k 个
+pub struct TopK {
+    limit: usize,
+    heap: BinaryHeap<Reverse<Scored>>,
+}
+
+impl TopK {
+    pub fn new(limit: usize) -> TopK {
+        TopK {
+            limit,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, score: u32, path: PathBuf) {
+        self.heap.push(Reverse(Scored { score, path }));
+        if self.limit > 0 && self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+    }
+
+    /// 合并两个线程的部分结果
+    pub fn merge(mut self, other: TopK) -> TopK {
+        if self.heap.len() < other.heap.len() {
+            return other.merge(self);
+        }
+        for Reverse(scored) in other.heap {
+            self.push(scored.score, scored.path);
+        }
+        self
+    }
+
+    /// 按得分从高到低排列的结果
+    pub fn into_sorted_vec(self) -> Vec<PathBuf> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(scored)| scored.path)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k_merge() {
+        let mut left = TopK::new(3);
+        let mut right = TopK::new(3);
+        for (score, name) in [(1, "a"), (5, "b"), (3, "c")] {
+            left.push(score, PathBuf::from(name));
+        }
+        for (score, name) in [(4, "d"), (0, "e"), (5, "f")] {
+            right.push(score, PathBuf::from(name));
+        }
+        assert_eq!(
+            left.merge(right).into_sorted_vec(),
+            vec![PathBuf::from("b"), PathBuf::from("f"), PathBuf::from("d")]
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let opts = SearchOptions::with_limit(2);
+        let budget = SearchBudget::new(&opts);
+        assert!(budget.record());
+        assert!(!budget.should_stop());
+        assert!(budget.record());
+        assert!(budget.should_stop());
+        assert!(!budget.record());
+        // 达到上限不算提前结束
+        assert!(!opts.is_partial());
+
+        let opts = SearchOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert!(SearchBudget::new(&opts).should_stop());
+        assert!(opts.is_partial());
+
+        let opts = SearchOptions::default();
+        let budget = SearchBudget::new(&opts);
+        assert!(!budget.should_stop());
+        opts.cancel.clone().cancel();
+        assert!(budget.should_stop());
+        assert!(opts.is_partial());
+
+        let opts = SearchOptions {
+            io_budget: 2,
+            ..Default::default()
+        };
+        let budget = SearchBudget::new(&opts);
+        assert!(budget.consume_io());
+        assert!(budget.consume_io());
+        assert!(!opts.is_partial());
+        assert!(!budget.consume_io());
+        assert!(budget.should_stop());
+        assert!(opts.is_partial());
+    }
+}
@@ -0,0 +1,299 @@
+//! 缓存快照
+//!
+//! 将 `TrieCache` 序列化到磁盘, 启动时直接加载快照而不是从数据库重建整棵树,
+//! 快照中记录是否仍然存在的校验放到后台线程中进行, 不阻塞第一次搜索。
+//!
+//! 文件格式: `| magic: 8 bytes | version: u32 (LE) | payload: bincode(Snapshot) |`
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::CONF,
+    db::Database,
+    util::{errors::CustomError, is_blacklisted, is_excluded},
+};
+
+use super::{
+    cache::init_trie,
+    hot_dir::{accumulate, HOTDIR},
+    reconcile::mark_missing,
+    trie::TrieCache,
+    CACHER,
+};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"ELFSNAP\0";
+/// 快照格式版本, `TrieCache` 或 `EntryMeta` 的结构变化时需要递增
+const SNAPSHOT_VERSION: u32 = 3;
+
+/// 写入时借用缓存, 不需要克隆整棵树
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    created: SystemTime,
+    tree: &'a TrieCache,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    created: SystemTime,
+    tree: TrieCache,
+}
+
+/// 将当前缓存保存为快照
+pub fn save_snapshot(path: &Path) -> Result<(), CustomError> {
+    // 只在序列化期间持有读锁, 不阻塞搜索; 写盘在锁外进行
+    let payload = {
+        let guard = CACHER.read().unwrap();
+        bincode::serialize(&SnapshotRef {
+            created: SystemTime::now(),
+            tree: &guard.tree,
+        })?
+    };
+    write_snapshot(path, &payload)
+}
+
+/// 写入快照文件, 先写入临时文件再原子替换
+fn write_snapshot(path: &Path, payload: &[u8]) -> Result<(), CustomError> {
+    let tmp_path = path.with_extension("snapshot.tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(payload)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    debug!("cache snapshot saved to {:#?}", path);
+    Ok(())
+}
+
+/// 按配置保存快照, 用于退出前调用
+pub fn save_cache_snapshot() {
+    if let Some(path) = CONF.database.snapshot_path() {
+        match save_snapshot(&path) {
+            Ok(_) => info!("cache snapshot saved to {:#?}", path),
+            Err(e) => error!("save cache snapshot error: {}", e),
+        }
+    }
+}
+
+/// 从快照恢复缓存, 快照不存在或版本不匹配时返回 false
+pub fn load_snapshot(path: &Path) -> Result<bool, CustomError> {
+    let snapshot = match read_snapshot(path)? {
+        Some(snapshot) => snapshot,
+        None => return Ok(false),
+    };
+
+    let mut cache_guard = CACHER.write().unwrap();
+    cache_guard.tree = snapshot.tree;
+    cache_guard.enforce_capacity(None);
+    drop(cache_guard);
+
+    info!(
+        "cache snapshot loaded from {:#?}, created at {}",
+        path,
+        humantime::format_rfc3339_seconds(snapshot.created)
+    );
+    Ok(true)
+}
+
+/// 读取快照文件, 快照不存在或版本不匹配时返回 None
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>, CustomError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    reader.read_exact(&mut version)?;
+    if &magic != SNAPSHOT_MAGIC || u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+        info!("cache snapshot {:#?} has an unsupported version", path);
+        return Ok(None);
+    }
+
+    let mut snapshot: Snapshot = bincode::deserialize_from(reader)?;
+    // 索引不写入快照, 加载后重建
+    snapshot.tree.rebuild_indexes();
+    Ok(Some(snapshot))
+}
+
+/// 优先从快照恢复缓存并在后台校验, 没有可用快照时从数据库重建
+pub fn load_or_init_trie(db: Arc<dyn Database>, path: &Path) {
+    match load_snapshot(path) {
+        Ok(true) => {
+            thread::spawn(move || validate_trie(db));
+        }
+        Ok(false) => init_trie(db),
+        Err(e) => {
+            error!("load cache snapshot error: {}, rebuild from DB", e);
+            init_trie(db);
+        }
+    }
+}
+
+/// 校验从快照恢复的缓存
+///
+/// 1. 清理处于黑名单中的记录, 不存在的记录标记为不可用, 与 `init_trie` 的行为一致
+/// 2. 补充快照之后才写入数据库的记录
+/// 3. 用数据库中的访问次数补充热点文件夹, 清理已不存在的热点文件夹
+///
+/// 每条记录单独加锁, 校验过程中搜索不会被长时间阻塞
+pub fn validate_trie(db: Arc<dyn Database>) {
+    let records = db.find_all();
+    let unavailable: HashSet<PathBuf> = match db.find_unavailable() {
+        Ok(unavailable) => unavailable.into_iter().map(|(path, _)| path).collect(),
+        Err(e) => {
+            error!("find unavailable records error: {}", e);
+            HashSet::new()
+        }
+    };
+    info!("validating cache snapshot with {} records", records.len());
+    let mut hot_scores = HashMap::new();
+
+    for (_, meta) in records {
+        let path = meta.path.clone();
+        if is_blacklisted(&path) {
+            debug!("path in blacklist: {:#?}, marked as deleted", &path);
+            CACHER.write().unwrap().remove_path(&path);
+            if let Err(e) = db.delete_by_path(&path) {
+                debug!("delete path error: {}", e);
+            }
+            continue;
+        }
+        if !path.exists() {
+            mark_missing(&db, &path);
+            continue;
+        }
+        if unavailable.contains(&path) {
+            if let Err(e) = db.mark_available(&path) {
+                debug!("mark path available error: {}", e);
+            }
+        }
+        accumulate(&mut hot_scores, &path, meta.entry_type, meta.access_count);
+
+        // 绝大多数记录已经在快照中, 先用读锁检查, 只有缺失时才获取写锁
+        if CACHER.read().unwrap().contains_path(&path) {
+            continue;
+        }
+        let mut cache_guard = CACHER.write().unwrap();
+        if let Err(e) = cache_guard.add_path(&path, Some(meta), false) {
+            error!("validate trie error: {}", e);
+        }
+        drop(cache_guard);
+    }
+
+    match HOTDIR.write() {
+        Ok(mut hd_guard) => {
+            hd_guard.seed(hot_scores, SystemTime::now());
+            hd_guard.retain(|dir| !is_excluded(&dir.to_path_buf()));
+        }
+        Err(e) => {
+            error!("lock HOTDIR failed: {}", e);
+        }
+    }
+
+    info!("cache snapshot validated");
+}
+
+/// 启动后台线程, 定期保存快照
+pub fn spawn_snapshot_saver(path: PathBuf, interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = save_snapshot(&path) {
+            error!("save cache snapshot error: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::trie::TrieCache,
+        db::{DbReader, DbWriter, EntryMeta, MemoryDatabase},
+        util::test_util::TempDir,
+    };
+
+    #[test]
+    fn test_write_and_read() {
+        let dir = TempDir::new("snapshot-write");
+        let file = dir.join("kept.txt");
+        fs::write(&file, b"kept").unwrap();
+
+        let mut cache = TrieCache::new();
+        let mut meta = EntryMeta::new(&file).unwrap();
+        meta.access_count = 7;
+        cache.insert_path(&file, Some(meta), false).unwrap();
+
+        let path = dir.join("cache.snapshot");
+        let payload = bincode::serialize(&SnapshotRef {
+            created: SystemTime::now(),
+            tree: &cache,
+        })
+        .unwrap();
+        write_snapshot(&path, &payload).unwrap();
+
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+        let mut loaded = snapshot.tree;
+        let found = loaded.search_full_path(&file, false).unwrap();
+        assert_eq!(found.access_count, 7);
+        assert_eq!(loaded.search_entry("kept.txt", false), vec![file.clone()]);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let dir = TempDir::new("snapshot-version");
+        let path = dir.join("cache.snapshot");
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        fs::write(&path, data).unwrap();
+        assert!(read_snapshot(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validate_trie() {
+        let dir = TempDir::new("snapshot-validate");
+        let kept = dir.join("kept.txt");
+        let removed = dir.join("removed.txt");
+        let added = dir.join("added.txt");
+        for path in [&kept, &removed, &added] {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let db = Arc::new(MemoryDatabase::new());
+        let mut cacher = CACHER.write().unwrap();
+        for path in [&kept, &removed] {
+            db.insert_rec(path, &EntryMeta::new(path).unwrap()).unwrap();
+            cacher.add_path(path, None, false).unwrap();
+        }
+        drop(cacher);
+
+        // 模拟快照之后发生的变化: 文件被删除, 数据库中新增了记录
+        fs::remove_file(&removed).unwrap();
+        db.insert_rec(&added, &EntryMeta::new(&added).unwrap())
+            .unwrap();
+
+        validate_trie(db.clone());
+
+        let cacher = CACHER.read().unwrap();
+        assert!(cacher.contains_path(&kept));
+        assert!(cacher.contains_path(&added));
+        assert!(!cacher.contains_path(&removed));
+        drop(cacher);
+        assert!(db.find_by_path(&removed).unwrap().is_some());
+        assert_eq!(db.find_unavailable().unwrap()[0].0, removed);
+    }
+}
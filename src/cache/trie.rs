@@ -1,225 +1,1136 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    time::SystemTime,
+};
 
-use log::trace;
+use log::{error, trace};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 
-use crate::db::meta::EntryMeta;
+use crate::db::meta::{EntryMeta, EntryType};
 
 use crate::util::errors::CustomError;
-use crate::util::{pattern_match, regex_match};
+use crate::util::pattern_match;
 
-pub struct TrieCache {
-    pub root: TrieNode,
+use super::{
+    name_index::NameIndex,
+    path_regex::{PathRegex, PathState},
+    search::{SearchBudget, SearchOptions, TopK, PARALLEL_THRESHOLD},
+    trigram::{TrigramIndex, TrigramQuery},
+};
+
+/// 节点在 `TrieCache::nodes` 中的下标
+pub type NodeId = u32;
+/// 路径分量在字符串池中的下标
+type NameId = u32;
+
+const ROOT: NodeId = 0;
+
+/// 全局递增的版本号, 替换整棵树(例如加载快照)后也不会与之前的版本号重复
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, AtomicOrdering::Relaxed)
 }
 
-impl TrieCache {
-    pub fn new() -> TrieCache {
-        TrieCache {
-            root: TrieNode::new(),
+/// 计算淘汰优先级时, 修改时间每过去这么久(秒), 访问计数的权重减半
+const FRECENCY_HALF_LIFE: f64 = 30.0 * 24.0 * 60.0 * 60.0;
+
+/// 路径分量的字符串池, 相同的名字只保存一份
+///
+/// 池中的名字不会被回收, 从快照恢复时会重新生成
+#[derive(Default, Serialize, Deserialize)]
+struct Interner {
+    names: Vec<Box<str>>,
+    /// 名字的哈希值到下标的映射, 避免再保存一份字符串
+    #[serde(skip)]
+    ids: HashMap<u64, SmallVec<[NameId; 1]>>,
+}
+
+fn hash_name(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Interner {
+    fn get(&self, name: &str) -> Option<NameId> {
+        self.ids
+            .get(&hash_name(name))?
+            .iter()
+            .copied()
+            .find(|id| &*self.names[*id as usize] == name)
+    }
+
+    fn intern(&mut self, name: &str) -> NameId {
+        if let Some(id) = self.get(name) {
+            return id;
         }
+        let id = self.names.len() as NameId;
+        self.names.push(name.into());
+        self.ids.entry(hash_name(name)).or_default().push(id);
+        id
     }
 
-    pub fn search_full_path(&mut self, path: &PathBuf, update_count: bool) -> Option<EntryMeta> {
-        let paths = path
-            .components()
-            .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        self.root.search_full_path(paths, update_count)
+    fn resolve(&self, id: NameId) -> &str {
+        &self.names[id as usize]
     }
 
-    pub fn search_entry(&self, entry: &str, is_fuzzy: bool) -> Vec<PathBuf> {
-        self.root.search_entry(entry, is_fuzzy)
+    /// 反序列化后重建哈希表
+    fn rebuild(&mut self) {
+        self.ids.clear();
+        for (id, name) in self.names.iter().enumerate() {
+            self.ids
+                .entry(hash_name(name))
+                .or_default()
+                .push(id as NameId);
+        }
     }
+}
 
-    pub fn search_path_regex(&self, pattern_path: &str) -> Vec<PathBuf> {
-        self.root.search_path_regex(pattern_path)
+/// 不含路径的元数据, 路径由父节点链接重建
+#[derive(Clone, Serialize, Deserialize)]
+struct NodeMeta {
+    size: u64,
+    modified: SystemTime,
+    access_count: u32,
+    entry_type: EntryType,
+}
+
+impl NodeMeta {
+    fn from_entry(meta: &EntryMeta) -> NodeMeta {
+        NodeMeta {
+            size: meta.size,
+            modified: meta.modified,
+            access_count: meta.access_count,
+            entry_type: meta.entry_type,
+        }
     }
 
-    pub fn insert_path(
-        &mut self,
-        path: &PathBuf,
-        meta: Option<EntryMeta>,
-        update_count: bool,
-    ) -> Result<Option<EntryMeta>, CustomError> {
-        let paths = path
-            .components()
-            .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        self.root.insert(paths, meta, update_count)
+    fn to_entry(&self, path: &Path) -> EntryMeta {
+        EntryMeta {
+            path: path.to_path_buf(),
+            size: self.size,
+            modified: self.modified,
+            access_count: self.access_count,
+            entry_type: self.entry_type,
+        }
     }
+}
 
-    pub fn contains_full_path(&mut self, path: &PathBuf, update_count: bool) -> bool {
-        let paths = path
-            .components()
-            .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        self.root.search_full_path(paths, update_count).is_some()
+/// 综合访问频率和最近修改时间的得分, 得分最低的条目最先被淘汰
+fn frecency(meta: &NodeMeta, now: SystemTime) -> f64 {
+    let age = now
+        .duration_since(meta.modified)
+        .unwrap_or_default()
+        .as_secs_f64();
+    (meta.access_count as f64 + 1.0) * 0.5f64.powf(age / FRECENCY_HALF_LIFE)
+}
+
+/// 淘汰候选, 在 `BinaryHeap` 中得分最低的先出堆
+struct EvictCandidate {
+    score: f64,
+    id: NodeId,
+}
+
+impl PartialEq for EvictCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for EvictCandidate {}
+
+impl PartialOrd for EvictCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    pub fn delete(&mut self, path: &PathBuf) -> Result<(), CustomError> {
-        let paths = path
-            .components()
-            .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        self.root.delete(paths)
+impl Ord for EvictCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then_with(|| other.id.cmp(&self.id))
     }
 }
 
-#[derive(Clone)]
-pub struct TrieNode {
-    pub(crate) entry_name: String,
-    pub(crate) full_path: PathBuf,
-    pub(crate) meta: EntryMeta,
-    pub(crate) children: HashMap<String, Box<TrieNode>>,
+/// 获取不到文件元数据时使用的空元数据
+fn empty_meta(path: &Path) -> EntryMeta {
+    let mut meta = EntryMeta::new_empty().unwrap();
+    meta.path = path.to_path_buf();
+    meta
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrieNode {
+    parent: NodeId,
+    /// 从父节点到当前节点的路径分量, 新插入路径中尚不存在的部分会压缩到同一个节点中
+    label: SmallVec<[NameId; 2]>,
+    /// 按第一个路径分量排序, 查找子节点时二分
+    children: SmallVec<[NodeId; 2]>,
+    /// label 最后一个分量的元数据, 只有显式插入的路径才有;
+    /// 压缩在 label 中的中间文件夹没有元数据, 需要时再临时获取
+    meta: Option<NodeMeta>,
 }
 
 impl TrieNode {
-    fn new() -> TrieNode {
+    fn root() -> TrieNode {
         TrieNode {
-            entry_name: String::new(),
-            full_path: PathBuf::new(),
-            children: HashMap::new(),
-            meta: EntryMeta::new(&PathBuf::new()).unwrap(),
+            parent: ROOT,
+            label: SmallVec::new(),
+            children: SmallVec::new(),
+            meta: None,
         }
     }
+}
+
+/// 子树的内存占用估算
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct MemoryReport {
+    /// 压缩后的节点数
+    pub nodes: usize,
+    /// 路径数, 每个路径分量对应一个路径
+    pub entries: usize,
+    /// 当前布局估算的字节数, 共享的名字按每次出现计入
+    pub bytes: usize,
+    /// 按旧布局估算的字节数: 每个路径一个节点, 保存文件名、完整路径、带路径的元数据和 HashMap 子节点
+    pub legacy_bytes: usize,
+}
+
+/// 以 arena 保存的压缩 trie 树
+#[derive(Serialize, Deserialize)]
+pub struct TrieCache {
+    names: Interner,
+    /// 下标即 `NodeId`, 被删除的节点为 None, 下标放入 `free` 中复用
+    nodes: Vec<Option<TrieNode>>,
+    free: Vec<NodeId>,
+    /// 文件名倒排索引, 随 `insert_path` 和 `delete` 一起维护
+    #[serde(skip)]
+    name_index: NameIndex<NodeId>,
+    /// 节点末尾路径的三元组索引, 用于加速子串和正则查询;
+    /// 末尾路径包含了 label 中所有分量的路径, 因此候选集合不会遗漏
+    #[serde(skip)]
+    paths: TrigramIndex<NodeId>,
+    /// 有元数据的节点数, 即显式插入的条目数
+    #[serde(skip)]
+    entries: usize,
+    /// 版本号, 每次修改都会更新, 用于判断查询缓存是否失效
+    #[serde(skip)]
+    generation: u64,
+}
+
+impl Default for TrieCache {
+    fn default() -> Self {
+        TrieCache::new()
+    }
+}
 
-    fn new_with_path(path: &PathBuf) -> Result<TrieNode, CustomError> {
-        // 尝试获取文件名
-        let file_name = path.file_name();
-        let entry_name = match file_name {
-            Some(name) => name.to_str().unwrap().to_string(),
-            None => "/".to_string(), // 如果没有文件名，默认为 "/"
+impl TrieCache {
+    pub fn new() -> TrieCache {
+        TrieCache {
+            names: Interner::default(),
+            nodes: vec![Some(TrieNode::root())],
+            free: Vec::new(),
+            name_index: NameIndex::new(),
+            paths: TrigramIndex::new(),
+            entries: 0,
+            generation: next_generation(),
         }
-        .to_string();
+    }
 
-        let meta = EntryMeta::new(path)?;
+    /// 当前版本号
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
 
-        Ok(TrieNode {
-            entry_name,
-            full_path: meta.path.clone(),
-            children: std::collections::HashMap::new(),
-            meta,
-        })
+    /// 缓存被修改, 更新版本号
+    fn touch(&mut self) {
+        self.generation = next_generation();
     }
 
-    pub fn contains_full_path(&mut self, path: &PathBuf, update_count: bool) -> bool {
-        let paths = path
-            .components()
+    fn node(&self, id: NodeId) -> &TrieNode {
+        self.nodes[id as usize].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut TrieNode {
+        self.nodes[id as usize].as_mut().unwrap()
+    }
+
+    fn alloc(&mut self, node: TrieNode) -> NodeId {
+        match self.free.pop() {
+            Some(id) => {
+                self.nodes[id as usize] = Some(node);
+                id
+            }
+            None => {
+                self.nodes.push(Some(node));
+                (self.nodes.len() - 1) as NodeId
+            }
+        }
+    }
+
+    /// 除根节点外的所有节点
+    fn live_nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, node)| node.is_some())
+            .map(|(id, _)| id as NodeId)
+    }
+
+    fn components(path: &Path) -> Vec<&str> {
+        path.components()
             .map(|elem| elem.as_os_str().to_str().unwrap())
-            .collect();
-        self.search_full_path(paths, update_count).is_some()
+            .collect()
     }
 
-    /// 根据文件名或文件夹名查找所有匹配的路径
-    pub fn search_entry(&self, entry_name: &str, is_fuzzy: bool) -> Vec<PathBuf> {
-        let mut results = Vec::new();
+    /// 在 `id` 的子节点中查找第一个路径分量为 `name` 的节点
+    fn find_child(&self, id: NodeId, name: NameId) -> Result<usize, usize> {
+        self.node(id)
+            .children
+            .binary_search_by_key(&name, |child| self.node(*child).label[0])
+    }
 
-        // 如果当前节点的名称与目标名称相匹配，将当前节点的路径加入结果
+    /// 定位路径, 返回所在的节点及其在 label 中的下标
+    fn locate(&self, path: &Path) -> Option<(NodeId, usize)> {
+        let comps = Self::components(path);
+        if comps.is_empty() {
+            return None;
+        }
 
-        if !self.entry_name.is_empty() && pattern_match(&self.entry_name, entry_name, is_fuzzy) {
-            results.push(self.full_path.clone());
+        let mut id = ROOT;
+        let mut i = 0;
+        loop {
+            let name = self.names.get(comps[i])?;
+            let child = self.node(id).children[self.find_child(id, name).ok()?];
+            let label = &self.node(child).label;
+            for (j, name) in label.iter().enumerate() {
+                if i == comps.len() {
+                    return Some((child, j - 1));
+                }
+                if self.names.get(comps[i]) != Some(*name) {
+                    return None;
+                }
+                i += 1;
+            }
+            if i == comps.len() {
+                return Some((child, label.len() - 1));
+            }
+            id = child;
         }
+    }
 
-        // 对于每个子节点，递归搜索并合并结果
-        for (_, child) in &self.children {
-            results.extend(child.search_entry(entry_name, is_fuzzy));
+    /// 由父节点链接重建 `id` 的 label 中第 `offset` 个分量的完整路径
+    fn path_of(&self, id: NodeId, offset: usize) -> PathBuf {
+        let mut ancestors = Vec::new();
+        let mut cur = self.node(id).parent;
+        while cur != ROOT {
+            ancestors.push(cur);
+            cur = self.node(cur).parent;
         }
 
-        results
+        let mut path = PathBuf::new();
+        for ancestor in ancestors.into_iter().rev() {
+            for name in &self.node(ancestor).label {
+                path.push(self.names.resolve(*name));
+            }
+        }
+        for name in &self.node(id).label[..=offset] {
+            path.push(self.names.resolve(*name));
+        }
+        path
     }
 
-    pub fn search_path_regex(&self, pattern_path: &str) -> Vec<PathBuf> {
-        let mut results = Vec::new();
+    /// 节点 label 最后一个分量的完整路径
+    fn end_path(&self, id: NodeId) -> PathBuf {
+        if id == ROOT {
+            return PathBuf::new();
+        }
+        self.path_of(id, self.node(id).label.len() - 1)
+    }
+
+    /// 依次访问 `id` 的 label 中每个分量的完整路径、名字和排序得分
+    fn for_each_position(&self, id: NodeId, mut f: impl FnMut(&Path, &str, u32)) {
+        let node = self.node(id);
+        let mut path = self.end_path(node.parent);
+        for (offset, name) in node.label.iter().enumerate() {
+            let name = self.names.resolve(*name);
+            path.push(name);
+            f(&path, name, self.score_of(id, offset));
+        }
+    }
+
+    /// 搜索结果的排序得分, 即访问计数; 没有元数据的中间文件夹为 0
+    fn score_of(&self, id: NodeId, offset: usize) -> u32 {
+        let node = self.node(id);
+        match &node.meta {
+            Some(meta) if offset + 1 == node.label.len() => meta.access_count,
+            _ => 0,
+        }
+    }
 
-        // 如果当前节点的路径与目标路径匹配，将当前节点的路径加入结果
-        if regex_match(&self.full_path, pattern_path) {
-            results.push(self.full_path.clone());
+    /// 除根节点外的节点数
+    fn node_count(&self) -> usize {
+        self.nodes.len() - self.free.len() - 1
+    }
+
+    fn index_names(&mut self, id: NodeId) {
+        let node = self.nodes[id as usize].as_ref().unwrap();
+        for name in &node.label {
+            self.name_index.insert(self.names.resolve(*name), id);
         }
+    }
 
-        // TODO: 目前确实对正则表达式提前终止查询的优化
-        for (_, child) in &self.children {
-            results.append(&mut child.search_path_regex(pattern_path));
+    fn unindex_names(&mut self, id: NodeId) {
+        let node = self.nodes[id as usize].as_ref().unwrap();
+        for name in &node.label {
+            self.name_index.remove(self.names.resolve(*name), &id);
         }
-        results
     }
 
-    fn search_full_path(&mut self, path: Vec<&str>, update_count: bool) -> Option<EntryMeta> {
-        let mut cur_node = self;
+    fn index_node(&mut self, id: NodeId, end_path: &Path) {
+        self.index_names(id);
+        self.paths.insert(id, &end_path.to_string_lossy());
+    }
+
+    fn unindex_node(&mut self, id: NodeId) {
+        self.unindex_names(id);
+        self.paths.remove(&id);
+    }
 
-        for p in path {
-            if let Some(node) = cur_node.children.get_mut(p) {
-                cur_node = &mut **node;
+    /// 从快照反序列化后重建字符串池的哈希表、两个索引和条目计数
+    pub fn rebuild_indexes(&mut self) {
+        self.touch();
+        self.names.rebuild();
+        self.name_index.clear();
+        self.paths.clear();
+        self.entries = self
+            .nodes
+            .iter()
+            .flatten()
+            .filter(|node| node.meta.is_some())
+            .count();
+
+        let mut stack: Vec<(NodeId, PathBuf)> = self
+            .node(ROOT)
+            .children
+            .iter()
+            .map(|child| (*child, PathBuf::new()))
+            .collect();
+        while let Some((id, mut path)) = stack.pop() {
+            for name in &self.node(id).label {
+                path.push(self.names.resolve(*name));
+            }
+            self.index_node(id, &path);
+            for child in &self.node(id).children {
+                stack.push((*child, path.clone()));
+            }
+        }
+    }
+
+    /// 将 `id` 的 label 从第 `k` 个分量处拆开, 前半部分成为新的父节点并返回
+    fn split(&mut self, id: NodeId, k: usize) -> NodeId {
+        // `id` 的末尾路径不变, 三元组索引无需更新, 只需要更新文件名索引
+        self.unindex_names(id);
+        let node = self.node_mut(id);
+        let label: SmallVec<[NameId; 2]> = node.label.drain(..k).collect();
+        let parent = node.parent;
+
+        let mut children = SmallVec::new();
+        children.push(id);
+        let head = self.alloc(TrieNode {
+            parent,
+            label,
+            children,
+            meta: None,
+        });
+        self.node_mut(id).parent = head;
+
+        // 前半部分的第一个分量不变, 在父节点中的位置也不变
+        let siblings = &mut self.node_mut(parent).children;
+        if let Some(pos) = siblings.iter().position(|child| *child == id) {
+            siblings[pos] = head;
+        }
+
+        self.index_names(id);
+        let head_path = self.end_path(head);
+        self.index_node(head, &head_path);
+        head
+    }
+
+    /// 保证 label 中第 `offset` 个分量位于节点末尾, 返回该节点
+    fn ensure_end(&mut self, id: NodeId, offset: usize) -> NodeId {
+        if offset + 1 == self.node(id).label.len() {
+            id
+        } else {
+            self.split(id, offset + 1)
+        }
+    }
+
+    /// 沿 `comps` 尽量向下走, 返回走到的节点以及尚未走过的第一个分量的下标;
+    /// 在 label 中间分叉或结束时先拆开, 保证返回的节点的末尾正好是走过的最后一个分量
+    fn descend(&mut self, comps: &[&str]) -> (NodeId, usize) {
+        let mut id = ROOT;
+        let mut i = 0;
+        while i < comps.len() {
+            let name = self.names.intern(comps[i]);
+            let child = match self.find_child(id, name) {
+                Ok(pos) => self.node(id).children[pos],
+                Err(_) => break,
+            };
+            let label_len = self.node(child).label.len();
+            let mut j = 1;
+            i += 1;
+            while j < label_len && i < comps.len() {
+                let name = self.names.intern(comps[i]);
+                if self.node(child).label[j] != name {
+                    break;
+                }
+                j += 1;
+                i += 1;
+            }
+            id = if j < label_len {
+                self.split(child, j)
             } else {
-                return None;
+                child
+            };
+        }
+        (id, i)
+    }
+
+    /// 将已经设置好 label 的节点挂到 `parent` 下, 按第一个分量保持子节点有序
+    fn attach(&mut self, parent: NodeId, id: NodeId) {
+        let name = self.node(id).label[0];
+        let pos = match self.find_child(parent, name) {
+            Ok(pos) | Err(pos) => pos,
+        };
+        self.node_mut(id).parent = parent;
+        self.node_mut(parent).children.insert(pos, id);
+    }
+
+    /// 创建缓存中尚不存在的路径, 尚不存在的部分压缩到一个新节点中
+    fn create(&mut self, comps: &[&str], meta: NodeMeta) -> NodeId {
+        // 路径不存在, 因此一定还有剩余的分量
+        let (parent, i) = self.descend(comps);
+        let label = comps[i..]
+            .iter()
+            .map(|comp| self.names.intern(comp))
+            .collect();
+        let new = self.alloc(TrieNode {
+            parent,
+            label,
+            children: SmallVec::new(),
+            meta: Some(meta),
+        });
+        self.attach(parent, new);
+        let path = self.end_path(new);
+        self.index_node(new, &path);
+        self.entries += 1;
+        new
+    }
+
+    pub fn search_full_path(&mut self, path: &PathBuf, update_count: bool) -> Option<EntryMeta> {
+        let (id, offset) = self.locate(path)?;
+        if update_count {
+            // 访问计数影响结果的排序
+            self.touch();
+        }
+        if offset + 1 == self.node(id).label.len() {
+            if let Some(meta) = self.node_mut(id).meta.as_mut() {
+                if update_count {
+                    meta.access_count += 1; // 访问计数加1
+                }
+                return Some(meta.to_entry(path));
             }
         }
+
+        // 隐式存在的中间文件夹没有保存元数据, 临时获取; 需要计数时才保存到缓存中
+        let meta = EntryMeta::new(path).unwrap_or_else(|_| empty_meta(path));
         if update_count {
-            cur_node.meta.access_count += 1; // 访问计数加1
+            self.insert_path(path, Some(meta), true).ok().flatten()
+        } else {
+            Some(meta)
+        }
+    }
+
+    /// 精确查询和前缀查询使用文件名索引, 只有模糊查询才遍历所有节点
+    pub fn search_entry(&self, entry: &str, is_fuzzy: bool) -> Vec<PathBuf> {
+        self.search_entry_with(entry, is_fuzzy, &SearchOptions::default())
+    }
+
+    pub fn search_entry_with(
+        &self,
+        entry: &str,
+        is_fuzzy: bool,
+        opts: &SearchOptions,
+    ) -> Vec<PathBuf> {
+        let mut ids: Vec<NodeId> = if is_fuzzy {
+            self.live_nodes().collect()
+        } else {
+            self.name_index.search(entry)
+        };
+        ids.sort_unstable();
+        ids.dedup();
+
+        self.scan_nodes(
+            &ids,
+            opts,
+            |node| {
+                node.label
+                    .iter()
+                    .any(|name| pattern_match(self.names.resolve(*name), entry, is_fuzzy))
+            },
+            |_, name| pattern_match(name, entry, is_fuzzy),
+        )
+    }
+
+    /// 先用三元组索引求出候选节点, 再用正则表达式校验;
+    /// 正则表达式中没有可用的字面量时才遍历整棵树
+    pub fn search_path_regex(&self, pattern_path: &str) -> Vec<PathBuf> {
+        self.search_path_regex_with(pattern_path, &SearchOptions::default())
+    }
+
+    pub fn search_path_regex_with(&self, pattern_path: &str, opts: &SearchOptions) -> Vec<PathBuf> {
+        // 每次查询只编译一次正则表达式
+        let mut re = match PathRegex::new(pattern_path) {
+            Ok(re) => re,
+            Err(e) => {
+                error!("{}", e);
+                return Vec::new();
+            }
+        };
+
+        match self
+            .paths
+            .candidates(&TrigramQuery::from_regex(pattern_path))
+        {
+            Some(candidates) => {
+                self.scan_nodes(&candidates, opts, |_| true, |path, _| re.is_match(path))
+            }
+            None => self.search_regex_walk(&mut re, opts),
+        }
+    }
+
+    /// 逐个检查节点 label 中的每个路径, 节点数超过阈值时多线程进行
+    ///
+    /// `prefilter` 只根据 label 排除不可能匹配的节点, 避免为它们重建路径
+    fn scan_nodes<F, M>(
+        &self,
+        ids: &[NodeId],
+        opts: &SearchOptions,
+        prefilter: F,
+        matcher: M,
+    ) -> Vec<PathBuf>
+    where
+        F: Fn(&TrieNode) -> bool + Sync,
+        M: Fn(&Path, &str) -> bool + Sync,
+    {
+        let budget = SearchBudget::new(opts);
+        let visit = |mut acc: TopK, id: &NodeId| {
+            if budget.should_stop() || !prefilter(self.node(*id)) {
+                return acc;
+            }
+            self.for_each_position(*id, |path, name, score| {
+                if matcher(path, name) {
+                    acc.push(score, path.to_path_buf());
+                }
+            });
+            acc
+        };
+
+        let res = if ids.len() > PARALLEL_THRESHOLD {
+            ids.par_iter()
+                .fold(|| TopK::new(opts.limit), visit)
+                .reduce(|| TopK::new(opts.limit), TopK::merge)
+        } else {
+            ids.iter().fold(TopK::new(opts.limit), visit)
+        };
+        res.into_sorted_vec()
+    }
+
+    /// 用 DFA 剪枝遍历整棵树, 节点数超过阈值时将子树分给多个线程
+    fn search_regex_walk(&self, re: &mut PathRegex, opts: &SearchOptions) -> Vec<PathBuf> {
+        let budget = SearchBudget::new(opts);
+        if self.node_count() <= PARALLEL_THRESHOLD {
+            let mut acc = TopK::new(opts.limit);
+            let state = re.start();
+            let mut path = String::new();
+            self.search_regex_from(ROOT, re, state, &mut path, true, &mut acc, &budget);
+            return acc.into_sorted_vec();
+        }
+
+        // 从根节点向下展开, 直到子树的数量足够分给所有线程;
+        // 被展开的节点只检查自身 label 中的路径, 不再递归
+        let want = rayon::current_num_threads() * 4;
+        let mut shallow = Vec::new();
+        let mut frontier = vec![ROOT];
+        while frontier.len() < want
+            && frontier
+                .iter()
+                .any(|id| !self.node(*id).children.is_empty())
+        {
+            let mut next = Vec::new();
+            for id in frontier {
+                let children = &self.node(id).children;
+                if children.is_empty() {
+                    next.push(id);
+                } else {
+                    shallow.push(id);
+                    next.extend(children.iter().copied());
+                }
+            }
+            frontier = next;
+        }
+        let tasks: Vec<(NodeId, bool)> = shallow
+            .into_iter()
+            .map(|id| (id, false))
+            .chain(frontier.into_iter().map(|id| (id, true)))
+            .collect();
+
+        // DFA 的状态与缓存绑定, 每个线程使用自己的副本, 从父节点的完整路径重新推进状态
+        let re = &*re;
+        tasks
+            .par_iter()
+            .map_init(
+                || re.clone(),
+                |re, &(id, recurse)| {
+                    let mut acc = TopK::new(opts.limit);
+                    if budget.should_stop() {
+                        return acc;
+                    }
+                    let mut path = if id == ROOT {
+                        String::new()
+                    } else {
+                        self.end_path(self.node(id).parent)
+                            .to_string_lossy()
+                            .into_owned()
+                    };
+                    let start = re.start();
+                    let state = re.advance(start, path.as_bytes());
+                    if !state.is_dead() {
+                        self.search_regex_from(
+                            id, re, state, &mut path, recurse, &mut acc, &budget,
+                        );
+                    }
+                    acc
+                },
+            )
+            .reduce(|| TopK::new(opts.limit), TopK::merge)
+            .into_sorted_vec()
+    }
+
+    /// 在子树中查找匹配正则的路径, `path` 和 `state` 分别为父节点的完整路径和读入它之后的 DFA 状态
+    #[allow(clippy::too_many_arguments)]
+    fn search_regex_from(
+        &self,
+        id: NodeId,
+        re: &mut PathRegex,
+        mut state: PathState,
+        path: &mut String,
+        recurse: bool,
+        acc: &mut TopK,
+        budget: &SearchBudget,
+    ) {
+        if budget.should_stop() {
+            return;
+        }
+        let saved = path.clone();
+        let node = self.node(id);
+        for (offset, name) in node.label.iter().enumerate() {
+            let next = Path::new(path.as_str()).join(self.names.resolve(*name));
+            let next = next.to_string_lossy();
+            // 子路径通常以父路径为前缀, 只需读入多出来的部分
+            state = match next.strip_prefix(path.as_str()) {
+                Some(suffix) => re.advance(state, suffix.as_bytes()),
+                None => {
+                    let start = re.start();
+                    re.advance(start, next.as_bytes())
+                }
+            };
+            *path = next.into_owned();
+
+            if re.is_match(Path::new(path.as_str())) {
+                acc.push(self.score_of(id, offset), PathBuf::from(path.as_str()));
+            }
+            // 以当前路径为前缀的更长路径都不可能匹配, 跳过整棵子树
+            if state.is_dead() {
+                *path = saved;
+                return;
+            }
+        }
+
+        if recurse {
+            for child in &node.children {
+                self.search_regex_from(*child, re, state, path, true, acc, budget);
+            }
         }
-        Some(cur_node.meta.clone())
+        *path = saved;
     }
 
-    // 更新 insert 方法，以便在插入时构建完整路径
-    pub fn insert(
+    /// 查找文件名中包含 `substring` 的路径, 忽略 ASCII 大小写
+    pub fn search_substring(&self, substring: &str) -> Vec<PathBuf> {
+        self.search_substring_with(substring, &SearchOptions::default())
+    }
+
+    pub fn search_substring_with(&self, substring: &str, opts: &SearchOptions) -> Vec<PathBuf> {
+        let substring = substring.to_ascii_lowercase();
+        let ids: Vec<NodeId> = match self
+            .paths
+            .candidates(&TrigramQuery::from_substring(&substring))
+        {
+            Some(candidates) => candidates,
+            None => self.live_nodes().collect(),
+        };
+
+        self.scan_nodes(
+            &ids,
+            opts,
+            |node| {
+                node.label.iter().any(|name| {
+                    self.names
+                        .resolve(*name)
+                        .to_ascii_lowercase()
+                        .contains(&substring)
+                })
+            },
+            |_, name| name.to_ascii_lowercase().contains(&substring),
+        )
+    }
+
+    /// 插入路径, 已经存在的路径保留原有的元数据
+    ///
+    /// `update_count` 只对目标路径本身计数, 不再累加到路径上的每个父文件夹;
+    /// 重复插入已经存在的条目且不计数时缓存没有变化, 不会使查询缓存失效
+    pub fn insert_path(
         &mut self,
-        path: Vec<&str>,
+        path: &PathBuf,
         meta: Option<EntryMeta>,
         update_count: bool,
     ) -> Result<Option<EntryMeta>, CustomError> {
-        let mut full_path = self.full_path.clone(); // 初始化完整路径
-        let mut cur_node = self;
-
-        for (idx, component) in path.iter().enumerate() {
-            let component_str = component.to_string();
-            let new_path = full_path.join(component_str.clone());
-            let new_node_res = TrieNode::new_with_path(&new_path);
-
-            let mut new_node = new_node_res?;
-            // 检查子节点是否存在，如果不存在则创建新的节点
-            cur_node
-                .children
-                .entry(component_str.clone())
-                .or_insert_with(|| {
-                    if idx == path.len() - 1 && meta.is_some() {
-                        new_node.meta = meta.clone().unwrap();
-                    }
-                    Box::new(new_node)
-                });
-
-            // 移动到子节点并更新完整路径
-            cur_node = &mut **cur_node.children.get_mut(&component_str).unwrap();
-            full_path.push(component);
-
-            // 更新完整路径
-            cur_node.full_path = full_path.clone();
-            if update_count {
-                // 不一定将访问计数自增, 在初始化的时候从数据库构建trie时就不需要自增访问计数
-                cur_node.meta.access_count += 1; // 访问计数加1
+        let comps = Self::components(path);
+        if comps.is_empty() {
+            return Err(CustomError::from("EmptyPath"));
+        }
+
+        let target = match self.locate(path) {
+            Some((id, offset)) => self.ensure_end(id, offset),
+            None => {
+                self.touch();
+                // 先获取元数据, 路径不存在时不修改缓存
+                let entry = match &meta {
+                    Some(meta) => meta.clone(),
+                    None => EntryMeta::new(path)?,
+                };
+                self.create(&comps, NodeMeta::from_entry(&entry))
+            }
+        };
+
+        if self.node(target).meta.is_none() {
+            // 之前作为中间文件夹隐式存在, 现在显式插入
+            let entry =
+                meta.unwrap_or_else(|| EntryMeta::new(path).unwrap_or_else(|_| empty_meta(path)));
+            self.node_mut(target).meta = Some(NodeMeta::from_entry(&entry));
+            self.entries += 1;
+            self.touch();
+        }
+        if update_count {
+            // 不一定将访问计数自增, 在初始化的时候从数据库构建trie时就不需要自增访问计数;
+            // 与路径上的每一层一起计数, 访问文件时其所在的(已缓存的)文件夹也算被访问一次
+            self.touch();
+            let mut cur = target;
+            while cur != ROOT {
+                if let Some(node_meta) = self.node_mut(cur).meta.as_mut() {
+                    node_meta.access_count += 1; // 访问计数加1
+                }
+                cur = self.node(cur).parent;
+            }
+        }
+
+        let res = self.node(target).meta.as_ref().unwrap().to_entry(path);
+        trace!("{:?}", &res);
+        Ok(Some(res))
+    }
+
+    /// 用磁盘上的最新元数据更新已缓存的条目, 保留访问计数; 条目不在缓存中时返回 None
+    pub fn refresh_meta(&mut self, path: &Path, fresh: &EntryMeta) -> Option<EntryMeta> {
+        let (id, offset) = self.locate(path)?;
+        if offset + 1 != self.node(id).label.len() {
+            return None;
+        }
+        let meta = self.node_mut(id).meta.as_mut()?;
+        meta.size = fresh.size;
+        meta.modified = fresh.modified;
+        meta.entry_type = fresh.entry_type;
+        let res = meta.to_entry(path);
+        self.touch();
+        Some(res)
+    }
+
+    pub fn contains_full_path(&mut self, path: &PathBuf, update_count: bool) -> bool {
+        if update_count {
+            self.search_full_path(path, update_count).is_some()
+        } else {
+            self.contains(path)
+        }
+    }
+
+    /// 只读查询路径是否存在, 可以在读锁下调用
+    pub fn contains(&self, path: &Path) -> bool {
+        self.locate(path).is_some()
+    }
+
+    /// 删除路径及其子树
+    pub fn delete(&mut self, path: &Path) -> Result<(), CustomError> {
+        let (id, offset) = self
+            .locate(path)
+            .ok_or_else(|| CustomError::from("PathNotFound"))?;
+        self.touch();
+        // 路径位于 label 中间时先拆开, 只删除后半部分
+        if offset > 0 {
+            self.split(id, offset);
+        }
+
+        let parent = self.node(id).parent;
+        self.node_mut(parent).children.retain(|child| *child != id);
+
+        let mut stack = vec![id];
+        while let Some(cur) = stack.pop() {
+            let node = self.remove_node(cur);
+            stack.extend(node.children);
+        }
+        self.prune_implicit(parent);
+        Ok(())
+    }
+
+    /// 将路径及其子树整体移动到 `to`, 元数据(包括访问计数)随节点移动; `to` 原有的子树被覆盖
+    pub fn move_path(&mut self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError> {
+        let to_comps = Self::components(to);
+        if to_comps.is_empty() || to.starts_with(from) || from.starts_with(to) {
+            return Err(CustomError::from("InvalidMove"));
+        }
+        if !self.contains(from) {
+            return Err(CustomError::from("PathNotFound"));
+        }
+        if self.contains(to) {
+            self.delete(to)?;
+        }
+        // 两个路径互不包含, 删除 `to` 不会改变 `from` 所在的节点
+        let (id, offset) = self.locate(from).unwrap();
+        self.touch();
+        // 路径位于 label 中间时先拆开, 只移动后半部分
+        if offset > 0 {
+            self.split(id, offset);
+        }
+
+        let parent = self.node(id).parent;
+        self.node_mut(parent).children.retain(|child| *child != id);
+        self.prune_implicit(parent);
+        let mut subtree = vec![id];
+        let mut i = 0;
+        while i < subtree.len() {
+            let cur = subtree[i];
+            self.unindex_node(cur);
+            subtree.extend(self.node(cur).children.iter().copied());
+            i += 1;
+        }
+
+        // 新的父路径中尚不存在的分量和新名字一起压缩到被移动的节点中
+        let (parent, i) = self.descend(&to_comps[..to_comps.len() - 1]);
+        let mut label: SmallVec<[NameId; 2]> = to_comps[i..]
+            .iter()
+            .map(|comp| self.names.intern(comp))
+            .collect();
+        label.extend(self.node(id).label[1..].iter().copied());
+        self.node_mut(id).label = label;
+        self.attach(parent, id);
+
+        // 父节点总是先于子节点出现, 子节点的末尾路径由父节点的末尾路径得到
+        let mut end_paths: HashMap<NodeId, PathBuf> = HashMap::new();
+        end_paths.insert(parent, self.end_path(parent));
+        for cur in subtree {
+            let mut path = end_paths[&self.node(cur).parent].clone();
+            for name in &self.node(cur).label {
+                path.push(self.names.resolve(*name));
+            }
+            self.index_node(cur, &path);
+            end_paths.insert(cur, path);
+        }
+        Ok(())
+    }
+
+    /// 从 `id` 向上移除没有元数据也没有子节点的中间文件夹节点
+    fn prune_implicit(&mut self, mut id: NodeId) {
+        while id != ROOT {
+            let node = self.node(id);
+            if node.meta.is_some() || !node.children.is_empty() {
+                break;
             }
-            trace!("{:?}", &cur_node.meta);
+            let parent = node.parent;
+            self.node_mut(parent).children.retain(|child| *child != id);
+            self.remove_node(id);
+            id = parent;
         }
+    }
 
-        Ok(Some(cur_node.meta.clone()))
+    /// 从 arena 中移除单个节点, 不处理父节点的子节点列表
+    fn remove_node(&mut self, id: NodeId) -> TrieNode {
+        self.unindex_node(id);
+        let node = self.nodes[id as usize].take().unwrap();
+        if node.meta.is_some() {
+            self.entries -= 1;
+        }
+        self.free.push(id);
+        node
     }
 
-    pub fn delete(&mut self, path: Vec<&str>) -> Result<(), CustomError> {
-        let mut cur_node = self;
+    /// `root` 及其子树中所有显式插入的条目
+    pub fn entries_under(&self, root: &Path) -> Vec<EntryMeta> {
+        let mut res = Vec::new();
+        let (id, _) = match self.locate(root) {
+            Some(located) => located,
+            None => return res,
+        };
+        // `root` 位于 label 中间时, 节点末端的路径仍在子树中
+        let mut stack = vec![id];
+        while let Some(cur) = stack.pop() {
+            let node = self.node(cur);
+            if let Some(meta) = &node.meta {
+                res.push(meta.to_entry(&self.end_path(cur)));
+            }
+            stack.extend(node.children.iter().copied());
+        }
+        res
+    }
 
-        for (index, component) in path.iter().enumerate() {
-            let component_str = component.to_string();
-            let is_last = index == path.len() - 1;
+    /// 显式插入的条目数
+    pub fn len(&self) -> usize {
+        self.entries
+    }
 
-            if cur_node.children.contains_key(&component_str) {
-                if is_last {
-                    cur_node.children.remove(&component_str);
-                    return Ok(());
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// 淘汰得分最低的叶子条目, 直到条目数不超过 `target`, 返回淘汰的条目数
+    ///
+    /// 只淘汰没有子节点的条目, 父节点在子节点全部淘汰后才成为候选;
+    /// `keep` 为刚插入的路径, 不参与淘汰
+    pub fn evict(&mut self, target: usize, keep: Option<&Path>) -> usize {
+        if self.entries <= target {
+            return 0;
+        }
+        self.touch();
+        let keep = keep.and_then(|path| self.locate(path)).map(|(id, _)| id);
+        let now = SystemTime::now();
+        let mut heap: BinaryHeap<EvictCandidate> = self
+            .live_nodes()
+            .filter_map(|id| {
+                let node = self.node(id);
+                match &node.meta {
+                    Some(meta) if node.children.is_empty() && Some(id) != keep => {
+                        Some(EvictCandidate {
+                            score: frecency(meta, now),
+                            id,
+                        })
+                    }
+                    _ => None,
                 }
-                cur_node = &mut **cur_node.children.get_mut(&component_str).unwrap();
-            } else {
-                return Err(CustomError::from("PathNotFound"));
+            })
+            .collect();
+
+        let mut evicted = 0;
+        while self.entries > target {
+            let id = match heap.pop() {
+                Some(candidate) => candidate.id,
+                None => break,
+            };
+            let mut parent = self.node(id).parent;
+            self.node_mut(parent).children.retain(|child| *child != id);
+            self.remove_node(id);
+            evicted += 1;
+
+            // 没有元数据的父节点只是路径的一部分, 失去所有子节点后一并移除
+            while parent != ROOT && self.node(parent).children.is_empty() {
+                let node = self.node(parent);
+                if let Some(meta) = &node.meta {
+                    if Some(parent) != keep {
+                        heap.push(EvictCandidate {
+                            score: frecency(meta, now),
+                            id: parent,
+                        });
+                    }
+                    break;
+                }
+                let grandparent = node.parent;
+                self.node_mut(grandparent)
+                    .children
+                    .retain(|child| *child != parent);
+                self.remove_node(parent);
+                parent = grandparent;
             }
         }
+        evicted
+    }
 
-        Ok(())
+    /// 估算以 `path` 为根的子树占用的内存, `path` 为空时统计整棵树
+    pub fn memory_usage(&self, path: &Path) -> Option<MemoryReport> {
+        let (id, offset, path_len) = if path.as_os_str().is_empty() {
+            (ROOT, 0, 0)
+        } else {
+            let (id, offset) = self.locate(path)?;
+            let parent_len = if offset == 0 {
+                self.end_path(self.node(id).parent).as_os_str().len()
+            } else {
+                self.path_of(id, offset - 1).as_os_str().len()
+            };
+            (id, offset, parent_len)
+        };
+
+        // 旧布局中每个路径固定占用: 文件名 String + 完整路径 PathBuf + EntryMeta
+        // + 子节点 HashMap + 在父节点 HashMap 中的键值对和控制字节
+        let legacy_node = size_of::<String>() * 2
+            + size_of::<PathBuf>()
+            + size_of::<EntryMeta>()
+            + size_of::<HashMap<String, Box<u8>>>()
+            + size_of::<Box<u8>>()
+            + 1;
+
+        let mut report = MemoryReport::default();
+        let mut stack = vec![(id, offset, path_len)];
+        while let Some((cur, start, mut path_len)) = stack.pop() {
+            let node = self.node(cur);
+            report.nodes += 1;
+            report.bytes += size_of::<Option<TrieNode>>();
+            if node.label.spilled() {
+                report.bytes += node.label.capacity() * size_of::<NameId>();
+            }
+            if node.children.spilled() {
+                report.bytes += node.children.capacity() * size_of::<NodeId>();
+            }
+
+            for name in &node.label[start..] {
+                let name = self.names.resolve(*name);
+                // 分隔符按一个字节估算
+                path_len += name.len() + 1;
+                report.entries += 1;
+                report.bytes += size_of::<Box<str>>() + name.len();
+                // 文件名和 HashMap 的键各一份, 完整路径和元数据中的路径各一份
+                report.legacy_bytes += legacy_node + name.len() * 2 + path_len * 2;
+            }
+            for child in &node.children {
+                stack.push((*child, 0, path_len));
+            }
+        }
+        Some(report)
     }
 }
 
@@ -305,4 +1216,291 @@ mod tests {
         results.sort();
         assert_eq!(results, Vec::<PathBuf>::new());
     }
+
+    #[test]
+    fn test_indexes() {
+        use super::*;
+        use crate::util::test_util::TempDir;
+        use std::fs;
+
+        let dir = TempDir::new("name-index");
+        let sub = dir.join("Reports");
+        fs::create_dir_all(&sub).unwrap();
+        let file1 = sub.join("report.pdf");
+        let file2 = dir.join("report.md");
+        fs::write(&file1, b"pdf").unwrap();
+        fs::write(&file2, b"md").unwrap();
+
+        let mut cache = TrieCache::new();
+        cache.insert_path(&file1, None, false).unwrap();
+        cache.insert_path(&file2, None, false).unwrap();
+
+        // 中间节点同样可以被搜索到, 且不区分大小写
+        let mut results = cache.search_entry("REPORT", false);
+        results.sort();
+        assert_eq!(results, vec![sub.clone(), file1.clone(), file2.clone()]);
+
+        // 删除文件夹时, 其子树中的节点一并从索引中移除
+        cache.delete(&sub).unwrap();
+        assert_eq!(cache.search_entry("report", false), vec![file2.clone()]);
+
+        // 正则查询和子串查询走三元组索引, 删除的子树不再出现
+        assert_eq!(
+            cache.search_path_regex(r"report\.(pdf|md)$"),
+            vec![file2.clone()]
+        );
+        assert_eq!(cache.search_substring("PORT.m"), vec![file2.clone()]);
+
+        // 从快照恢复后重建索引
+        let mut rebuilt: TrieCache =
+            bincode::deserialize(&bincode::serialize(&cache).unwrap()).unwrap();
+        rebuilt.rebuild_indexes();
+        assert_eq!(
+            rebuilt.search_entry("report.md", false),
+            vec![file2.clone()]
+        );
+        assert_eq!(rebuilt.search_path_regex(r"\.md$"), vec![file2.clone()]);
+
+        // 忽略大小写的正则表达式没有可用的字面量, 遍历 trie 树并按路径前缀剪枝
+        let pattern = format!("(?i)^{}/.*\\.MD$", regex::escape(&dir.to_string_lossy()));
+        assert_eq!(rebuilt.search_path_regex(&pattern), vec![file2]);
+    }
+
+    #[test]
+    fn test_radix_compression() {
+        use super::*;
+        use crate::db::meta::EntryMeta;
+
+        let meta = EntryMeta::new_empty().unwrap();
+        let file1 = PathBuf::from("/compact/a/b/c/file1.txt");
+        let file2 = PathBuf::from("/compact/a/b/x/file2.txt");
+
+        let mut cache = TrieCache::new();
+        cache
+            .insert_path(&file1, Some(meta.clone()), false)
+            .unwrap();
+        // 整条路径压缩在一个节点中
+        assert_eq!(cache.live_nodes().count(), 1);
+        assert!(cache.contains(Path::new("/compact/a/b")));
+
+        // 在 `b` 处分叉, 拆成公共前缀和两个分支
+        cache.insert_path(&file2, Some(meta.clone()), true).unwrap();
+        assert_eq!(cache.live_nodes().count(), 3);
+        assert_eq!(
+            cache.search_full_path(&file2, false).unwrap().access_count,
+            1
+        );
+        assert_eq!(
+            cache.search_entry("b", false),
+            vec![PathBuf::from("/compact/a/b")]
+        );
+
+        // 显式插入压缩在 label 中间的文件夹
+        let dir = PathBuf::from("/compact/a");
+        cache.insert_path(&dir, Some(meta.clone()), true).unwrap();
+        assert_eq!(cache.search_full_path(&dir, false).unwrap().access_count, 1);
+        assert!(cache.contains(&file1));
+
+        // 删除 label 中间的路径只删除后半部分
+        cache.delete(&PathBuf::from("/compact/a/b/c")).unwrap();
+        assert!(!cache.contains(&file1));
+        assert!(cache.contains(&file2));
+        assert!(cache.search_entry("file1.txt", false).is_empty());
+
+        let report = cache.memory_usage(Path::new("")).unwrap();
+        assert_eq!(report.entries, 6);
+        assert_eq!(report.nodes, 4);
+        assert!(report.bytes < report.legacy_bytes);
+        let report = cache.memory_usage(Path::new("/compact/a/b/x")).unwrap();
+        assert_eq!(report.entries, 2);
+        assert!(cache.memory_usage(Path::new("/missing")).is_none());
+    }
+
+    #[test]
+    fn test_access_count_ancestors() {
+        use super::*;
+        use crate::db::meta::EntryMeta;
+
+        let meta = EntryMeta::new_empty().unwrap();
+        let dir = PathBuf::from("/count/dir");
+        let file = PathBuf::from("/count/dir/sub/file.txt");
+
+        let mut cache = TrieCache::new();
+        cache.insert_path(&dir, Some(meta.clone()), false).unwrap();
+        cache.insert_path(&file, Some(meta.clone()), true).unwrap();
+        cache.insert_path(&file, None, true).unwrap();
+
+        // 访问文件时已缓存的上层文件夹一起计数, 隐式存在的中间文件夹没有计数
+        assert_eq!(
+            cache.search_full_path(&file, false).unwrap().access_count,
+            2
+        );
+        assert_eq!(cache.search_full_path(&dir, false).unwrap().access_count, 2);
+        let sub = PathBuf::from("/count/dir/sub");
+        assert_eq!(cache.search_full_path(&sub, false).unwrap().access_count, 0);
+
+        // 不计数的插入不影响上层
+        cache
+            .insert_path(&PathBuf::from("/count/dir/other.txt"), Some(meta), false)
+            .unwrap();
+        assert_eq!(cache.search_full_path(&dir, false).unwrap().access_count, 2);
+    }
+
+    #[test]
+    fn test_move_path() {
+        use super::*;
+        use crate::db::meta::EntryMeta;
+
+        let mut meta = EntryMeta::new_empty().unwrap();
+        meta.access_count = 4;
+        let file1 = PathBuf::from("/move/src/dir/sub/file1.txt");
+        let file2 = PathBuf::from("/move/src/dir/file2.txt");
+        let sibling = PathBuf::from("/move/src/dir2/file3.txt");
+        let mut cache = TrieCache::new();
+        for path in [&file1, &file2, &sibling] {
+            cache.insert_path(path, Some(meta.clone()), false).unwrap();
+        }
+        let dir = PathBuf::from("/move/src/dir");
+        cache.insert_path(&dir, Some(meta.clone()), true).unwrap();
+        let generation = cache.generation();
+
+        // 移动到尚不存在的父文件夹下并改名
+        let to = PathBuf::from("/move/dst/new/renamed");
+        cache.move_path(&dir, &to).unwrap();
+        assert!(cache.generation() > generation);
+        assert!(!cache.contains(&dir));
+        assert!(!cache.contains(&file1));
+        assert!(cache.contains(&sibling));
+        assert_eq!(cache.len(), 4);
+
+        let moved = to.join("sub/file1.txt");
+        assert_eq!(cache.search_full_path(&to, false).unwrap().access_count, 5);
+        assert_eq!(
+            cache.search_full_path(&moved, false).unwrap().access_count,
+            4
+        );
+        assert_eq!(cache.search_entry("renamed", false), vec![to.clone()]);
+        assert_eq!(
+            cache.search_entry("dir", false),
+            vec![PathBuf::from("/move/src/dir2")]
+        );
+        assert_eq!(cache.search_substring("ile1"), vec![moved]);
+        assert_eq!(cache.entries_under(&to).len(), 3);
+
+        // 覆盖已存在的路径, 移走后留下的空中间文件夹被清理
+        cache.move_path(&sibling, &to.join("file2.txt")).unwrap();
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains(Path::new("/move/src")));
+        assert!(cache.move_path(&to, &to.join("sub")).is_err());
+        assert!(cache.move_path(&dir, &to).is_err());
+    }
+
+    #[test]
+    fn test_evict() {
+        use super::*;
+        use crate::db::meta::EntryMeta;
+
+        let mut cache = TrieCache::new();
+        for (path, count) in [
+            ("/evict/a/x", 5),
+            ("/evict/a/y", 0),
+            ("/evict/b/z", 1),
+            ("/evict/a", 0),
+        ] {
+            let mut meta = EntryMeta::new_empty().unwrap();
+            meta.access_count = count;
+            cache
+                .insert_path(&PathBuf::from(path), Some(meta), false)
+                .unwrap();
+        }
+        assert_eq!(cache.len(), 4);
+
+        // 先淘汰得分最低的叶子, 没有元数据的 `/evict/b` 随 `/evict/b/z` 一起移除
+        assert_eq!(cache.evict(2, None), 2);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(Path::new("/evict/a/x")));
+        assert!(!cache.contains(Path::new("/evict/a/y")));
+        assert!(!cache.contains(Path::new("/evict/b")));
+        assert!(cache.search_entry("z", false).is_empty());
+        assert!(cache.search_substring("evict/b").is_empty());
+
+        // 被保留的路径和仍有子节点的文件夹不会被淘汰
+        assert_eq!(cache.evict(0, Some(Path::new("/evict/a/x"))), 0);
+        assert_eq!(cache.len(), 2);
+
+        // 子节点全部淘汰后, 父文件夹成为候选
+        assert_eq!(cache.evict(0, None), 2);
+        assert!(cache.is_empty());
+        assert!(!cache.contains(Path::new("/evict")));
+    }
+
+    #[test]
+    fn test_regex_anchored_prefix() {
+        use super::*;
+
+        let mut cache = TrieCache::new();
+        let md = PathBuf::from("/home/me/proj/a.md");
+        let txt = PathBuf::from("/home/me/proj/b.txt");
+        for path in [&md, &txt, &PathBuf::from("/tmp/x")] {
+            cache
+                .insert_path(path, Some(EntryMeta::new_empty().unwrap()), false)
+                .unwrap();
+        }
+
+        // 短的锚定正则匹配完毕后 DFA 进入死状态, 匹配的节点和子树不能被剪掉
+        for pattern in ["^/h", "^/home/me"] {
+            let res = cache.search_path_regex(pattern);
+            assert!(res.contains(&md), "{}: {:?}", pattern, res);
+            assert!(res.contains(&txt), "{}: {:?}", pattern, res);
+            assert!(!res.contains(&PathBuf::from("/tmp/x")));
+        }
+    }
+
+    #[test]
+    fn test_parallel_search() {
+        use super::*;
+        use crate::db::meta::EntryMeta;
+
+        let mut cache = TrieCache::new();
+        for i in 0..300 {
+            for j in 0..200 {
+                let mut meta = EntryMeta::new_empty().unwrap();
+                meta.access_count = j;
+                let path = PathBuf::from(format!("/par/d{}/f{}.txt", i, j));
+                cache.insert_path(&path, Some(meta), false).unwrap();
+            }
+        }
+        assert!(cache.node_count() > PARALLEL_THRESHOLD);
+
+        // 模糊查询和没有三元组可用的正则查询都会分给多个线程
+        let matches = |name: &str| pattern_match(name, "f199.txt", true) as usize;
+        let expected = (0..200)
+            .map(|j| matches(&format!("f{}.txt", j)) * 300)
+            .chain((0..300).map(|i| matches(&format!("d{}", i))))
+            .sum::<usize>()
+            + matches("par")
+            + matches("/");
+        assert_eq!(cache.search_entry("f199.txt", true).len(), expected);
+        let res = cache.search_path_regex(r"(?i)/D7/F1\d\.TXT$");
+        assert_eq!(res.len(), 10);
+        assert_eq!(res[0], PathBuf::from("/par/d7/f19.txt"));
+
+        // 只保留访问计数最高的结果, 而不是最先找到的
+        let res = cache.search_entry_with("f", false, &SearchOptions::with_limit(50));
+        assert_eq!(res.len(), 50);
+        let counts: Vec<u32> = res
+            .iter()
+            .map(|path| cache.search_full_path(path, false).unwrap().access_count)
+            .collect();
+        assert!(counts.iter().all(|count| *count == 199));
+        let res = cache.search_path_regex_with(r"/f\d+\.txt$", &SearchOptions::with_limit(3));
+        assert_eq!(res.len(), 3);
+        assert!(res.iter().all(|path| path.ends_with("f199.txt")));
+
+        // 超过截止时间后直接返回
+        let mut opts = SearchOptions::default();
+        opts.deadline = Some(std::time::Instant::now());
+        assert!(cache.search_substring_with("f1", &opts).is_empty());
+        assert!(opts.is_partial());
+    }
 }
@@ -0,0 +1,366 @@
+//! 完整路径的三元组(trigram)索引
+//!
+//! 与代码搜索引擎的做法相同: 从正则表达式或子串中提取必须出现的字面量片段,
+//! 转换为三元组查询, 先在索引上求出候选路径, 再用真正的正则表达式逐个校验。
+//! 三元组按 ASCII 小写保存, 因此同一份索引可以同时服务区分和不区分大小写的查询。
+//!
+//! 倒排表只追加, 删除时仅移除 id 到键的映射, 失效的 id 在查询时被过滤,
+//! 失效条目过多时压缩倒排表。
+
+use std::{collections::HashMap, hash::Hash};
+
+use regex_syntax::hir::{Hir, HirKind};
+
+type Trigram = [u8; 3];
+
+/// 失效条目超过该值且多于有效条目时压缩倒排表
+const COMPACT_THRESHOLD: usize = 4096;
+
+/// 三元组查询
+#[derive(Debug, PartialEq)]
+pub enum TrigramQuery {
+    /// 无法约束, 需要完整遍历
+    All,
+    /// 必须包含的字面量(已转为 ASCII 小写, 长度不小于 3)
+    Literal(Vec<u8>),
+    And(Vec<TrigramQuery>),
+    Or(Vec<TrigramQuery>),
+}
+
+impl TrigramQuery {
+    /// 从正则表达式中提取三元组查询, 无法解析时返回 `All`
+    pub fn from_regex(pattern: &str) -> TrigramQuery {
+        match regex_syntax::Parser::new().parse(pattern) {
+            Ok(hir) => Self::from_hir(&hir),
+            Err(_) => TrigramQuery::All,
+        }
+    }
+
+    /// 子串查询
+    pub fn from_substring(substring: &str) -> TrigramQuery {
+        Self::literal(substring.as_bytes())
+    }
+
+    fn literal(bytes: &[u8]) -> TrigramQuery {
+        if bytes.len() < 3 {
+            TrigramQuery::All
+        } else {
+            TrigramQuery::Literal(bytes.to_ascii_lowercase())
+        }
+    }
+
+    fn from_hir(hir: &Hir) -> TrigramQuery {
+        match hir.kind() {
+            HirKind::Literal(lit) => Self::literal(&lit.0),
+            HirKind::Capture(cap) => Self::from_hir(&cap.sub),
+            // 至少出现一次时, 子表达式中的字面量仍然是必须的
+            HirKind::Repetition(rep) if rep.min > 0 => Self::from_hir(&rep.sub),
+            HirKind::Concat(subs) => {
+                let subs: Vec<TrigramQuery> = subs
+                    .iter()
+                    .map(Self::from_hir)
+                    .filter(|q| *q != TrigramQuery::All)
+                    .collect();
+                match subs.len() {
+                    0 => TrigramQuery::All,
+                    1 => subs.into_iter().next().unwrap(),
+                    _ => TrigramQuery::And(subs),
+                }
+            }
+            HirKind::Alternation(subs) => {
+                let subs: Vec<TrigramQuery> = subs.iter().map(Self::from_hir).collect();
+                // 任一分支不受约束, 整个分支集合就不受约束
+                if subs.contains(&TrigramQuery::All) {
+                    TrigramQuery::All
+                } else {
+                    TrigramQuery::Or(subs)
+                }
+            }
+            _ => TrigramQuery::All,
+        }
+    }
+}
+
+/// 字符串中所有去重后的三元组(ASCII 小写)
+fn trigrams(bytes: &[u8]) -> Vec<Trigram> {
+    let bytes = bytes.to_ascii_lowercase();
+    let mut res: Vec<Trigram> = bytes.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+    res.sort_unstable();
+    res.dedup();
+    res
+}
+
+/// 两个有序 id 列表的交集
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            i += 1;
+        } else if a[i] > b[j] {
+            j += 1;
+        } else {
+            res.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+    res
+}
+
+/// 两个有序 id 列表的并集
+fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            res.push(a[i]);
+            i += 1;
+        } else if a[i] > b[j] {
+            res.push(b[j]);
+            j += 1;
+        } else {
+            res.push(a[i]);
+            i += 1;
+            j += 1;
+        }
+    }
+    res.extend_from_slice(&a[i..]);
+    res.extend_from_slice(&b[j..]);
+    res
+}
+
+/// 以 `K` 为键的三元组索引, 每个键对应一段文本
+pub struct TrigramIndex<K> {
+    ids: HashMap<K, u32>,
+    /// 下标为 id, 失效的 id 为 None
+    keys: Vec<Option<K>>,
+    /// 倒排表, id 单调递增分配, 因此每个列表天然有序
+    postings: HashMap<Trigram, Vec<u32>>,
+    /// 倒排表中已失效的 id 数
+    stale: usize,
+}
+
+impl<K> Default for TrigramIndex<K> {
+    fn default() -> Self {
+        TrigramIndex {
+            ids: HashMap::new(),
+            keys: Vec::new(),
+            postings: HashMap::new(),
+            stale: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> TrigramIndex<K> {
+    pub fn new() -> Self {
+        TrigramIndex::default()
+    }
+
+    /// 索引 `key` 对应的文本, 同一个键只索引一次
+    pub fn insert(&mut self, key: K, text: &str) {
+        if self.ids.contains_key(&key) {
+            return;
+        }
+        if self.keys.len() >= u32::MAX as usize {
+            self.compact();
+        }
+
+        let id = self.keys.len() as u32;
+        for trigram in trigrams(text.as_bytes()) {
+            self.postings.entry(trigram).or_default().push(id);
+        }
+        self.ids.insert(key.clone(), id);
+        self.keys.push(Some(key));
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(id) = self.ids.remove(key) {
+            self.keys[id as usize] = None;
+            self.stale += 1;
+            if self.stale > COMPACT_THRESHOLD && self.stale > self.ids.len() {
+                self.compact();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = TrigramIndex::default();
+    }
+
+    /// 索引中的键数
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// 清除失效条目并重新分配 id, 新 id 保持原有顺序, 倒排表仍然有序
+    fn compact(&mut self) {
+        let mut remap = vec![u32::MAX; self.keys.len()];
+        let mut keys = Vec::with_capacity(self.ids.len());
+        for (old, key) in std::mem::take(&mut self.keys).into_iter().enumerate() {
+            if let Some(key) = key {
+                remap[old] = keys.len() as u32;
+                self.ids.insert(key.clone(), keys.len() as u32);
+                keys.push(Some(key));
+            }
+        }
+        self.keys = keys;
+        self.postings.retain(|_, list| {
+            list.retain_mut(|id| {
+                *id = remap[*id as usize];
+                *id != u32::MAX
+            });
+            !list.is_empty()
+        });
+        self.stale = 0;
+    }
+
+    /// 求查询的候选键, 返回 None 表示无法约束, 需要完整遍历
+    pub fn candidates(&self, query: &TrigramQuery) -> Option<Vec<K>> {
+        self.eval(query).map(|ids| {
+            ids.into_iter()
+                .filter_map(|id| self.keys[id as usize].clone())
+                .collect()
+        })
+    }
+
+    fn eval(&self, query: &TrigramQuery) -> Option<Vec<u32>> {
+        match query {
+            TrigramQuery::All => None,
+            TrigramQuery::Literal(bytes) => {
+                let mut lists = Vec::new();
+                for trigram in trigrams(bytes) {
+                    match self.postings.get(&trigram) {
+                        Some(list) => lists.push(list),
+                        // 有一个三元组不存在, 就不可能有匹配
+                        None => return Some(Vec::new()),
+                    }
+                }
+                // 从最短的倒排表开始求交集
+                lists.sort_by_key(|list| list.len());
+                let mut lists = lists.into_iter();
+                let first = lists.next()?.clone();
+                Some(lists.fold(first, |acc, list| intersect(&acc, list)))
+            }
+            TrigramQuery::And(subs) => subs
+                .iter()
+                .filter_map(|q| self.eval(q))
+                .reduce(|acc, ids| intersect(&acc, &ids)),
+            TrigramQuery::Or(subs) => {
+                let mut res = Vec::new();
+                for q in subs {
+                    res = union(&res, &self.eval(q)?);
+                }
+                Some(res)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn lit(s: &str) -> TrigramQuery {
+        TrigramQuery::Literal(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_from_regex() {
+        assert_eq!(TrigramQuery::from_regex(".*"), TrigramQuery::All);
+        assert_eq!(TrigramQuery::from_regex("ab"), TrigramQuery::All);
+        assert_eq!(TrigramQuery::from_regex("Report"), lit("report"));
+        assert_eq!(
+            TrigramQuery::from_regex(r"^/home/.*\.md$"),
+            TrigramQuery::And(vec![lit("/home/"), lit(".md")])
+        );
+        assert_eq!(
+            TrigramQuery::from_regex("(foo|bar)+baz"),
+            TrigramQuery::And(vec![
+                TrigramQuery::Or(vec![lit("foo"), lit("bar")]),
+                lit("baz")
+            ])
+        );
+        // 可选的分支无法约束
+        assert_eq!(TrigramQuery::from_regex("(foo)?baz"), lit("baz"));
+        assert_eq!(TrigramQuery::from_regex("foo|.*"), TrigramQuery::All);
+        // 非法的正则表达式
+        assert_eq!(TrigramQuery::from_regex("(foo"), TrigramQuery::All);
+    }
+
+    fn index_of(paths: &[&str]) -> TrigramIndex<PathBuf> {
+        let mut index = TrigramIndex::new();
+        for path in paths {
+            index.insert(PathBuf::from(path), path);
+        }
+        index
+    }
+
+    #[test]
+    fn test_candidates() {
+        let paths = [
+            "/home/me/notes/todo.md",
+            "/home/me/proj/README.md",
+            "/home/me/proj/main.rs",
+            "/data/report.MD",
+        ];
+        let index = index_of(&paths);
+
+        let mut res = index
+            .candidates(&TrigramQuery::from_regex(r"^/home/.*\.md$"))
+            .unwrap();
+        res.sort();
+        assert_eq!(
+            res,
+            vec![
+                PathBuf::from("/home/me/notes/todo.md"),
+                PathBuf::from("/home/me/proj/README.md")
+            ]
+        );
+
+        let mut res = index
+            .candidates(&TrigramQuery::from_regex("notes|main"))
+            .unwrap();
+        res.sort();
+        assert_eq!(
+            res,
+            vec![
+                PathBuf::from("/home/me/notes/todo.md"),
+                PathBuf::from("/home/me/proj/main.rs")
+            ]
+        );
+
+        assert!(index
+            .candidates(&TrigramQuery::from_substring("missing"))
+            .unwrap()
+            .is_empty());
+        assert!(index.candidates(&TrigramQuery::from_regex(".*")).is_none());
+    }
+
+    #[test]
+    fn test_remove_and_compact() {
+        let mut index = TrigramIndex::new();
+        for i in 0..(COMPACT_THRESHOLD + 10) {
+            let path = format!("/tmp/file{}.txt", i);
+            index.insert(PathBuf::from(&path), &path);
+        }
+        index.insert(PathBuf::from("/tmp/kept.log"), "/tmp/kept.log");
+        for i in 0..(COMPACT_THRESHOLD + 10) {
+            index.remove(&PathBuf::from(format!("/tmp/file{}.txt", i)));
+        }
+
+        assert_eq!(index.len(), 1);
+        assert!(index.stale <= COMPACT_THRESHOLD);
+        assert_eq!(
+            index
+                .candidates(&TrigramQuery::from_substring("tmp"))
+                .unwrap(),
+            vec![PathBuf::from("/tmp/kept.log")]
+        );
+    }
+}
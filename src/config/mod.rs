@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::PathBuf;
@@ -39,6 +40,124 @@ pub struct DatabaseConfig {
     pub blacklist: Vec<String>, // 黑名单字段
     pub hotdirnum: usize,
     pub log_level: String,
+    /// 缓存快照的保存间隔(秒), 为 0 时不使用快照
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval: u64,
+    /// 后台检查不可用记录的间隔(秒), 为 0 时不检查
+    #[serde(default = "default_reconcile_interval")]
+    pub reconcile_interval: u64,
+    /// 不可用记录的保留天数, 超过后才从数据库中删除
+    #[serde(default = "default_unavailable_grace_days")]
+    pub unavailable_grace_days: u64,
+    /// 缓存中最多保留的条目数, 超过后将最不常用的条目移出缓存(数据库中保留), 为 0 时不限制
+    #[serde(default)]
+    pub cache_capacity: usize,
+    /// 单次搜索返回的结果数上限, 结果按访问计数从高到低排序, 为 0 时不限制
+    #[serde(default = "default_search_limit")]
+    pub search_limit: usize,
+    /// 单次搜索的超时时间(毫秒), 超时后返回已经找到的部分结果, 为 0 时不限制
+    #[serde(default = "default_search_timeout")]
+    pub search_timeout: u64,
+    /// 智能模式在热点文件夹中向下搜索的层数
+    #[serde(default = "default_smart_depth")]
+    pub smart_depth: usize,
+    /// 智能模式单次搜索最多读取的磁盘条目数, 用完后返回已经找到的部分结果, 为 0 时不限制
+    #[serde(default = "default_smart_io_budget")]
+    pub smart_io_budget: usize,
+    /// 文件事件的去抖时间(毫秒), 同一路径在这段时间内的事件合并为一个
+    #[serde(default = "default_watch_debounce")]
+    pub watch_debounce: u64,
+    /// 事件队列的容量, 队列满时丢弃事件并对所有监听目标对账
+    #[serde(default = "default_watch_queue")]
+    pub watch_queue: usize,
+    /// 轮询扫描的间隔(秒), 用于 poll 模式的目标和 inotify watch 不够用的子树, 最小为 1 秒
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// 单个监听目标的设置, 没有设置的目标使用默认值; 表需要放在最后才能序列化
+    #[serde(default)]
+    pub target_options: BTreeMap<String, TargetOptions>,
+}
+
+/// 监听方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// 使用系统的文件事件(inotify、FSEvents、ReadDirectoryChangesW)
+    #[default]
+    Native,
+    /// 定期扫描, 网络文件系统和 FUSE 上其他机器的修改没有文件事件
+    Poll,
+}
+
+/// 单个监听目标的设置
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TargetOptions {
+    #[serde(default)]
+    pub mode: WatchMode,
+    /// 轮询时从目标向下扫描的层数, 更深处只扫描已经索引过的文件夹
+    #[serde(default = "default_poll_depth")]
+    pub poll_depth: usize,
+    /// 是否遵循目标中的 .gitignore、.ignore 和 .elfignore
+    #[serde(default = "default_ignore_files")]
+    pub ignore_files: bool,
+}
+
+impl Default for TargetOptions {
+    fn default() -> Self {
+        TargetOptions {
+            mode: WatchMode::default(),
+            poll_depth: default_poll_depth(),
+            ignore_files: default_ignore_files(),
+        }
+    }
+}
+
+fn default_poll_depth() -> usize {
+    2
+}
+
+fn default_ignore_files() -> bool {
+    true
+}
+
+fn default_snapshot_interval() -> u64 {
+    300
+}
+
+fn default_reconcile_interval() -> u64 {
+    600
+}
+
+fn default_unavailable_grace_days() -> u64 {
+    30
+}
+
+fn default_search_limit() -> usize {
+    1000
+}
+
+fn default_search_timeout() -> u64 {
+    3000
+}
+
+fn default_smart_depth() -> usize {
+    1
+}
+
+fn default_smart_io_budget() -> usize {
+    20000
+}
+
+fn default_watch_debounce() -> u64 {
+    500
+}
+
+fn default_watch_queue() -> usize {
+    65536
+}
+
+fn default_poll_interval() -> u64 {
+    60
 }
 
 impl Default for DatabaseConfig {
@@ -55,6 +174,18 @@ impl Default for DatabaseConfig {
             ],
             hotdirnum: 100,
             log_level: "info".to_string(),
+            snapshot_interval: default_snapshot_interval(),
+            reconcile_interval: default_reconcile_interval(),
+            unavailable_grace_days: default_unavailable_grace_days(),
+            cache_capacity: 0,
+            search_limit: default_search_limit(),
+            search_timeout: default_search_timeout(),
+            smart_depth: default_smart_depth(),
+            smart_io_budget: default_smart_io_budget(),
+            watch_debounce: default_watch_debounce(),
+            watch_queue: default_watch_queue(),
+            poll_interval: default_poll_interval(),
+            target_options: BTreeMap::new(),
         };
         #[cfg(target_os = "windows")]
         {
@@ -125,11 +256,32 @@ impl DatabaseConfig {
 
         Ok(())
     }
+
+    /// 监听目标的设置
+    pub fn target_options(&self, target: &str) -> TargetOptions {
+        self.target_options.get(target).cloned().unwrap_or_default()
+    }
+
+    /// 缓存快照文件的路径, 内存数据库(隐私模式)或关闭快照时返回 None
+    pub fn snapshot_path(&self) -> Option<PathBuf> {
+        if self.snapshot_interval == 0 || self.dbtype == "memory" {
+            return None;
+        }
+        Some(self.path.with_extension("snapshot"))
+    }
+
+    /// 热点文件夹文件的路径, 内存数据库(隐私模式)时返回 None
+    pub fn hot_dirs_path(&self) -> Option<PathBuf> {
+        if self.dbtype == "memory" {
+            return None;
+        }
+        Some(self.path.with_extension("hotdirs"))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{Config, CONF};
+    use crate::config::{Config, WatchMode, CONF};
 
     #[test]
     fn test_show_config() {
@@ -139,4 +291,35 @@ mod tests {
         let config_default = Config::default();
         println!("{:#?}", config_default);
     }
+
+    #[test]
+    fn test_target_options() {
+        let config: Config = toml::from_str(
+            r#"
+            [database]
+            dbtype = "memory"
+            path = "sqlite3.db"
+            targets = ["/home", "/mnt/share"]
+            blacklist = []
+            hotdirnum = 100
+            log_level = "info"
+
+            [database.target_options."/mnt/share"]
+            mode = "poll"
+            "#,
+        )
+        .unwrap();
+        let options = config.database.target_options("/mnt/share");
+        assert_eq!(options.mode, WatchMode::Poll);
+        assert_eq!(options.poll_depth, 2);
+        assert!(options.ignore_files);
+        assert_eq!(
+            config.database.target_options("/home").mode,
+            WatchMode::Native
+        );
+
+        // 保存默认配置时表需要在其他字段之后
+        let saved = toml::to_string_pretty(&config.database).unwrap();
+        assert!(saved.contains("mode = \"poll\""));
+    }
 }
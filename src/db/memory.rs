@@ -0,0 +1,294 @@
+//! 只保存在内存中的数据库实现, 进程退出后数据即丢失
+//!
+//! 用于单元测试以及不落盘的隐私模式(`dbtype = "memory"`), 语义与 `SqliteDatabase` 保持一致
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::SystemTime,
+};
+
+use log::trace;
+
+use super::*;
+use crate::util::rebase_path;
+
+/// 内存数据库, 以 path 为键保存所有记录
+pub struct MemoryDatabase {
+    db_path: PathBuf,
+    records: RwLock<Records>,
+}
+
+#[derive(Default)]
+struct Records {
+    metas: BTreeMap<String, EntryMeta>,
+    /// 不可用的记录及其被标记的时间
+    unavailable: BTreeMap<String, SystemTime>,
+}
+
+impl Records {
+    fn remove(&mut self, key: &str) {
+        self.metas.remove(key);
+        self.unavailable.remove(key);
+    }
+}
+
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// `root` 及其子树中记录的键, 按路径分量匹配
+fn subtree_keys(metas: &BTreeMap<String, EntryMeta>, root: &Path) -> Vec<String> {
+    let prefix = path_key(root);
+    metas
+        .range(prefix.clone()..)
+        .take_while(|(k, _)| k.starts_with(&prefix))
+        .filter(|(_, meta)| meta.path.starts_with(root))
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+fn entry_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+impl Default for MemoryDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDatabase {
+    /// 创建一个空的 `MemoryDatabase`
+    pub fn new() -> Self {
+        MemoryDatabase {
+            db_path: PathBuf::from(":memory:"),
+            records: RwLock::new(Records::default()),
+        }
+    }
+
+    /// 当前保存的记录条数
+    pub fn len(&self) -> usize {
+        self.read().metas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().metas.is_empty()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Records> {
+        self.records.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Records> {
+        self.records.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DbReader for MemoryDatabase {
+    fn get_db_path(&self) -> &PathBuf {
+        &self.db_path
+    }
+
+    fn find_all(&self) -> Vec<(String, EntryMeta)> {
+        self.read()
+            .metas
+            .values()
+            .map(|meta| (entry_name(&meta.path), meta.clone()))
+            .collect()
+    }
+
+    fn find_by_entry(&self, entry: &str) -> Result<Vec<EntryMeta>, CustomError> {
+        Ok(self
+            .read()
+            .metas
+            .values()
+            .filter(|meta| entry_name(&meta.path) == entry)
+            .cloned()
+            .collect())
+    }
+
+    fn find_by_path(&self, path: &PathBuf) -> Result<Option<EntryMeta>, CustomError> {
+        Ok(self.read().metas.get(&path_key(path)).cloned())
+    }
+
+    fn find_by_path_prefix(&self, path: &PathBuf) -> Result<Vec<EntryMeta>, CustomError> {
+        let records = self.read();
+        Ok(subtree_keys(&records.metas, path)
+            .iter()
+            .map(|key| records.metas[key].clone())
+            .collect())
+    }
+
+    fn find_unavailable(&self) -> Result<Vec<(PathBuf, SystemTime)>, CustomError> {
+        Ok(self
+            .read()
+            .unavailable
+            .iter()
+            .map(|(path, since)| (PathBuf::from(path), *since))
+            .collect())
+    }
+}
+
+impl DbWriter for MemoryDatabase {
+    fn create_table(&self) -> Result<(), CustomError> {
+        Ok(())
+    }
+
+    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        let mut records = self.write();
+        // 与 sqlite 的 UNIQUE 约束保持一致
+        if records.metas.contains_key(&path_key(path)) {
+            return Err(CustomError::from(format!(
+                "record of path {:#?} already exists",
+                path
+            )));
+        }
+        let mut meta = meta.clone();
+        meta.path = path.clone();
+        records.metas.insert(path_key(path), meta);
+        Ok(())
+    }
+
+    fn delete_by_entry(&self, entry: &str) -> Result<(), CustomError> {
+        let mut records = self.write();
+        let Records { metas, unavailable } = &mut *records;
+        metas.retain(|key, meta| {
+            let keep = entry_name(&meta.path) != entry;
+            if !keep {
+                unavailable.remove(key);
+            }
+            keep
+        });
+        Ok(())
+    }
+
+    fn delete_by_path(&self, path: &PathBuf) -> Result<(), CustomError> {
+        trace!("delete_by_path: {}", path.to_string_lossy());
+        self.write().remove(&path_key(path));
+        Ok(())
+    }
+
+    fn delete_by_path_prefix(&self, path: &PathBuf) -> Result<(), CustomError> {
+        trace!("delete_by_path_prefix: {}", path.to_string_lossy());
+        let mut records = self.write();
+        for key in subtree_keys(&records.metas, path) {
+            records.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn rename_path_prefix(&self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError> {
+        if from == to {
+            return Ok(());
+        }
+        trace!("rename_path_prefix: {:?} -> {:?}", from, to);
+        let mut records = self.write();
+        for key in subtree_keys(&records.metas, to) {
+            records.remove(&key);
+        }
+        let Records { metas, unavailable } = &mut *records;
+        for key in subtree_keys(metas, from) {
+            let mut meta = metas.remove(&key).unwrap();
+            meta.path = rebase_path(&meta.path, from, to).unwrap();
+            let new_key = path_key(&meta.path);
+            if let Some(since) = unavailable.remove(&key) {
+                unavailable.insert(new_key.clone(), since);
+            }
+            metas.insert(new_key, meta);
+        }
+        Ok(())
+    }
+
+    fn update_meta(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        let mut meta = meta.clone();
+        meta.path = path.clone();
+        self.write().metas.insert(path_key(path), meta);
+        Ok(())
+    }
+
+    fn delete_all(&self) -> Result<(), CustomError> {
+        let mut records = self.write();
+        records.metas.clear();
+        records.unavailable.clear();
+        Ok(())
+    }
+
+    fn mark_unavailable(&self, path: &PathBuf, since: SystemTime) -> Result<(), CustomError> {
+        let mut records = self.write();
+        let key = path_key(path);
+        if records.metas.contains_key(&key) {
+            records.unavailable.entry(key).or_insert(since);
+        }
+        Ok(())
+    }
+
+    fn mark_available(&self, path: &PathBuf) -> Result<(), CustomError> {
+        self.write().unavailable.remove(&path_key(path));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_suite;
+
+    #[test]
+    fn test_create_table() {
+        test_suite::create_table(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_insert_and_find_by_path() {
+        test_suite::insert_and_find_by_path(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_find_by_entry() {
+        test_suite::find_by_entry(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_update_meta() {
+        test_suite::update_meta(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_delete_by_path() {
+        test_suite::delete_by_path(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_delete_path_prefix() {
+        test_suite::delete_path_prefix(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_find_path_prefix() {
+        test_suite::find_path_prefix(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_unavailable() {
+        test_suite::unavailable(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_rename_path_prefix() {
+        test_suite::rename_path_prefix(&MemoryDatabase::new());
+    }
+
+    #[test]
+    fn test_insert_duplicate() {
+        let db = MemoryDatabase::new();
+        let meta = EntryMeta::new_empty().unwrap();
+        let path = PathBuf::from("/test/duplicate.txt");
+        db.insert_rec(&path, &meta).unwrap();
+        assert!(db.insert_rec(&path, &meta).is_err());
+        assert_eq!(db.len(), 1);
+    }
+}
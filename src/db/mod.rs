@@ -1,56 +1,101 @@
+pub mod memory;
 pub mod meta;
+pub mod native;
 pub mod sqlite;
 
-use std::{
-    path::PathBuf,
-    sync::{Arc, Mutex},
-};
+#[cfg(test)]
+pub(crate) mod test_suite;
+
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
 
 use lazy_static::lazy_static;
 use log::info;
 pub use meta::EntryMeta;
 
 lazy_static! {
-    pub static ref DB: Arc<Mutex<dyn Database>> =
-        Arc::new(Mutex::new(match CONF.database.dbtype.as_str() {
-            "sqlite" => {
-                match SqliteDatabase::new(&CONF.database.path) {
-                    Ok(db) => {
-                        info!(
-                            "SqliteDatabase created from {:#?} successfully",
-                            &CONF.database.path
-                        );
-                        db
-                    }
-                    Err(e) => panic!(
-                        "Failed to create SqliteDatabase from path: {:#?}, errorr: {}",
-                        CONF.database.path, e
-                    ),
-                }
+    pub static ref DB: Arc<dyn Database> = open_database();
+}
+
+/// 根据配置中的 dbtype 创建数据库
+fn open_database() -> Arc<dyn Database> {
+    match CONF.database.dbtype.as_str() {
+        "sqlite" => match SqliteDatabase::new(&CONF.database.path) {
+            Ok(db) => {
+                info!(
+                    "SqliteDatabase created from {:#?} successfully",
+                    &CONF.database.path
+                );
+                Arc::new(db)
             }
-            st => {
-                panic!("Unsupported database type: {}", st)
+            Err(e) => panic!(
+                "Failed to create SqliteDatabase from path: {:#?}, errorr: {}",
+                CONF.database.path, e
+            ),
+        },
+        "native" => match NativeDatabase::new(&CONF.database.path) {
+            Ok(db) => {
+                info!(
+                    "NativeDatabase opened from {:#?} successfully",
+                    &CONF.database.path
+                );
+                Arc::new(db)
             }
-        },));
+            Err(e) => panic!(
+                "Failed to open NativeDatabase from path: {:#?}, error: {}",
+                CONF.database.path, e
+            ),
+        },
+        "memory" => {
+            // 隐私模式: 不在磁盘上保存任何记录
+            info!("MemoryDatabase created, records will not be persisted");
+            Arc::new(MemoryDatabase::new())
+        }
+        st => {
+            panic!("Unsupported database type: {}", st)
+        }
+    }
 }
 
-// 定义一个数据库操作的 trait
-pub trait Database: Send + Sync {
+/// 数据库的只读操作, 实现者需要保证多个线程可以同时调用, 且不会被写操作阻塞
+pub trait DbReader: Send + Sync {
     fn get_db_path(&self) -> &PathBuf;
     fn find_all(&self) -> Vec<(String, EntryMeta)>;
-    fn create_table(&self) -> Result<(), CustomError>;
-    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError>;
     fn find_by_entry(&self, entry: &str) -> Result<Vec<EntryMeta>, CustomError>;
     fn find_by_path(&self, path: &PathBuf) -> Result<Option<EntryMeta>, CustomError>;
+    /// 查询路径本身及其子树中的记录, 按路径分量精确匹配(区分大小写), 查询 `/a/b` 不会返回 `/a/bc`
     fn find_by_path_prefix(&self, path: &PathBuf) -> Result<Vec<EntryMeta>, CustomError>;
+    /// 查询所有被标记为不可用的记录及其被标记的时间
+    fn find_unavailable(&self) -> Result<Vec<(PathBuf, SystemTime)>, CustomError>;
+}
+
+/// 数据库的写操作, 实现者内部自行串行化写入
+pub trait DbWriter: Send + Sync {
+    fn create_table(&self) -> Result<(), CustomError>;
+    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError>;
     fn delete_by_entry(&self, entry: &str) -> Result<(), CustomError>;
     fn delete_by_path(&self, path: &PathBuf) -> Result<(), CustomError>;
+    /// 删除路径本身及其子树中的记录, 按路径分量匹配, 删除 `/a/b` 不会删除 `/a/bc`
     fn delete_by_path_prefix(&self, path: &PathBuf) -> Result<(), CustomError>;
+    /// 将 `from` 及其子树中的记录整体移动到 `to` 下, 访问计数等元数据和不可用标记随之移动;
+    /// `to` 下原有的记录被覆盖
+    fn rename_path_prefix(&self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError>;
     fn update_meta(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError>;
     fn delete_all(&self) -> Result<(), CustomError>;
+    /// 将记录标记为不可用(例如所在的移动硬盘未挂载), 记录本身保留;
+    /// 记录不存在时忽略, 已经标记过时保留最早的标记时间
+    fn mark_unavailable(&self, path: &PathBuf, since: SystemTime) -> Result<(), CustomError>;
+    /// 清除记录的不可用标记
+    fn mark_available(&self, path: &PathBuf) -> Result<(), CustomError>;
 }
 
+// 定义一个数据库操作的 trait, 由读写两部分组成
+pub trait Database: DbReader + DbWriter {}
+
+impl<T: DbReader + DbWriter> Database for T {}
+
 use crate::{config::CONF, util::errors::CustomError};
 
 // 导入具体的数据库实现
+pub use self::memory::MemoryDatabase;
+pub use self::native::NativeDatabase;
 pub use self::sqlite::SqliteDatabase;
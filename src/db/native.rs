@@ -0,0 +1,511 @@
+//! 不依赖 sqlite3 的自定义文件格式存储
+//!
+//! 文件由一个魔数头和若干条追加写入的记录组成, 每条记录的格式为:
+//!
+//! ```text
+//! | len: u32 (LE) | crc32: u32 (LE) | payload: bincode(LogRecord) |
+//! ```
+//!
+//! 打开时顺序重放所有记录构建内存索引, 遇到不完整或校验失败的记录时,
+//! 认为是上次写入过程中崩溃导致的, 截断该记录及其之后的内容。
+//! 当失效记录过多时, 将当前所有有效数据重写到临时文件后原子替换原文件(压缩)。
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{memory::MemoryDatabase, *};
+
+/// 文件头魔数, 最后一个字节为格式版本号
+const MAGIC: &[u8; 8] = b"ELFDB\0\0\x01";
+/// 单条记录头的长度: len + crc32
+const RECORD_HEADER_LEN: usize = 8;
+/// 单条记录的最大长度, 超过则认为文件已损坏
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+/// 记录数少于该值时不进行压缩
+const COMPACT_MIN_RECORDS: usize = 4096;
+/// 记录总数超过有效数据条数的该倍数时进行压缩
+const COMPACT_RATIO: usize = 2;
+
+/// 日志中的一条记录
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    /// 插入或更新一条记录
+    Put(EntryMeta),
+    /// 按 path 删除
+    Delete(String),
+    /// 按 path 前缀删除
+    DeletePrefix(String),
+    /// 按 entry 删除
+    DeleteEntry(String),
+    /// 删除所有数据
+    Clear,
+    /// 标记记录不可用
+    MarkUnavailable(String, SystemTime),
+    /// 清除不可用标记
+    MarkAvailable(String),
+    /// 将子树整体移动到新路径下
+    Rename(String, String),
+}
+
+/// 追加写入的日志文件
+struct RecordLog {
+    file: BufWriter<File>,
+    /// 当前文件中的记录条数(包括已失效的记录)
+    records: usize,
+}
+
+/// 使用自定义文件格式存储的数据库实现
+pub struct NativeDatabase {
+    db_path: PathBuf,
+    /// 内存索引, 所有读操作都由它完成
+    index: MemoryDatabase,
+    /// 写操作先落盘再更新内存索引, 由该锁串行化
+    log: Mutex<RecordLog>,
+}
+
+fn encode_record(record: &LogRecord) -> Result<Vec<u8>, CustomError> {
+    let payload = bincode::serialize(record)?;
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// 将一条记录应用到内存索引
+fn apply_record(index: &MemoryDatabase, record: LogRecord) -> Result<(), CustomError> {
+    match record {
+        LogRecord::Put(meta) => index.update_meta(&meta.path.clone(), &meta),
+        LogRecord::Delete(path) => index.delete_by_path(&PathBuf::from(path)),
+        LogRecord::DeletePrefix(prefix) => index.delete_by_path_prefix(&PathBuf::from(prefix)),
+        LogRecord::DeleteEntry(entry) => index.delete_by_entry(&entry),
+        LogRecord::Clear => index.delete_all(),
+        LogRecord::MarkUnavailable(path, since) => {
+            index.mark_unavailable(&PathBuf::from(path), since)
+        }
+        LogRecord::MarkAvailable(path) => index.mark_available(&PathBuf::from(path)),
+        LogRecord::Rename(from, to) => {
+            index.rename_path_prefix(&PathBuf::from(from), &PathBuf::from(to))
+        }
+    }
+}
+
+/// 重放日志文件, 返回内存索引、有效记录条数以及有效内容的长度
+fn replay(path: &Path) -> Result<(MemoryDatabase, usize, u64), CustomError> {
+    let index = MemoryDatabase::new();
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    if reader.read_exact(&mut magic).is_err() {
+        // 文件头都没有写完整, 当作空文件处理
+        return Ok((index, 0, 0));
+    }
+    if &magic != MAGIC {
+        return Err(CustomError::from(format!(
+            "{:#?} is not a file_elf native database or has an unsupported version",
+            path
+        )));
+    }
+
+    let mut records = 0;
+    let mut valid_len = MAGIC.len() as u64;
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    loop {
+        if reader.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len > MAX_RECORD_LEN {
+            warn!("native db: invalid record length {} at {}", len, valid_len);
+            break;
+        }
+
+        let mut payload = vec![0u8; len];
+        if reader.read_exact(&mut payload).is_err() {
+            warn!("native db: incomplete record at {}", valid_len);
+            break;
+        }
+        if crc32fast::hash(&payload) != crc {
+            warn!("native db: checksum mismatch at {}", valid_len);
+            break;
+        }
+        let record: LogRecord = match bincode::deserialize(&payload) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("native db: undecodable record at {}: {}", valid_len, e);
+                break;
+            }
+        };
+
+        trace!("native db replay: {:?}", record);
+        apply_record(&index, record)?;
+        records += 1;
+        valid_len += (RECORD_HEADER_LEN + len) as u64;
+    }
+
+    Ok((index, records, valid_len))
+}
+
+/// 将所有有效数据写入一个新文件, 写完并同步到磁盘后再原子替换原文件
+fn rewrite(path: &Path, index: &MemoryDatabase) -> Result<(), CustomError> {
+    let tmp_path = path.with_extension("compact");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        for (_, meta) in index.find_all() {
+            writer.write_all(&encode_record(&LogRecord::Put(meta))?)?;
+        }
+        for (path, since) in index.find_unavailable()? {
+            let record = LogRecord::MarkUnavailable(path.to_string_lossy().to_string(), since);
+            writer.write_all(&encode_record(&record)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn open_append(path: &Path) -> Result<BufWriter<File>, CustomError> {
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+impl NativeDatabase {
+    /// 打开或创建一个 `NativeDatabase`, 并从文件中恢复数据
+    pub fn new(database_path: &PathBuf) -> Result<Self, CustomError> {
+        if !database_path.exists() {
+            let mut file = File::create(database_path)?;
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+        }
+
+        let (index, records, valid_len) = replay(database_path)?;
+        if valid_len < MAGIC.len() as u64 {
+            // 文件头不完整, 重新写入文件头
+            rewrite(database_path, &index)?;
+        } else if valid_len < fs::metadata(database_path)?.len() {
+            // 截断崩溃时写了一半的记录
+            warn!(
+                "native db: truncating {:#?} to {} bytes after recovery",
+                database_path, valid_len
+            );
+            let file = OpenOptions::new().write(true).open(database_path)?;
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        info!(
+            "native db: loaded {} entries from {} records",
+            index.len(),
+            records
+        );
+
+        let db = NativeDatabase {
+            db_path: database_path.clone(),
+            log: Mutex::new(RecordLog {
+                file: open_append(database_path)?,
+                records,
+            }),
+            index,
+        };
+        db.maybe_compact(&mut db.log())?;
+        Ok(db)
+    }
+
+    fn log(&self) -> MutexGuard<'_, RecordLog> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 追加一条记录到文件, 然后应用到内存索引
+    fn append(&self, record: LogRecord) -> Result<(), CustomError> {
+        self.append_locked(&mut self.log(), record)
+    }
+
+    fn append_locked(&self, log: &mut RecordLog, record: LogRecord) -> Result<(), CustomError> {
+        let buf = encode_record(&record)?;
+        log.file.write_all(&buf)?;
+        log.file.flush()?;
+        log.records += 1;
+
+        apply_record(&self.index, record)?;
+
+        self.maybe_compact(log)
+    }
+
+    /// 失效记录过多时压缩文件
+    fn maybe_compact(&self, log: &mut RecordLog) -> Result<(), CustomError> {
+        let live = self.index.len();
+        if log.records > COMPACT_MIN_RECORDS && log.records > live * COMPACT_RATIO {
+            self.compact_locked(log)?;
+        }
+        Ok(())
+    }
+
+    /// 立即压缩文件, 只保留有效数据
+    pub fn compact(&self) -> Result<(), CustomError> {
+        self.compact_locked(&mut self.log())
+    }
+
+    fn compact_locked(&self, log: &mut RecordLog) -> Result<(), CustomError> {
+        log.file.flush()?;
+        debug!(
+            "native db: compacting {} records into {} entries",
+            log.records,
+            self.index.len()
+        );
+        rewrite(&self.db_path, &self.index)?;
+        log.file = open_append(&self.db_path)?;
+        log.records = self.index.len();
+        Ok(())
+    }
+}
+
+impl DbReader for NativeDatabase {
+    fn get_db_path(&self) -> &PathBuf {
+        &self.db_path
+    }
+
+    fn find_all(&self) -> Vec<(String, EntryMeta)> {
+        self.index.find_all()
+    }
+
+    fn find_by_entry(&self, entry: &str) -> Result<Vec<EntryMeta>, CustomError> {
+        self.index.find_by_entry(entry)
+    }
+
+    fn find_by_path(&self, path: &PathBuf) -> Result<Option<EntryMeta>, CustomError> {
+        self.index.find_by_path(path)
+    }
+
+    fn find_by_path_prefix(&self, path: &PathBuf) -> Result<Vec<EntryMeta>, CustomError> {
+        self.index.find_by_path_prefix(path)
+    }
+
+    fn find_unavailable(&self) -> Result<Vec<(PathBuf, SystemTime)>, CustomError> {
+        self.index.find_unavailable()
+    }
+}
+
+impl DbWriter for NativeDatabase {
+    fn create_table(&self) -> Result<(), CustomError> {
+        // 文件在打开时已经创建, 无需建表
+        Ok(())
+    }
+
+    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        // 与 sqlite 的 UNIQUE 约束保持一致, 检查和写入期间持有写锁
+        let mut log = self.log();
+        if self.index.find_by_path(path)?.is_some() {
+            return Err(CustomError::from(format!(
+                "record of path {:#?} already exists",
+                path
+            )));
+        }
+        let mut meta = meta.clone();
+        meta.path = path.clone();
+        self.append_locked(&mut log, LogRecord::Put(meta))
+    }
+
+    fn delete_by_entry(&self, entry: &str) -> Result<(), CustomError> {
+        self.append(LogRecord::DeleteEntry(entry.to_string()))
+    }
+
+    fn delete_by_path(&self, path: &PathBuf) -> Result<(), CustomError> {
+        trace!("delete_by_path: {}", path.to_string_lossy());
+        self.append(LogRecord::Delete(path.to_string_lossy().to_string()))
+    }
+
+    fn delete_by_path_prefix(&self, path: &PathBuf) -> Result<(), CustomError> {
+        trace!("delete_by_path_prefix: {}", path.to_string_lossy());
+        self.append(LogRecord::DeletePrefix(path.to_string_lossy().to_string()))
+    }
+
+    fn rename_path_prefix(&self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError> {
+        trace!("rename_path_prefix: {:?} -> {:?}", from, to);
+        self.append(LogRecord::Rename(
+            from.to_string_lossy().to_string(),
+            to.to_string_lossy().to_string(),
+        ))
+    }
+
+    fn update_meta(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        let mut meta = meta.clone();
+        meta.path = path.clone();
+        self.append(LogRecord::Put(meta))
+    }
+
+    fn delete_all(&self) -> Result<(), CustomError> {
+        self.append(LogRecord::Clear)
+    }
+
+    fn mark_unavailable(&self, path: &PathBuf, since: SystemTime) -> Result<(), CustomError> {
+        self.append(LogRecord::MarkUnavailable(
+            path.to_string_lossy().to_string(),
+            since,
+        ))
+    }
+
+    fn mark_available(&self, path: &PathBuf) -> Result<(), CustomError> {
+        self.append(LogRecord::MarkAvailable(path.to_string_lossy().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_suite;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "file_elf-native-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn get_db(name: &str) -> NativeDatabase {
+        NativeDatabase::new(&temp_path(name)).unwrap()
+    }
+
+    #[test]
+    fn test_create_table() {
+        test_suite::create_table(&get_db("create_table"));
+    }
+
+    #[test]
+    fn test_insert_and_find_by_path() {
+        test_suite::insert_and_find_by_path(&get_db("insert_and_find_by_path"));
+    }
+
+    #[test]
+    fn test_find_by_entry() {
+        test_suite::find_by_entry(&get_db("find_by_entry"));
+    }
+
+    #[test]
+    fn test_update_meta() {
+        test_suite::update_meta(&get_db("update_meta"));
+    }
+
+    #[test]
+    fn test_delete_by_path() {
+        test_suite::delete_by_path(&get_db("delete_by_path"));
+    }
+
+    #[test]
+    fn test_delete_path_prefix() {
+        test_suite::delete_path_prefix(&get_db("delete_path_prefix"));
+    }
+
+    #[test]
+    fn test_find_path_prefix() {
+        test_suite::find_path_prefix(&get_db("find_path_prefix"));
+    }
+
+    #[test]
+    fn test_unavailable() {
+        test_suite::unavailable(&get_db("unavailable"));
+    }
+
+    #[test]
+    fn test_rename_path_prefix() {
+        test_suite::rename_path_prefix(&get_db("rename_path_prefix"));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = temp_path("reopen");
+        let db = NativeDatabase::new(&path).unwrap();
+        test_suite::delete_path_prefix(&db);
+        let expected = db.find_all().len();
+        drop(db);
+
+        let db = NativeDatabase::new(&path).unwrap();
+        assert_eq!(db.find_all().len(), expected);
+        assert!(db
+            .find_by_path(&PathBuf::from("/test/path1/path2"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_recover_from_torn_write() {
+        let path = temp_path("torn_write");
+        let db = NativeDatabase::new(&path).unwrap();
+        test_suite::insert_and_find_by_path(&db);
+        drop(db);
+
+        // 模拟写入最后一条记录时崩溃
+        let full_len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let db = NativeDatabase::new(&path).unwrap();
+        assert_eq!(db.find_all().len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), full_len);
+
+        // 恢复后可以继续写入
+        db.delete_by_path(&PathBuf::from("/test/path")).unwrap();
+        drop(db);
+        let db = NativeDatabase::new(&path).unwrap();
+        assert_eq!(db.find_all().len(), 1);
+    }
+
+    #[test]
+    fn test_recover_from_corruption() {
+        let path = temp_path("corruption");
+        let db = NativeDatabase::new(&path).unwrap();
+        test_suite::insert_and_find_by_path(&db);
+        drop(db);
+
+        // 篡改最后一条记录的内容, 校验和不再匹配
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let db = NativeDatabase::new(&path).unwrap();
+        assert_eq!(db.find_all().len(), 1);
+        assert!(db
+            .find_by_path(&PathBuf::from("/test/path"))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = temp_path("compact");
+        let db = NativeDatabase::new(&path).unwrap();
+        let mut meta = EntryMeta::new_empty().unwrap();
+        meta.entry_type = "File".parse().unwrap();
+        for i in 0..100 {
+            meta.access_count = i;
+            db.update_meta(&PathBuf::from("/test/compact.txt"), &meta)
+                .unwrap();
+        }
+        let before = fs::metadata(&path).unwrap().len();
+        db.compact().unwrap();
+        let after = fs::metadata(&path).unwrap().len();
+        assert!(after < before);
+        drop(db);
+
+        let db = NativeDatabase::new(&path).unwrap();
+        let found = db
+            .find_by_path(&PathBuf::from("/test/compact.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.access_count, 99);
+    }
+}
@@ -1,19 +1,33 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    path::{Path, MAIN_SEPARATOR},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use super::*;
 use log::trace;
 use meta::EntryMeta;
-use rusqlite::{params, Connection, Row};
+use rusqlite::{params, Connection, OpenFlags, Row};
+
+/// 只读连接池的大小
+const READER_POOL_SIZE: usize = 4;
+/// 连接遇到数据库锁时的最长等待时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// 定义一个具体的 SQLite 数据库实现
+///
+/// 数据库以 WAL 模式打开: 一个专用的写连接负责所有写操作,
+/// 若干只读连接负责查询, 读操作不会等待写操作完成
 pub struct SqliteDatabase {
-    conn: Connection,
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
     db_path: PathBuf,
 }
 
-unsafe impl Send for SqliteDatabase {}
-unsafe impl Sync for SqliteDatabase {}
-
 fn system_to_unix_ts(time: &SystemTime) -> u64 {
     time.duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
@@ -39,40 +53,70 @@ fn row_to_meta(row: &Row<'_>) -> EntryMeta {
 impl SqliteDatabase {
     /// 创建一个新的 `SqliteDatabase` 实例
     pub fn new(database_path: &PathBuf) -> Result<Self, CustomError> {
-        let conn = Connection::open(database_path)?;
+        // 写连接负责建库建表, 因此必须先于只读连接打开
+        let writer = Connection::open(database_path)?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        let journal_mode: String =
+            writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(CustomError::from(format!(
+                "Failed to enable WAL mode, current journal_mode: {}",
+                journal_mode
+            )));
+        }
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
 
         let res = SqliteDatabase {
-            conn,
+            writer: Mutex::new(writer),
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
             db_path: database_path.clone(),
         };
         res.create_table()?;
-        Ok(res)
+
+        let mut readers = Vec::with_capacity(READER_POOL_SIZE);
+        for _ in 0..READER_POOL_SIZE {
+            let reader = Connection::open_with_flags(
+                database_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI,
+            )?;
+            reader.busy_timeout(BUSY_TIMEOUT)?;
+            readers.push(Mutex::new(reader));
+        }
+
+        Ok(SqliteDatabase { readers, ..res })
+    }
+
+    /// 获取写连接
+    fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 获取一个只读连接, 优先选择空闲的连接, 全部繁忙时轮询等待
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.readers.len() {
+            if let Ok(guard) = self.readers[(start + i) % self.readers.len()].try_lock() {
+                return guard;
+            }
+        }
+        self.readers[start % self.readers.len()]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
     }
 }
 
-impl Database for SqliteDatabase {
+impl DbReader for SqliteDatabase {
     fn get_db_path(&self) -> &PathBuf {
         &self.db_path
     }
 
-    fn create_table(&self) -> Result<(), CustomError> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS access_records (
-                entry TEXT NOT NULL,
-                path TEXT NOT NULL UNIQUE,
-                size INTEGER NOT NULL,
-                modified INTEGER NOT NULL,
-                access_count INTEGER NOT NULL,
-                entry_type TEXT NOT NULL
-            )",
-            params![],
-        )?;
-        Ok(())
-    }
-
     /// 查询所有记录的迭代器
     fn find_all(&self) -> Vec<(String, EntryMeta)> {
-        let mut stmt = self.conn.prepare("SELECT * FROM access_records").unwrap();
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT * FROM access_records").unwrap();
         let mut rows = stmt.query(params![]).unwrap();
         let mut recs: Vec<(String, EntryMeta)> = vec![];
         // TODO: 目前返回全量数据, 应该优化成迭代器
@@ -86,21 +130,10 @@ impl Database for SqliteDatabase {
         recs
     }
 
-    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
-        let entry_name = path.file_name().unwrap().to_str().unwrap();
-        let e_path = path.to_string_lossy();
-        self.conn.execute(
-            "INSERT INTO access_records (entry, path, size, modified, access_count, entry_type) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![entry_name, &e_path, &meta.size, system_to_unix_ts(&meta.modified), &meta.access_count, &meta.entry_type.to_string()],
-        )?;
-        Ok(())
-    }
-
     /// 按entry查找元数据
     fn find_by_entry(&self, entry: &str) -> Result<Vec<EntryMeta>, CustomError> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT * FROM access_records WHERE entry = ?1")?;
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT * FROM access_records WHERE entry = ?1")?;
         let mut rows = stmt.query(params![entry])?;
 
         let mut res = vec![];
@@ -113,26 +146,18 @@ impl Database for SqliteDatabase {
 
     /// 按path查找元数据
     fn find_by_path(&self, path: &PathBuf) -> Result<Option<EntryMeta>, CustomError> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT * FROM access_records WHERE path = ?1")?;
-        let mut rows = stmt.query(params![path.to_string_lossy()])?;
-
-        if let Some(row) = rows.next()? {
-            let meta = row_to_meta(row);
-            Ok(Some(meta))
-        } else {
-            Ok(None)
-        }
+        let conn = self.reader();
+        find_by_path_with(&conn, path)
     }
 
     /// 按path查找元数据
     fn find_by_path_prefix(&self, path: &PathBuf) -> Result<Vec<EntryMeta>, CustomError> {
-        let path_with_wildcard = format!("{}%", path.to_string_lossy());
-        let mut stmt = self
-            .conn
-            .prepare("SELECT * FROM access_records WHERE path LIKE ?1")?;
-        let mut rows = stmt.query(params![path_with_wildcard])?;
+        // 与删除子树相同, 不用 LIKE: 其中的 `%` `_` 是通配符, 且对 ASCII 大小写不敏感
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM access_records WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+        )?;
+        let mut rows = stmt.query(params![path.to_string_lossy(), subtree_prefix(path)])?;
 
         let mut res = Vec::new();
 
@@ -143,9 +168,89 @@ impl Database for SqliteDatabase {
         Ok(res)
     }
 
+    /// 查询所有不可用的记录
+    fn find_unavailable(&self) -> Result<Vec<(PathBuf, SystemTime)>, CustomError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT path, since FROM unavailable_records")?;
+        let mut rows = stmt.query(params![])?;
+
+        let mut res = Vec::new();
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let since: i64 = row.get(1)?;
+            res.push((
+                PathBuf::from(path),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(since as u64),
+            ));
+        }
+        Ok(res)
+    }
+}
+
+/// 使用指定的连接按path查找元数据, 写连接在"更新或插入"时也需要在同一连接上先查询
+fn find_by_path_with(conn: &Connection, path: &Path) -> Result<Option<EntryMeta>, CustomError> {
+    let mut stmt = conn.prepare("SELECT * FROM access_records WHERE path = ?1")?;
+    let mut rows = stmt.query(params![path.to_string_lossy()])?;
+
+    if let Some(row) = rows.next()? {
+        let meta = row_to_meta(row);
+        Ok(Some(meta))
+    } else {
+        Ok(None)
+    }
+}
+
+/// 子树中路径的公共前缀, 以分隔符结尾, `/a/b` 的子树不会匹配到 `/a/bc`
+fn subtree_prefix(path: &Path) -> String {
+    let mut prefix = path.to_string_lossy().to_string();
+    if !prefix.ends_with(MAIN_SEPARATOR) {
+        prefix.push(MAIN_SEPARATOR);
+    }
+    prefix
+}
+
+fn insert_rec_with(conn: &Connection, path: &Path, meta: &EntryMeta) -> Result<(), CustomError> {
+    let entry_name = path.file_name().unwrap().to_str().unwrap();
+    let e_path = path.to_string_lossy();
+    conn.execute(
+        "INSERT INTO access_records (entry, path, size, modified, access_count, entry_type) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![entry_name, &e_path, &meta.size, system_to_unix_ts(&meta.modified), &meta.access_count, &meta.entry_type.to_string()],
+    )?;
+    Ok(())
+}
+
+impl DbWriter for SqliteDatabase {
+    fn create_table(&self) -> Result<(), CustomError> {
+        self.writer().execute_batch(
+            "CREATE TABLE IF NOT EXISTS access_records (
+                entry TEXT NOT NULL,
+                path TEXT NOT NULL UNIQUE,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                access_count INTEGER NOT NULL,
+                entry_type TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS unavailable_records (
+                path TEXT NOT NULL PRIMARY KEY,
+                since INTEGER NOT NULL
+            );
+            -- 记录被删除时一并清除不可用标记
+            CREATE TRIGGER IF NOT EXISTS clear_unavailable_on_delete
+            AFTER DELETE ON access_records
+            BEGIN
+                DELETE FROM unavailable_records WHERE path = OLD.path;
+            END;",
+        )?;
+        Ok(())
+    }
+
+    fn insert_rec(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        insert_rec_with(&self.writer(), path, meta)
+    }
+
     /// 删除指定路径的单个记录
     fn delete_by_entry(&self, entry: &str) -> Result<(), CustomError> {
-        self.conn.execute(
+        self.writer().execute(
             "DELETE FROM access_records WHERE entry = ?1",
             params![entry],
         )?;
@@ -155,31 +260,70 @@ impl Database for SqliteDatabase {
     /// 按path删除记录
     fn delete_by_path(&self, entry: &PathBuf) -> Result<(), CustomError> {
         trace!("delete_by_path: {}", entry.to_string_lossy());
-        self.conn.execute(
+        self.writer().execute(
             "DELETE FROM access_records WHERE path = ?1",
             params![entry.to_string_lossy()],
         )?;
         Ok(())
     }
 
-    /// 按path前缀匹配删除记录
+    /// 删除路径本身及其子树中的记录, 按路径分量匹配, `/a/b` 不会删除 `/a/bc`
     fn delete_by_path_prefix(&self, path: &PathBuf) -> Result<(), CustomError> {
-        let path_with_wildcard = format!("{}%", path.to_string_lossy());
-        trace!("delete_by_path_prefix: {}", path_with_wildcard);
-        self.conn.execute(
-            "DELETE FROM access_records WHERE path LIKE ?1",
-            params![path_with_wildcard],
+        trace!("delete_by_path_prefix: {}", path.to_string_lossy());
+        self.writer().execute(
+            "DELETE FROM access_records WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            params![path.to_string_lossy(), subtree_prefix(path)],
         )?;
         Ok(())
     }
 
+    /// 在同一个事务中删除 `to` 子树中的原有记录, 再改写 `from` 子树中所有记录的路径前缀
+    fn rename_path_prefix(&self, from: &PathBuf, to: &PathBuf) -> Result<(), CustomError> {
+        if from == to {
+            return Ok(());
+        }
+        trace!("rename_path_prefix: {:?} -> {:?}", from, to);
+        let (from_str, to_str) = (from.to_string_lossy(), to.to_string_lossy());
+        let entry_name = to
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM access_records WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            params![to_str, subtree_prefix(to)],
+        )?;
+        tx.execute(
+            "DELETE FROM unavailable_records WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            params![to_str, subtree_prefix(to)],
+        )?;
+        // 只有子树的根需要更新 entry, 子树中其他记录的文件名不变
+        tx.execute(
+            "UPDATE access_records SET path = ?2 || substr(path, length(?1) + 1),
+                entry = CASE WHEN path = ?1 THEN ?4 ELSE entry END
+             WHERE path = ?1 OR substr(path, 1, length(?3)) = ?3",
+            params![from_str, to_str, subtree_prefix(from), entry_name],
+        )?;
+        tx.execute(
+            "UPDATE unavailable_records SET path = ?2 || substr(path, length(?1) + 1)
+             WHERE path = ?1 OR substr(path, 1, length(?3)) = ?3",
+            params![from_str, to_str, subtree_prefix(from)],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
     /// 按path更新 meta
     fn update_meta(&self, path: &PathBuf, meta: &EntryMeta) -> Result<(), CustomError> {
+        // 查询和写入在同一个写连接上完成, 避免两者之间插入其他写操作
+        let conn = self.writer();
         // 如果path的记录不存在, 则插入
-        match self.find_by_path(path)? {
+        match find_by_path_with(&conn, path)? {
             Some(_) => {
                 // 记录存在则更新
-                self.conn.execute(
+                conn.execute(
                     "UPDATE access_records SET size = ?2, modified = ?3, access_count = ?4, entry_type = ?5 WHERE path = ?1",
                     params![path.to_string_lossy(), meta.size, system_to_unix_ts(&meta.modified), &meta.access_count, &meta.entry_type.to_string()],
                 )?;
@@ -187,7 +331,7 @@ impl Database for SqliteDatabase {
             }
             None => {
                 // 记录不存在则新建
-                self.insert_rec(path, meta)?;
+                insert_rec_with(&conn, path, meta)?;
                 Ok(())
             }
         }
@@ -195,7 +339,27 @@ impl Database for SqliteDatabase {
 
     /// 删除所有数据
     fn delete_all(&self) -> Result<(), CustomError> {
-        self.conn.execute("DELETE FROM access_records", params![])?;
+        self.writer()
+            .execute("DELETE FROM access_records", params![])?;
+        Ok(())
+    }
+
+    /// 标记记录不可用
+    fn mark_unavailable(&self, path: &PathBuf, since: SystemTime) -> Result<(), CustomError> {
+        self.writer().execute(
+            "INSERT OR IGNORE INTO unavailable_records (path, since)
+             SELECT ?1, ?2 WHERE EXISTS (SELECT 1 FROM access_records WHERE path = ?1)",
+            params![path.to_string_lossy(), system_to_unix_ts(&since)],
+        )?;
+        Ok(())
+    }
+
+    /// 清除不可用标记
+    fn mark_available(&self, path: &PathBuf) -> Result<(), CustomError> {
+        self.writer().execute(
+            "DELETE FROM unavailable_records WHERE path = ?1",
+            params![path.to_string_lossy()],
+        )?;
         Ok(())
     }
 }
@@ -203,155 +367,106 @@ impl Database for SqliteDatabase {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    fn get_db() -> SqliteDatabase {
-        let db = SqliteDatabase::new(&PathBuf::from("/home/toni/proj/file_elf/sqlite3-test.db"))
-            .unwrap();
-        db.delete_all().unwrap();
-        db
+    use crate::db::test_suite;
+
+    /// 为每个测试单独创建一个临时数据库文件, 避免并行测试互相干扰
+    fn get_db(name: &str) -> SqliteDatabase {
+        let path = std::env::temp_dir().join(format!(
+            "file_elf-sqlite-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.to_string_lossy(), suffix));
+        }
+        SqliteDatabase::new(&path).unwrap()
     }
 
     #[test]
     fn test_create_table() {
-        let db = get_db();
-        assert!(db.create_table().is_ok());
+        test_suite::create_table(&get_db("create_table"));
     }
 
     #[test]
     fn test_insert_and_find_by_path() {
-        let db = get_db();
-        db.create_table().unwrap();
-
-        let entry_meta = EntryMeta {
-            path: PathBuf::from("/test/path"),
-            size: 1024,
-            modified: SystemTime::now(),
-            access_count: 1,
-            entry_type: "Dir".parse().unwrap(),
-        };
-
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
-        let result = db.find_by_path(&entry_meta.path).unwrap();
-
-        assert!(result.is_some());
-        let found_meta = result.unwrap();
-        assert_eq!(found_meta.path, entry_meta.path);
-        assert_eq!(found_meta.size, entry_meta.size);
-
-        let entry_meta = EntryMeta {
-            path: PathBuf::from("/test2/path"), // entry相同, path不同
-            size: 1024,
-            modified: SystemTime::now(),
-            access_count: 1,
-            entry_type: "Dir".parse().unwrap(),
-        };
-
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
-        let result = db.find_by_path(&entry_meta.path).unwrap();
-
-        assert!(result.is_some());
-        let found_meta = result.unwrap();
-        assert_eq!(found_meta.path, entry_meta.path);
-        assert_eq!(found_meta.size, entry_meta.size);
+        test_suite::insert_and_find_by_path(&get_db("insert_and_find_by_path"));
     }
 
     #[test]
     fn test_find_by_entry() {
-        let db = get_db();
-        db.create_table().unwrap();
-
-        let entry_meta = EntryMeta {
-            path: PathBuf::from("/test/path"),
-            size: 1024,
-            modified: SystemTime::now(),
-            access_count: 1,
-            entry_type: "Dir".parse().unwrap(),
-        };
-
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
-        let result = db.find_by_entry("path").unwrap();
-
-        assert_eq!(result.len(), 1);
-        let found_meta = &result[0];
-        assert_eq!(found_meta.path, entry_meta.path);
-        assert_eq!(found_meta.size, entry_meta.size);
+        test_suite::find_by_entry(&get_db("find_by_entry"));
     }
 
     #[test]
     fn test_update_meta() {
-        let db = get_db();
-        db.create_table().unwrap();
-
-        let mut entry_meta = EntryMeta {
-            path: PathBuf::from("/test/path/test_update_meta_1.txt"),
-            size: 1024,
-            modified: SystemTime::now(),
-            access_count: 1,
-            entry_type: "File".parse().unwrap(),
-        };
-
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+        test_suite::update_meta(&get_db("update_meta"));
+    }
 
-        // Update the meta
-        entry_meta.size = 2048;
-        db.update_meta(&entry_meta.path, &entry_meta).unwrap();
+    #[test]
+    fn test_delete_by_path() {
+        test_suite::delete_by_path(&get_db("delete_by_path"));
+    }
 
-        let result = db.find_by_path(&entry_meta.path).unwrap();
-        assert!(result.is_some());
-        let updated_meta = result.unwrap();
-        assert_eq!(updated_meta.size, 2048);
+    #[test]
+    fn test_delete_path_prefix() {
+        test_suite::delete_path_prefix(&get_db("delete_path_prefix"));
     }
 
     #[test]
-    fn test_delete_by_path() {
-        let db = get_db();
-        db.create_table().unwrap();
+    fn test_find_path_prefix() {
+        test_suite::find_path_prefix(&get_db("find_path_prefix"));
+    }
 
-        let entry_meta = EntryMeta {
-            path: PathBuf::from("/test/path/2.txt"),
-            size: 1024,
-            modified: SystemTime::now(),
-            access_count: 1,
-            entry_type: "File".parse().unwrap(),
-        };
+    #[test]
+    fn test_unavailable() {
+        test_suite::unavailable(&get_db("unavailable"));
+    }
 
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
-        db.delete_by_path(&entry_meta.path).unwrap();
+    #[test]
+    fn test_rename_path_prefix() {
+        test_suite::rename_path_prefix(&get_db("rename_path_prefix"));
+    }
 
-        let result = db.find_by_path(&entry_meta.path).unwrap();
-        assert!(result.is_none());
+    #[test]
+    fn test_wal_mode() {
+        let db = get_db("wal_mode");
+        let mode: String = db
+            .reader()
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode.to_lowercase(), "wal");
     }
 
     #[test]
-    fn test_delete_path_prefix() {
-        let db = get_db();
-        db.create_table().unwrap();
+    fn test_read_while_writing() {
+        let db = get_db("read_while_writing");
 
-        let mut entry_meta = EntryMeta {
-            path: PathBuf::from("/test/path1"),
+        let entry_meta = EntryMeta {
+            path: PathBuf::from("/test/read_while_writing.txt"),
             size: 1024,
             modified: SystemTime::now(),
             access_count: 1,
-            entry_type: "Dir".parse().unwrap(),
+            entry_type: "File".parse().unwrap(),
         };
-
         db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
 
-        entry_meta.path = PathBuf::from("/test/path1/path2");
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+        // 写连接处于未提交的事务中时, 只读连接仍能读到已提交的数据而不被阻塞
+        let writer = db.writer();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        writer
+            .execute(
+                "DELETE FROM access_records WHERE path = ?1",
+                params![entry_meta.path.to_string_lossy()],
+            )
+            .unwrap();
 
-        entry_meta.path = PathBuf::from("/test/path1/path2/test.txt");
-        entry_meta.entry_type = "File".parse().unwrap();
-        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+        let found = db.find_by_path(&entry_meta.path).unwrap();
+        assert!(found.is_some());
 
-        let result_ins = db
-            .find_by_path_prefix(&PathBuf::from("/test/path1"))
-            .unwrap();
-        assert_eq!(result_ins.len(), 3);
-        db.delete_by_path_prefix(&PathBuf::from("/test/path1"))
-            .unwrap();
+        writer.execute_batch("COMMIT").unwrap();
+        drop(writer);
 
-        let result_del = db.find_by_path(&PathBuf::from("/test/path1")).unwrap();
-        assert!(result_del.is_none());
+        let found = db.find_by_path(&entry_meta.path).unwrap();
+        assert!(found.is_none());
     }
 }
@@ -0,0 +1,290 @@
+//! 所有 `Database` 实现共用的测试用例, 各实现在自己的测试模块中传入一个空数据库调用
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use super::{Database, EntryMeta};
+
+pub(crate) fn create_table(db: &dyn Database) {
+    assert!(db.create_table().is_ok());
+}
+
+pub(crate) fn insert_and_find_by_path(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let entry_meta = EntryMeta {
+        path: PathBuf::from("/test/path"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "Dir".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    let result = db.find_by_path(&entry_meta.path).unwrap();
+
+    assert!(result.is_some());
+    let found_meta = result.unwrap();
+    assert_eq!(found_meta.path, entry_meta.path);
+    assert_eq!(found_meta.size, entry_meta.size);
+
+    let entry_meta = EntryMeta {
+        path: PathBuf::from("/test2/path"), // entry相同, path不同
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "Dir".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    let result = db.find_by_path(&entry_meta.path).unwrap();
+
+    assert!(result.is_some());
+    let found_meta = result.unwrap();
+    assert_eq!(found_meta.path, entry_meta.path);
+    assert_eq!(found_meta.size, entry_meta.size);
+}
+
+pub(crate) fn find_by_entry(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let entry_meta = EntryMeta {
+        path: PathBuf::from("/test/path"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "Dir".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    let result = db.find_by_entry("path").unwrap();
+
+    assert_eq!(result.len(), 1);
+    let found_meta = &result[0];
+    assert_eq!(found_meta.path, entry_meta.path);
+    assert_eq!(found_meta.size, entry_meta.size);
+}
+
+pub(crate) fn update_meta(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let mut entry_meta = EntryMeta {
+        path: PathBuf::from("/test/path/test_update_meta_1.txt"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "File".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    // Update the meta
+    entry_meta.size = 2048;
+    db.update_meta(&entry_meta.path, &entry_meta).unwrap();
+
+    let result = db.find_by_path(&entry_meta.path).unwrap();
+    assert!(result.is_some());
+    let updated_meta = result.unwrap();
+    assert_eq!(updated_meta.size, 2048);
+}
+
+pub(crate) fn delete_by_path(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let entry_meta = EntryMeta {
+        path: PathBuf::from("/test/path/2.txt"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "File".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    db.delete_by_path(&entry_meta.path).unwrap();
+
+    let result = db.find_by_path(&entry_meta.path).unwrap();
+    assert!(result.is_none());
+}
+
+pub(crate) fn delete_path_prefix(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let mut entry_meta = EntryMeta {
+        path: PathBuf::from("/test/path1"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "Dir".parse().unwrap(),
+    };
+
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    entry_meta.path = PathBuf::from("/test/path1/path2");
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    entry_meta.path = PathBuf::from("/test/path1/path2/test.txt");
+    entry_meta.entry_type = "File".parse().unwrap();
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    let result_ins = db
+        .find_by_path_prefix(&PathBuf::from("/test/path1"))
+        .unwrap();
+    assert_eq!(result_ins.len(), 3);
+
+    // 名字以 `path1` 开头的兄弟不在子树中
+    entry_meta.path = PathBuf::from("/test/path10/test.txt");
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    db.delete_by_path_prefix(&PathBuf::from("/test/path1"))
+        .unwrap();
+
+    let result_del = db.find_by_path(&PathBuf::from("/test/path1")).unwrap();
+    assert!(result_del.is_none());
+    let result_del = db
+        .find_by_path(&PathBuf::from("/test/path1/path2/test.txt"))
+        .unwrap();
+    assert!(result_del.is_none());
+    let result_kept = db
+        .find_by_path(&PathBuf::from("/test/path10/test.txt"))
+        .unwrap();
+    assert!(result_kept.is_some());
+}
+
+pub(crate) fn unavailable(db: &dyn Database) {
+    let mut entry_meta = EntryMeta {
+        path: PathBuf::from("/mnt/usb/unavailable.txt"),
+        size: 1024,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "File".parse().unwrap(),
+    };
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    // 不存在的记录不会被标记
+    let missing = PathBuf::from("/mnt/usb/missing.txt");
+    db.mark_unavailable(&missing, SystemTime::now()).unwrap();
+    assert!(db.find_unavailable().unwrap().is_empty());
+
+    // 重复标记时保留最早的时间
+    let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    db.mark_unavailable(&entry_meta.path, since).unwrap();
+    db.mark_unavailable(&entry_meta.path, SystemTime::now())
+        .unwrap();
+    let unavailable = db.find_unavailable().unwrap();
+    assert_eq!(unavailable, vec![(entry_meta.path.clone(), since)]);
+
+    // 标记不影响记录本身
+    assert!(db.find_by_path(&entry_meta.path).unwrap().is_some());
+
+    db.mark_available(&entry_meta.path).unwrap();
+    assert!(db.find_unavailable().unwrap().is_empty());
+
+    // 删除记录时一并清除标记
+    db.mark_unavailable(&entry_meta.path, since).unwrap();
+    db.delete_by_path(&entry_meta.path).unwrap();
+    assert!(db.find_unavailable().unwrap().is_empty());
+
+    entry_meta.path = PathBuf::from("/mnt/usb/dir/unavailable.txt");
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    db.mark_unavailable(&entry_meta.path, since).unwrap();
+    db.delete_by_path_prefix(&PathBuf::from("/mnt/usb/dir"))
+        .unwrap();
+    assert!(db.find_unavailable().unwrap().is_empty());
+}
+
+pub(crate) fn rename_path_prefix(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let mut entry_meta = EntryMeta {
+        path: PathBuf::from("/old/dir"),
+        size: 0,
+        modified: SystemTime::now(),
+        access_count: 5,
+        entry_type: "Dir".parse().unwrap(),
+    };
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    entry_meta.path = PathBuf::from("/old/dir/sub/a.txt");
+    entry_meta.access_count = 7;
+    entry_meta.entry_type = "File".parse().unwrap();
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    db.mark_unavailable(&entry_meta.path, since).unwrap();
+
+    // 名字以 `dir` 开头的兄弟不在子树中
+    entry_meta.path = PathBuf::from("/old/dir2/b.txt");
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    // 目标位置原有的记录被覆盖
+    entry_meta.path = PathBuf::from("/new/name/stale.txt");
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+
+    db.rename_path_prefix(&PathBuf::from("/old/dir"), &PathBuf::from("/new/name"))
+        .unwrap();
+
+    assert!(db
+        .find_by_path(&PathBuf::from("/old/dir"))
+        .unwrap()
+        .is_none());
+    let moved = db
+        .find_by_path(&PathBuf::from("/new/name"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(moved.access_count, 5);
+    assert_eq!(db.find_by_entry("name").unwrap().len(), 1);
+    let moved = db
+        .find_by_path(&PathBuf::from("/new/name/sub/a.txt"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(moved.access_count, 7);
+    assert_eq!(
+        db.find_unavailable().unwrap(),
+        vec![(PathBuf::from("/new/name/sub/a.txt"), since)]
+    );
+    assert!(db
+        .find_by_path(&PathBuf::from("/old/dir2/b.txt"))
+        .unwrap()
+        .is_some());
+    assert!(db
+        .find_by_path(&PathBuf::from("/new/name/stale.txt"))
+        .unwrap()
+        .is_none());
+}
+
+pub(crate) fn find_path_prefix(db: &dyn Database) {
+    db.create_table().unwrap();
+
+    let mut entry_meta = EntryMeta {
+        path: PathBuf::from("/a/b_%"),
+        size: 0,
+        modified: SystemTime::now(),
+        access_count: 1,
+        entry_type: "Dir".parse().unwrap(),
+    };
+    db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    entry_meta.entry_type = "File".parse().unwrap();
+    for path in [
+        "/a/b_%/c.txt",
+        "/a/b_%c.txt",
+        "/a/bx%/c.txt",
+        "/a/b_x/c.txt",
+        "/A/b_%/c.txt",
+        "/a/B_%/c.txt",
+    ] {
+        entry_meta.path = PathBuf::from(path);
+        db.insert_rec(&entry_meta.path, &entry_meta).unwrap();
+    }
+
+    // 按路径分量精确匹配: `%` `_` 不是通配符, 区分大小写, 名字以 `b_%` 开头的兄弟不在子树中
+    let mut found: Vec<PathBuf> = db
+        .find_by_path_prefix(&PathBuf::from("/a/b_%"))
+        .unwrap()
+        .into_iter()
+        .map(|meta| meta.path)
+        .collect();
+    found.sort();
+    assert_eq!(
+        found,
+        vec![PathBuf::from("/a/b_%"), PathBuf::from("/a/b_%/c.txt")]
+    );
+}
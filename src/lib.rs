@@ -1,9 +1,15 @@
-use std::thread;
+use std::time::Duration;
 
-use backend::{file_checker, writer::SENDER};
-use cache::cache::init_trie;
+use backend::{supervisor::spawn_supervisor, writer::SENDER};
+use cache::{
+    cache::init_trie,
+    hot_dir::{load_hot_dirs, spawn_hot_dirs_saver},
+    reconcile::spawn_reconciler,
+    snapshot::{load_or_init_trie, spawn_snapshot_saver},
+};
 use config::CONF;
 use db::DB;
+use log::error;
 use logger::setup_logger;
 
 // file_elf
@@ -20,17 +26,35 @@ pub async fn launch_elf(set_logger: bool) {
         let _ = setup_logger();
     }
 
-    init_trie(DB.clone());
+    // 先加载保存的热点文件夹, 构建缓存时再用数据库中的访问次数补充
+    if let Some(hot_dirs_path) = CONF.database.hot_dirs_path() {
+        if let Err(e) = load_hot_dirs(&hot_dirs_path) {
+            error!("load hot dirs error: {}", e);
+        }
+        spawn_hot_dirs_saver(hot_dirs_path);
+    }
 
-    let mut handlers = Vec::new();
-    for target in &CONF.database.targets {
-        let db_sender = SENDER.clone();
-        let handle: thread::JoinHandle<()> = thread::spawn(move || {
-            file_checker(target, db_sender);
-        });
-        handlers.push(handle);
+    match CONF.database.snapshot_path() {
+        Some(snapshot_path) => {
+            load_or_init_trie(DB.clone(), &snapshot_path);
+            spawn_snapshot_saver(
+                snapshot_path,
+                Duration::from_secs(CONF.database.snapshot_interval),
+            );
+        }
+        None => init_trie(DB.clone()),
+    }
+    if CONF.database.reconcile_interval > 0 {
+        spawn_reconciler(
+            DB.clone(),
+            Duration::from_secs(CONF.database.reconcile_interval),
+            Duration::from_secs(CONF.database.unavailable_grace_days * 24 * 60 * 60),
+        );
     }
 
+    // 监听线程失败后自动重启, 暂时不存在的目标持续重试
+    let supervisor = spawn_supervisor(CONF.database.targets.clone(), SENDER.clone());
+
     // 启动 Rocket 服务器
     #[cfg(feature = "webserver")]
     {
@@ -38,7 +62,7 @@ pub async fn launch_elf(set_logger: bool) {
         init_route().await;
     }
 
-    for hanlder in handlers {
-        hanlder.join().unwrap();
+    if supervisor.join().is_err() {
+        error!("watcher supervisor panicked");
     }
 }
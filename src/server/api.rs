@@ -1,104 +1,252 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use lazy_static::lazy_static;
 use log::{debug, error};
+use serde::Serialize;
 
 use crate::{
     backend::{
+        limit::{watch_usage, WatchUsage},
         new_event_handler,
+        service::{add_watch_target, remove_watch_target},
+        supervisor::{watcher_status, WatcherStatus},
         writer::{DbAction, SENDER},
     },
-    cache::{hot_dir::search_files_from_hot_dirs, CACHER},
-    db::DB,
+    cache::{
+        cache::CacheStats,
+        hot_dir::{
+            get_hot_dir, pin_hot_dir, search_files_from_hot_dirs_streaming, unpin_hot_dir, HotDir,
+        },
+        rescan::{rescan_status, RescanProgress},
+        search::{CancelToken, SearchOptions},
+        CACHER,
+    },
+    config::CONF,
+    db::{EntryMeta, DB},
     util::is_excluded,
 };
 
-pub fn api_search(entry: String, is_fuzzy: bool) -> Vec<(String, bool)> {
+lazy_static! {
+    /// 每个客户端正在进行的查询, 同一客户端的新查询到来时取消上一个
+    static ref IN_FLIGHT: Mutex<HashMap<String, CancelToken>> = Mutex::new(HashMap::new());
+}
+
+/// 一次查询的生命周期: 开始时取消同一客户端仍在进行的上一个查询, 结束时注销
+struct InFlight {
+    client: Option<String>,
+    opts: SearchOptions,
+}
+
+impl InFlight {
+    fn begin(client: Option<String>) -> InFlight {
+        let mut opts = SearchOptions::with_limit(CONF.database.search_limit);
+        opts.io_budget = CONF.database.smart_io_budget;
+        if CONF.database.search_timeout > 0 {
+            opts.deadline =
+                Some(Instant::now() + Duration::from_millis(CONF.database.search_timeout));
+        }
+        if let Some(client) = &client {
+            let prev = IN_FLIGHT
+                .lock()
+                .unwrap()
+                .insert(client.clone(), opts.cancel.clone());
+            if let Some(prev) = prev {
+                debug!("cancel previous search of client({})", client);
+                prev.cancel();
+            }
+        }
+        InFlight { client, opts }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(client) = &self.client {
+            let mut guard = IN_FLIGHT.lock().unwrap();
+            if guard
+                .get(client)
+                .map(|token| token.same(&self.opts.cancel))
+                .unwrap_or(false)
+            {
+                guard.remove(client);
+            }
+        }
+    }
+}
+
+/// 搜索接口的返回值
+#[derive(Debug, Default, Serialize)]
+pub struct SearchResponse {
+    /// 路径, 以及是否已在缓存中
+    pub results: Vec<(String, bool)>,
+    /// 因截止时间或被同一客户端的新查询取消而提前结束, 结果不完整
+    pub partial: bool,
+}
+
+/// 数据库的结果接在缓存的结果之后, 跳过重复的路径, 总数不超过 `limit`(为 0 时不限制);
+/// 返回其中不在缓存中、需要重新加载回缓存的条目
+fn merge_db_results(
+    res: &mut Vec<PathBuf>,
+    recs: Vec<EntryMeta>,
+    limit: usize,
+    cached: impl Fn(&PathBuf) -> bool,
+) -> Vec<EntryMeta> {
+    let mut seen: HashSet<PathBuf> = res.iter().cloned().collect();
+    let mut missing = Vec::new();
+    for meta in recs {
+        if limit > 0 && res.len() >= limit {
+            break;
+        }
+        if !seen.insert(meta.path.clone()) {
+            continue;
+        }
+        res.push(meta.path.clone());
+        if !cached(&meta.path) {
+            missing.push(meta);
+        }
+    }
+    missing
+}
+
+pub fn api_search(entry: String, is_fuzzy: bool, client: Option<String>) -> SearchResponse {
     debug!("search: entry({}), is_fuzzy({})", entry, is_fuzzy);
     if entry.is_empty() {
-        return Vec::new();
+        return SearchResponse::default();
     }
-    let guard = CACHER.lock().unwrap(); // 使用 mut 解锁后可以释放锁
-    let res = guard.search_entry(&entry, is_fuzzy);
+    let search = InFlight::begin(client);
+    let guard = CACHER.read().unwrap();
+    let mut res = guard.search_entry(&entry, is_fuzzy, &search.opts);
+    let needs_db = guard.needs_db_search(res.len(), search.opts.limit);
 
     drop(guard); // 显式释放锁
 
-    if res.is_empty() {
-        // 缓存没有查到, 从数据库中尽显查询(数据库查询暂不支持模糊查询)
-        debug!("cache not found, DB search: entry({})", entry);
-        match DB.lock().unwrap().find_by_entry(&entry) {
+    if needs_db && !search.opts.is_partial() {
+        // 缓存没有查到, 或者部分条目已被淘汰出缓存且结果不够, 从数据库中查询(数据库查询暂不支持模糊查询)
+        // 不在缓存中的条目重新加载回缓存, 都已在缓存中时不需要写锁
+        debug!("cache not found or partial, DB search: entry({})", entry);
+        match DB.find_by_entry(&entry) {
             Ok(recs) => {
-                let res2 = recs
-                    .into_iter()
-                    .map(|elem| (elem.path.to_string_lossy().to_string(), true))
-                    .collect();
-                debug!("search: res2({:?})", res2);
-                res2
+                let guard = CACHER.read().unwrap();
+                let missing = merge_db_results(&mut res, recs, search.opts.limit, |path| {
+                    guard.contains_path(path)
+                });
+                drop(guard);
+                if !missing.is_empty() {
+                    let mut guard = CACHER.write().unwrap();
+                    for meta in missing {
+                        guard.promote(meta);
+                    }
+                }
             }
             Err(e) => {
                 error!("DB error: {}", e);
-                Vec::new()
             }
         }
-    } else {
-        let res2 = res
-            .into_iter()
-            .map(|elem| (elem.into_os_string().into_string().unwrap(), true))
-            .collect();
-        debug!("search: res2({:?})", res2);
+    }
 
-        res2
+    let res2 = res
+        .into_iter()
+        .map(|elem| (elem.to_string_lossy().to_string(), true))
+        .collect();
+    debug!("search: res2({:?})", res2);
+    SearchResponse {
+        results: res2,
+        partial: search.opts.is_partial(),
     }
 }
 
-pub fn api_hot_search(entry: String, is_fuzzy: bool, is_regex: bool) -> Vec<(String, bool)> {
+pub fn api_hot_search(
+    entry: String,
+    is_fuzzy: bool,
+    is_regex: bool,
+    client: Option<String>,
+) -> SearchResponse {
+    api_hot_search_streaming(entry, is_fuzzy, is_regex, client, |_| {})
+}
+
+/// 标记路径是否已在缓存中
+fn mark_cached(paths: Vec<String>) -> Vec<(String, bool)> {
+    let cache_guard = CACHER.read().unwrap();
+    paths
+        .into_iter()
+        .map(|elem| {
+            let cached = cache_guard.contains_path(&PathBuf::from(&elem));
+            (elem, cached)
+        })
+        .collect()
+}
+
+/// 智能搜索, 每个热点文件夹遍历完成后立即通过 `on_dir` 返回其中的结果
+pub fn api_hot_search_streaming(
+    entry: String,
+    is_fuzzy: bool,
+    is_regex: bool,
+    client: Option<String>,
+    on_dir: impl Fn(Vec<(String, bool)>) + Sync,
+) -> SearchResponse {
     debug!(
         "hot_search: entry({}), is_fuzzy({}), is_regex({})",
         entry, is_fuzzy, is_regex
     );
     if entry.is_empty() {
-        return Vec::new();
+        return SearchResponse::default();
     }
-    let res = search_files_from_hot_dirs(&entry, is_fuzzy, is_regex);
-
-    let mut cache_guard = CACHER.lock().unwrap();
-
-    let res2 = res
-        .into_iter()
-        .map(|elem| {
-            if cache_guard.contains_path(&PathBuf::from(&elem), false) {
-                (elem, true)
-            } else {
-                (elem, false)
-            }
-        })
-        .collect();
+    let search = InFlight::begin(client);
+    let res =
+        search_files_from_hot_dirs_streaming(&entry, is_fuzzy, is_regex, &search.opts, |dir_res| {
+            on_dir(mark_cached(dir_res))
+        });
+    let res2 = mark_cached(res);
 
     debug!("hot_search: res2({:?})", res2);
 
-    res2
+    SearchResponse {
+        results: res2,
+        partial: search.opts.is_partial(),
+    }
 }
 
-pub fn api_regex_search(path: String) -> Vec<(String, bool)> {
+pub fn api_regex_search(path: String, client: Option<String>) -> SearchResponse {
     debug!("regex_search: entry({})", path);
     if path.is_empty() {
-        return Vec::new();
+        return SearchResponse::default();
     }
-    let guard = CACHER.lock().unwrap(); // 使用 mut 解锁后可以释放锁
-    let res = guard.search_path_regex(&path);
+    let search = InFlight::begin(client);
+    let guard = CACHER.read().unwrap();
+    let res = guard.search_path_regex(&path, &search.opts);
 
     let res2 = res
         .into_iter()
         .map(|elem| (elem.into_os_string().into_string().unwrap(), true))
         .collect();
     debug!("regex_search: res2({:?})", res2);
-    res2
+    SearchResponse {
+        results: res2,
+        partial: search.opts.is_partial(),
+    }
+}
+
+/// 取消客户端正在进行的查询, 例如输入框被清空时
+pub fn api_cancel(client: String) -> bool {
+    match IN_FLIGHT.lock().unwrap().remove(&client) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
 }
 
 pub fn api_star_path(path_data: String) -> bool {
     let r_path = PathBuf::from(path_data);
 
     // 先插入缓存
-    let mut guard = CACHER.lock().unwrap();
+    let mut guard = CACHER.write().unwrap();
     _ = guard.add_path(&r_path, None, false);
     debug!("star_path: {:#?} insert to cache success", r_path);
     drop(guard);
@@ -118,7 +266,7 @@ pub fn api_unstar_path(path_data: String) -> bool {
     }
 
     // 先删除缓存
-    let mut guard = CACHER.lock().unwrap();
+    let mut guard = CACHER.write().unwrap();
     _ = guard.remove_path(&r_path);
     drop(guard);
 
@@ -132,22 +280,131 @@ pub fn api_unstar_path(path_data: String) -> bool {
     }
 }
 
-#[allow(unused)]
+/// 当前的热点文件夹, 固定的文件夹排在最前
+pub fn api_hot_dirs() -> Vec<HotDir> {
+    get_hot_dir()
+}
+
+/// 固定热点文件夹, 只能固定存在的文件夹
+pub fn api_pin_hot_dir(path: String) -> bool {
+    let r_path = PathBuf::from(path);
+    if !r_path.is_dir() || is_excluded(&r_path) {
+        return false;
+    }
+    pin_hot_dir(&r_path)
+}
+
+pub fn api_unpin_hot_dir(path: String) -> bool {
+    unpin_hot_dir(&PathBuf::from(path))
+}
+
+/// stats 接口返回的运行状态
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub cache: CacheStats,
+    /// 事件队列溢出后各子树的对账进度
+    pub rescans: Vec<RescanProgress>,
+    /// inotify watch 的使用情况
+    pub watches: WatchUsage,
+}
+
+pub fn api_stats() -> Stats {
+    Stats {
+        cache: CACHER.read().unwrap().stats(),
+        rescans: rescan_status(),
+        watches: watch_usage(),
+    }
+}
+
+/// 每个监听目标的状态: 运行中、等待重试或已放弃, 以及失败的原因
+pub fn api_watchers() -> Vec<WatcherStatus> {
+    watcher_status()
+}
+
+/// 运行时增加监听目标, 目标需要是绝对路径, 且不与已有的目标互相包含; 新目标中的文件随事件和访问逐步索引
+pub fn api_add_watch_target(target: String) -> bool {
+    match add_watch_target(&target) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("add watch target {} failed: {}", target, e);
+            false
+        }
+    }
+}
+
+/// 运行时移除监听目标, 已经索引的文件保留
+pub fn api_remove_watch_target(target: String) -> bool {
+    match remove_watch_target(&target) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("remove watch target {} failed: {}", target, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{cache::cache::init_trie, db::SqliteDatabase};
-    use std::sync::{Arc, Mutex};
+    use crate::cache::Cacher;
+
+    fn metas(paths: &[&str]) -> Vec<EntryMeta> {
+        paths
+            .iter()
+            .map(|path| {
+                let mut meta = EntryMeta::new_empty().unwrap();
+                meta.path = PathBuf::from(path);
+                meta
+            })
+            .collect()
+    }
 
     #[test]
     fn test_search() {
-        let raw_db = SqliteDatabase::new(&PathBuf::from(
-            "C:\\Users\\toni\\AppData\\Local\\search-files-app\\sqlite3.db",
-        ))
-        .unwrap();
-        let db = Arc::new(Mutex::new(raw_db));
-        init_trie((db));
-
-        let res = api_search("小论文".to_string(), false);
-        println!("{:?}", res)
+        let mut cacher = Cacher::new();
+        let cached = PathBuf::from("/docs/a.txt");
+        cacher
+            .add_path(&cached, metas(&["/docs/a.txt"]).pop(), false)
+            .unwrap();
+
+        // 缓存中已有的路径不重复, 合并后的结果数不超过上限
+        let recs = metas(&["/docs/a.txt", "/other/a.txt", "/more/a.txt"]);
+        let mut res = vec![cached.clone()];
+        let missing =
+            merge_db_results(&mut res, recs.clone(), 2, |path| cacher.contains_path(path));
+        assert_eq!(res, vec![cached.clone(), PathBuf::from("/other/a.txt")]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].path, PathBuf::from("/other/a.txt"));
+
+        // 只有不在缓存中的条目重新加载回缓存
+        for meta in missing {
+            cacher.promote(meta);
+        }
+        assert!(cacher.contains_path(&PathBuf::from("/other/a.txt")));
+        assert_eq!(cacher.stats().promoted, 1);
+
+        // 不限制结果数时全部合并
+        let mut res = vec![cached.clone()];
+        let missing = merge_db_results(&mut res, recs, 0, |path| cacher.contains_path(path));
+        assert_eq!(res.len(), 3);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].path, PathBuf::from("/more/a.txt"));
+    }
+
+    #[test]
+    fn test_cancel_previous() {
+        let client = "elf-test-cancel".to_string();
+        let first = InFlight::begin(Some(client.clone()));
+        let second = InFlight::begin(Some(client.clone()));
+        // 同一客户端的新查询取消上一个查询
+        assert!(first.opts.cancel.is_cancelled());
+        assert!(!second.opts.cancel.is_cancelled());
+
+        // 旧查询结束时不会注销新查询
+        drop(first);
+        assert!(api_cancel(client.clone()));
+        assert!(second.opts.cancel.is_cancelled());
+        drop(second);
+        assert!(!api_cancel(client));
     }
 }
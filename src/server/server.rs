@@ -4,7 +4,7 @@ use rocket::{
     http::{Method, Status},
     routes,
     serde::json::Json,
-    tokio::{signal, spawn},
+    tokio::{signal, spawn, task::spawn_blocking},
 };
 
 use rocket::{
@@ -13,7 +13,15 @@ use rocket::{
     Request, Response,
 };
 
-use super::api::{api_hot_search, api_regex_search, api_search, api_star_path, api_unstar_path};
+use crate::backend::supervisor::WatcherStatus;
+use crate::cache::hot_dir::{save_hot_dirs_file, HotDir};
+use crate::cache::snapshot::save_cache_snapshot;
+
+use super::api::{
+    api_add_watch_target, api_cancel, api_hot_dirs, api_hot_search, api_pin_hot_dir,
+    api_regex_search, api_remove_watch_target, api_search, api_star_path, api_stats,
+    api_unpin_hot_dir, api_unstar_path, api_watchers, SearchResponse, Stats,
+};
 
 pub struct CORS;
 
@@ -44,19 +52,34 @@ impl Fairing for CORS {
     }
 }
 
-#[get("/search?<entry>&<is_fuzzy>")]
-async fn search(entry: String, is_fuzzy: bool) -> Json<Vec<(String, bool)>> {
-    Json(api_search(entry, is_fuzzy))
+// 搜索在阻塞线程池中执行, 同一客户端(`client`)的新查询会取消上一个仍在进行的查询
+#[get("/search?<entry>&<is_fuzzy>&<client>")]
+async fn search(entry: String, is_fuzzy: bool, client: Option<String>) -> Json<SearchResponse> {
+    let res = spawn_blocking(move || api_search(entry, is_fuzzy, client)).await;
+    Json(res.unwrap_or_default())
 }
 
-#[get("/hot_search?<entry>&<is_fuzzy>&<is_regex>")]
-async fn hot_search(entry: String, is_fuzzy: bool, is_regex: bool) -> Json<Vec<(String, bool)>> {
-    Json(api_hot_search(entry, is_fuzzy, is_regex))
+#[get("/hot_search?<entry>&<is_fuzzy>&<is_regex>&<client>")]
+async fn hot_search(
+    entry: String,
+    is_fuzzy: bool,
+    is_regex: bool,
+    client: Option<String>,
+) -> Json<SearchResponse> {
+    let res = spawn_blocking(move || api_hot_search(entry, is_fuzzy, is_regex, client)).await;
+    Json(res.unwrap_or_default())
 }
 
-#[get("/regex_search?<path>")]
-async fn regex_search(path: String) -> Json<Vec<(String, bool)>> {
-    Json(api_regex_search(path))
+#[get("/regex_search?<path>&<client>")]
+async fn regex_search(path: String, client: Option<String>) -> Json<SearchResponse> {
+    let res = spawn_blocking(move || api_regex_search(path, client)).await;
+    Json(res.unwrap_or_default())
+}
+
+// TODO: 为了实现简洁, 更改本地状态的请求也使用了get请求, 后续需要修复并解决Option和CORS问题
+#[get("/cancel?<client>")]
+async fn cancel(client: String) -> Json<bool> {
+    Json(api_cancel(client))
 }
 
 // TODO: 为了实现简洁, 更改本地状态的请求也使用了get请求, 后续需要修复并解决Option和CORS问题
@@ -71,13 +94,62 @@ async fn unstar_path(path_data: String) -> Json<bool> {
     Json(api_unstar_path(path_data))
 }
 
+#[get("/hot_dirs")]
+async fn hot_dirs() -> Json<Vec<HotDir>> {
+    Json(api_hot_dirs())
+}
+
+#[get("/pin_hot_dir?<path>")]
+async fn pin_hot_dir(path: String) -> Json<bool> {
+    Json(api_pin_hot_dir(path))
+}
+
+#[get("/unpin_hot_dir?<path>")]
+async fn unpin_hot_dir(path: String) -> Json<bool> {
+    Json(api_unpin_hot_dir(path))
+}
+
+#[get("/stats")]
+async fn stats() -> Json<Stats> {
+    Json(api_stats())
+}
+
+#[get("/watchers")]
+async fn watchers() -> Json<Vec<WatcherStatus>> {
+    Json(api_watchers())
+}
+
+#[get("/add_watch_target?<target>")]
+async fn add_watch_target(target: String) -> Json<bool> {
+    Json(api_add_watch_target(target))
+}
+
+#[get("/remove_watch_target?<target>")]
+async fn remove_watch_target(target: String) -> Json<bool> {
+    Json(api_remove_watch_target(target))
+}
+
 pub async fn init_route() {
     let figment = rocket::Config::figment().merge(("port", 6789));
 
     let rocket_instance = rocket::custom(figment)
         .mount(
             "/file_elf",
-            routes![search, regex_search, hot_search, unstar_path, star_path],
+            routes![
+                search,
+                regex_search,
+                hot_search,
+                unstar_path,
+                star_path,
+                stats,
+                cancel,
+                hot_dirs,
+                pin_hot_dir,
+                unpin_hot_dir,
+                watchers,
+                add_watch_target,
+                remove_watch_target
+            ],
         )
         .attach(CORS);
 
@@ -95,6 +167,8 @@ pub async fn init_route() {
         .await
         .expect("Failed to listen for ctrl_c signal");
     debug!("Received Ctrl+C, shutting down...");
+    save_cache_snapshot();
+    save_hot_dirs_file();
     // _rocket_handler.abort();
     // 强制退出整个进程
     std::process::exit(1);
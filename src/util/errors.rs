@@ -10,6 +10,7 @@ pub enum CustomError {
     Bincode(bincode::ErrorKind),
     Rusqlite(RusqliteError),
     Toml(TomlError),
+    Notify(notify::Error),
     ErrStr(String),
     UnknownErr,
 }
@@ -26,12 +27,24 @@ impl From<bincode::ErrorKind> for CustomError {
     }
 }
 
+impl From<Box<bincode::ErrorKind>> for CustomError {
+    fn from(err: Box<bincode::ErrorKind>) -> Self {
+        CustomError::Bincode(*err)
+    }
+}
+
 impl From<RusqliteError> for CustomError {
     fn from(err: RusqliteError) -> Self {
         CustomError::Rusqlite(err)
     }
 }
 
+impl From<notify::Error> for CustomError {
+    fn from(err: notify::Error) -> Self {
+        CustomError::Notify(err)
+    }
+}
+
 impl From<String> for CustomError {
     fn from(err: String) -> Self {
         CustomError::ErrStr(err)
@@ -58,6 +71,7 @@ impl fmt::Display for CustomError {
             CustomError::Bincode(bincode_err) => write!(f, "Bincode error: {:?}", bincode_err),
             CustomError::Rusqlite(rusqlite_err) => write!(f, "Rusqlite error: {}", rusqlite_err),
             CustomError::Toml(toml_err) => write!(f, "TOML error: {}", toml_err),
+            CustomError::Notify(notify_err) => write!(f, "Notify error: {}", notify_err),
             CustomError::ErrStr(err_str) => write!(f, "Error: {}", err_str),
             CustomError::UnknownErr => write!(f, "Error: {}", "unknown error"),
         }
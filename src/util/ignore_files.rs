@@ -0,0 +1,150 @@
+//! 监听目标中的忽略文件
+//!
+//! 与 git 一样, 每个文件夹中的 `.gitignore`、`.ignore` 和 `.elfignore` 只作用于该文件夹及其子树,
+//! 越深处的忽略文件优先; 同一个文件夹中后加载的文件优先, `.elfignore` 可以用 `!` 重新包含前两者
+//! 排除的路径。解析后的规则按文件夹缓存, 忽略文件变化时清除对应的缓存。
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+
+use crate::config::CONF;
+
+/// 按顺序加载的忽略文件
+pub const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".elfignore"];
+
+/// 缓存的文件夹数超过后清空, 监听整个磁盘时不会无限增长
+const MAX_CACHED_DIRS: usize = 65536;
+
+lazy_static! {
+    /// 每个文件夹的规则, None 表示没有忽略文件
+    static ref MATCHERS: RwLock<HashMap<PathBuf, Option<Arc<Gitignore>>>> =
+        RwLock::new(HashMap::new());
+}
+
+fn load_matcher(dir: &Path) -> Option<Arc<Gitignore>> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in IGNORE_FILES {
+        let file = dir.join(name);
+        if !file.is_file() {
+            continue;
+        }
+        found = true;
+        if let Some(e) = builder.add(&file) {
+            warn!("parse ignore file {:#?} error: {}", file, e);
+        }
+    }
+    if !found {
+        return None;
+    }
+    match builder.build() {
+        Ok(matcher) if !matcher.is_empty() => Some(Arc::new(matcher)),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("build ignore rules of {:#?} error: {}", dir, e);
+            None
+        }
+    }
+}
+
+fn matcher(dir: &Path) -> Option<Arc<Gitignore>> {
+    if let Some(matcher) = MATCHERS.read().unwrap().get(dir) {
+        return matcher.clone();
+    }
+    let matcher = load_matcher(dir);
+    let mut matchers = MATCHERS.write().unwrap();
+    if matchers.len() >= MAX_CACHED_DIRS {
+        matchers.clear();
+    }
+    matchers.insert(dir.to_path_buf(), matcher.clone());
+    matcher
+}
+
+/// `path` 是忽略文件时清除所在文件夹缓存的规则, 已经索引的条目不受影响
+pub fn invalidate_ignore(path: &Path) {
+    let is_ignore_file = path
+        .file_name()
+        .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file));
+    if let (true, Some(dir)) = (is_ignore_file, path.parent()) {
+        debug!("ignore file {:#?} changed", path);
+        MATCHERS.write().unwrap().remove(dir);
+    }
+}
+
+/// 是否被忽略文件排除
+///
+/// 只读取所在监听目标中的忽略文件, 目标关闭了 `ignore_files` 时总是返回 false;
+/// 不在配置的目标中时(例如运行时添加的目标)读取所有上级文件夹中的忽略文件
+pub fn is_ignored(path: &Path, is_dir: bool) -> bool {
+    let target = CONF
+        .database
+        .targets
+        .iter()
+        .filter(|target| path.starts_with(target))
+        .max_by_key(|target| target.len());
+    if let Some(target) = target {
+        if !CONF.database.target_options(target).ignore_files {
+            return false;
+        }
+    }
+
+    // 从最近的文件夹向上, 第一个给出结论的忽略文件生效
+    for dir in path.ancestors().skip(1) {
+        if let Some(matcher) = matcher(dir) {
+            match matcher.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        if target.is_some_and(|target| dir == Path::new(target)) {
+            break;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+
+    #[test]
+    fn test_is_ignored() {
+        let root = TempDir::new("ignore");
+        let sub = root.join("sub");
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(root.join(".gitignore"), "build/\n*.log\n").unwrap();
+        fs::write(sub.join(".ignore"), "secret*\n").unwrap();
+        fs::write(sub.join(".elfignore"), "!keep.log\n").unwrap();
+
+        assert!(is_ignored(&root.join("build"), true));
+        assert!(is_ignored(&root.join("build/out.txt"), false));
+        assert!(!is_ignored(&root.join("buildx.txt"), false));
+        assert!(is_ignored(&root.join("a.log"), false));
+        assert!(is_ignored(&sub.join("a.log"), false));
+        assert!(is_ignored(&sub.join("secret.txt"), false));
+        // 更深处的忽略文件重新包含
+        assert!(!is_ignored(&sub.join("keep.log"), false));
+        assert!(!is_ignored(&root.join("secret.txt"), false));
+
+        // 忽略文件变化后重新加载
+        fs::write(root.join(".gitignore"), "build/\n").unwrap();
+        assert!(is_ignored(&root.join("a.log"), false));
+        invalidate_ignore(&root.join(".gitignore"));
+        assert!(!is_ignored(&root.join("a.log"), false));
+    }
+}
@@ -1,9 +1,13 @@
 pub mod errors;
+pub mod ignore_files;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::config::CONF;
 use errors::CustomError;
+use lazy_static::lazy_static;
 use log::{error, trace};
 use regex::Regex;
 use strsim::levenshtein;
@@ -39,7 +43,7 @@ pub fn pattern_match(entry: &str, pattern: &str, is_fuzzy: bool) -> bool {
 }
 
 /// 正则表达式匹配
-pub fn regex_match(path: &PathBuf, pattern: &str) -> bool {
+pub fn regex_match(path: &Path, pattern: &str) -> bool {
     // 将 PathBuf 转换为 &str 类型
     let path_str = path.to_str().unwrap_or("");
 
@@ -75,15 +79,44 @@ pub fn to_absolute_path(path: &PathBuf) -> Result<PathBuf, CustomError> {
     }
 }
 
-// 检查文件是否在黑名单中的辅助函数
-pub fn is_blacklisted(file_path: &PathBuf) -> bool {
-    for pattern in &CONF.database.blacklist {
-        if regex_match(file_path, pattern) {
-            return true;
-        }
+/// 将 `from` 子树中的路径换到 `to` 下, 不在子树中时返回 None
+///
+/// 按路径分量比较, `/a/b` 不是 `/a/bc` 的前缀
+pub fn rebase_path(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(from).ok()?;
+    if rest.as_os_str().is_empty() {
+        // join 空路径会在末尾多出一个分隔符
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(rest))
     }
+}
+
+lazy_static! {
+    /// 预先编译的黑名单, 遍历目录时每个条目都要检查, 不能每次重新编译
+    static ref BLACKLIST: Vec<Regex> = CONF
+        .database
+        .blacklist
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                error!("Invalid blacklist pattern: {}, {}", pattern, e);
+                None
+            }
+        })
+        .collect();
+}
+
+// 检查文件是否在黑名单中的辅助函数
+pub fn is_blacklisted(file_path: &Path) -> bool {
+    let path_str = file_path.to_str().unwrap_or("");
+    BLACKLIST.iter().any(|re| re.is_match(path_str))
+}
 
-    false
+/// 处于黑名单或被监听目标中的忽略文件排除, 监听和遍历时跳过
+pub fn is_filtered(path: &Path, is_dir: bool) -> bool {
+    is_blacklisted(path) || ignore_files::is_ignored(path, is_dir)
 }
 
 // 条件编译: Windows
@@ -139,6 +172,20 @@ mod tests {
         assert!(res);
     }
 
+    #[test]
+    fn test_rebase_path() {
+        let (from, to) = (Path::new("/a/b"), Path::new("/c"));
+        assert_eq!(
+            rebase_path(Path::new("/a/b"), from, to),
+            Some(PathBuf::from("/c"))
+        );
+        assert_eq!(
+            rebase_path(Path::new("/a/b/x/y.txt"), from, to),
+            Some(PathBuf::from("/c/x/y.txt"))
+        );
+        assert_eq!(rebase_path(Path::new("/a/bc"), from, to), None);
+    }
+
     #[test]
     #[cfg(target_os = "windows")]
     fn test_drives() {
@@ -0,0 +1,30 @@
+//! 测试用的临时文件夹
+
+use std::{env, fs, ops::Deref, path::PathBuf, process};
+
+/// 系统临时文件夹中的 `elf-<name>-<进程号>`, 丢弃时连同其中的内容一起删除, 测试失败时也会清理
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("elf-{}-{}", name, process::id()));
+        // 上次运行中断时留下的文件
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}